@@ -1,17 +1,21 @@
 use crate::event_analyzer::{DeleteRequest, EventAnalysisResult, Validator};
-use crate::worker_pool::WorkerTask;
+use crate::metrics::{EVENTS_VALIDATED, REJECTIONS, VALIDATION_DURATION, VALIDATION_TIMEOUTS};
+use crate::shadow_log::{ShadowRecord, ShadowSink};
+use crate::worker_pool::{WorkerError, WorkerTask};
 use async_trait::async_trait;
 use nostr_sdk::prelude::*;
 use std::num::NonZeroU64;
+use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::mpsc;
-use tokio::time::Duration;
-use tracing::{debug, error, info};
+use tokio::time::{Duration, Instant};
+use tracing::{debug, info};
 
 pub struct ValidationWorker {
     validator: Validator,
     deletion_sender: mpsc::Sender<DeleteRequest>,
     validation_timeout: NonZeroU64,
+    shadow_sink: Option<Arc<dyn ShadowSink>>,
 }
 
 impl ValidationWorker {
@@ -24,40 +28,83 @@ impl ValidationWorker {
             validator,
             deletion_sender,
             validation_timeout,
+            shadow_sink: None,
         }
     }
+
+    /// Shadow mode: rejections are recorded in the sink instead of deleted
+    pub fn with_shadow_sink(mut self, shadow_sink: Arc<dyn ShadowSink>) -> Self {
+        self.shadow_sink = Some(shadow_sink);
+        self
+    }
 }
 
 #[async_trait]
 impl WorkerTask<Event> for ValidationWorker {
-    async fn call(&self, event: Event) -> Result<()> {
+    type Error = ValidatorError;
+
+    async fn call(&self, event: Event) -> Result<(), ValidatorError> {
         debug!("Validating event {}", event.id);
 
-        match tokio::time::timeout(
+        let started = Instant::now();
+        let result = tokio::time::timeout(
             Duration::from_secs(self.validation_timeout.get()),
             self.validator.validate_event(event.clone()),
         )
-        .await
-        {
-            Ok(Ok(EventAnalysisResult::Reject(reason))) => {
-                info!("Rejected event {}: {}", event.id, reason);
+        .await;
+        metrics::histogram!(VALIDATION_DURATION).record(started.elapsed().as_secs_f64());
 
-                if self.deletion_sender.send(reason).await.is_err() {
-                    return Err(ValidatorError::ReceiverDropped(event.id).into());
+        match result {
+            Ok(Ok(EventAnalysisResult::Reject(rejection))) => {
+                info!(
+                    "Rejected event {} by rule {}: {}",
+                    event.id, rejection.rule_id, rejection.delete_request
+                );
+                let request_type = rejection.delete_request.request_type();
+                let rule_id = rejection.rule_id;
+
+                if let Some(shadow_sink) = &self.shadow_sink {
+                    shadow_sink
+                        .record(&ShadowRecord::new(&rejection, &event))
+                        .await
+                        .map_err(|e| ValidatorError::ShadowLogError(e.to_string()))?;
+                } else if self
+                    .deletion_sender
+                    .send(rejection.delete_request)
+                    .await
+                    .is_err()
+                {
+                    return Err(ValidatorError::ReceiverDropped(event.id));
                 }
+
+                // Only counted once handed over, failed attempts are retried
+                metrics::counter!(EVENTS_VALIDATED, "result" => "rejected").increment(1);
+                metrics::counter!(REJECTIONS, "request_type" => request_type, "rule" => rule_id)
+                    .increment(1);
             }
             Ok(Ok(EventAnalysisResult::Accept)) => {
+                metrics::counter!(EVENTS_VALIDATED, "result" => "accepted").increment(1);
                 debug!("Accepted event {}", event.id);
             }
             Ok(Err(e)) => {
-                return Err(ValidatorError::ValidationError(e.to_string()).into());
+                return Err(ValidatorError::ValidationError(e.to_string()));
             }
             Err(_) => {
-                return Err(ValidatorError::ValidationTimeout.into());
+                metrics::counter!(VALIDATION_TIMEOUTS).increment(1);
+                return Err(ValidatorError::ValidationTimeout);
             }
         }
         Ok(())
     }
+
+    // Errors are counted when the pool gives up, not on every attempt
+    fn poisoned(&self, _event: &Event, error: Option<&ValidatorError>) {
+        let result = match error {
+            Some(ValidatorError::ValidationTimeout) | None => "timeout",
+            Some(_) => "error",
+        };
+        metrics::counter!(EVENTS_VALIDATED, "result" => result).increment(1);
+    }
 }
 
 #[derive(Error, Debug)]
@@ -68,4 +115,14 @@ pub enum ValidatorError {
     ValidationError(String),
     #[error("Validation timed out")]
     ValidationTimeout,
+    #[error("Failed to record shadow rejection: {0}")]
+    ShadowLogError(String),
+}
+
+// Validation mostly fails on relay lookups that may work next time. With the
+// deletion task gone there's no point in trying again.
+impl WorkerError for ValidatorError {
+    fn is_retryable(&self) -> bool {
+        !matches!(self, ValidatorError::ReceiverDropped(_))
+    }
 }
@@ -0,0 +1,600 @@
+use crate::event_analyzer::DeleteRequest;
+use crate::settings::{Settings, SettingsError};
+use async_trait::async_trait;
+use nostr_sdk::prelude::*;
+use redis::aio::ConnectionManager;
+use redis::streams::StreamRangeReply;
+use redis::{AsyncCommands, Value};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+
+pub static DEFAULT_AUDIT_STREAM_KEY: &str = "deletion_audit";
+
+// Stream entries read per XRANGE call when querying
+const QUERY_PAGE_SIZE: usize = 1000;
+
+/// Who asked for a deletion
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "id", rename_all = "snake_case")]
+pub enum Actor {
+    /// A spam_cleaner rule
+    Rule(String),
+    /// A user, through the vanish request with this stream id
+    Vanish(String),
+    /// Someone running `relay_admin`
+    Operator(String),
+}
+
+impl From<&DeleteRequest> for Actor {
+    fn from(delete_request: &DeleteRequest) -> Self {
+        match delete_request {
+            DeleteRequest::Vanish(stream_id, _, _) => Actor::Vanish(stream_id.clone()),
+            _ => Actor::Rule(
+                delete_request
+                    .rule_id()
+                    .unwrap_or(delete_request.request_type())
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+/// A `strfry delete` run on behalf of one or more requests
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeleteCommand {
+    pub filter: Filter,
+    /// Events strfry reported as deleted, or that would be in a dry run
+    pub deleted: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub timestamp: Timestamp,
+    pub request_type: String,
+    pub reason: String,
+    pub actor: Actor,
+    pub event_ids: Vec<EventId>,
+    pub pubkeys: Vec<PublicKey>,
+    /// The commands that deleted this request's events. They are shared with
+    /// the rest of the batch, so the counts are for the whole batch
+    pub commands: Vec<DeleteCommand>,
+    pub batch_size: usize,
+    pub dry_run: bool,
+}
+
+impl AuditRecord {
+    pub fn new(
+        delete_request: &DeleteRequest,
+        commands: Vec<DeleteCommand>,
+        batch_size: usize,
+        dry_run: bool,
+    ) -> Self {
+        AuditRecord {
+            timestamp: Timestamp::now(),
+            request_type: delete_request.request_type().to_string(),
+            reason: delete_request.to_string(),
+            actor: Actor::from(delete_request),
+            event_ids: delete_request.event_ids(),
+            pubkeys: delete_request.authors(),
+            commands,
+            batch_size,
+            dry_run,
+        }
+    }
+
+    /// A change made by hand with `relay_admin`, like restoring events or
+    /// exempting a pubkey. Nothing is sent to strfry for these
+    pub fn operator(
+        operator: &str,
+        request_type: &str,
+        reason: String,
+        event_ids: Vec<EventId>,
+        pubkeys: Vec<PublicKey>,
+    ) -> Self {
+        AuditRecord {
+            timestamp: Timestamp::now(),
+            request_type: request_type.to_string(),
+            reason,
+            actor: Actor::Operator(operator.to_string()),
+            event_ids,
+            pubkeys,
+            commands: vec![],
+            batch_size: 1,
+            dry_run: false,
+        }
+    }
+}
+
+/// Finds the records mentioning a pubkey or an event id
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    pub pubkey: Option<PublicKey>,
+    pub event_id: Option<EventId>,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        if let Some(pubkey) = &self.pubkey {
+            if !record.pubkeys.contains(pubkey) {
+                return false;
+            }
+        }
+
+        if let Some(event_id) = &self.event_id {
+            if !record.event_ids.contains(event_id) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[async_trait]
+pub trait AuditSink: Send + Sync + 'static {
+    async fn append(&self, records: &[AuditRecord]) -> Result<(), AuditLogError>;
+    async fn query(&self, query: &AuditQuery) -> Result<Vec<AuditRecord>, AuditLogError>;
+}
+
+/// Writes every record to all the configured sinks
+#[derive(Clone, Default)]
+pub struct AuditLog {
+    sinks: Vec<Arc<dyn AuditSink>>,
+}
+
+impl AuditLog {
+    pub fn new(sinks: Vec<Arc<dyn AuditSink>>) -> Self {
+        AuditLog { sinks }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    /// A failing sink doesn't stop the others, nor the deletions
+    pub async fn append(&self, records: &[AuditRecord]) {
+        for sink in &self.sinks {
+            if let Err(e) = sink.append(records).await {
+                error!("Failed to write {} audit records: {}", records.len(), e);
+            }
+        }
+    }
+}
+
+/// JSONL file rotated once it grows past `max_bytes`. Rotated files get a
+/// numeric suffix, `.1` being the newest, and only `max_files` are kept.
+pub struct FileAuditSink {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: Mutex<(File, u64)>,
+}
+
+impl FileAuditSink {
+    pub async fn open(
+        path: &Path,
+        max_bytes: u64,
+        max_files: usize,
+    ) -> Result<Self, AuditLogError> {
+        let file = open_append(path).await?;
+        let size = file.metadata().await?.len();
+
+        Ok(FileAuditSink {
+            path: path.to_path_buf(),
+            max_bytes,
+            max_files,
+            file: Mutex::new((file, size)),
+        })
+    }
+
+    async fn rotate(&self) -> Result<File, AuditLogError> {
+        let oldest = rotated_path(&self.path, self.max_files);
+        if fs::try_exists(&oldest).await? {
+            fs::remove_file(&oldest).await?;
+        }
+
+        for n in (1..self.max_files).rev() {
+            let from = rotated_path(&self.path, n);
+            if fs::try_exists(&from).await? {
+                fs::rename(&from, rotated_path(&self.path, n + 1)).await?;
+            }
+        }
+
+        if self.max_files > 0 {
+            fs::rename(&self.path, rotated_path(&self.path, 1)).await?;
+        } else {
+            fs::remove_file(&self.path).await?;
+        }
+
+        open_append(&self.path).await
+    }
+}
+
+#[async_trait]
+impl AuditSink for FileAuditSink {
+    async fn append(&self, records: &[AuditRecord]) -> Result<(), AuditLogError> {
+        let mut lines = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut lines, record)?;
+            lines.push(b'\n');
+        }
+
+        let mut guard = self.file.lock().await;
+        let (file, size) = &mut *guard;
+        if *size > 0 && *size + lines.len() as u64 > self.max_bytes {
+            *file = self.rotate().await?;
+            *size = 0;
+        }
+
+        file.write_all(&lines).await?;
+        file.flush().await?;
+        *size += lines.len() as u64;
+        Ok(())
+    }
+
+    async fn query(&self, query: &AuditQuery) -> Result<Vec<AuditRecord>, AuditLogError> {
+        // Oldest first
+        let mut paths: Vec<PathBuf> = (1..=self.max_files)
+            .rev()
+            .map(|n| rotated_path(&self.path, n))
+            .collect();
+        paths.push(self.path.clone());
+
+        let mut matches = Vec::new();
+        for path in paths {
+            if !fs::try_exists(&path).await? {
+                continue;
+            }
+
+            // A line cut short by a crash doesn't hide the rest of the file
+            for line in fs::read_to_string(&path).await?.lines() {
+                let record: AuditRecord = match serde_json::from_str(line) {
+                    Ok(record) => record,
+                    Err(e) => {
+                        warn!("Skipping corrupt audit record in {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                if query.matches(&record) {
+                    matches.push(record);
+                }
+            }
+        }
+
+        Ok(matches)
+    }
+}
+
+async fn open_append(path: &Path) -> Result<File, AuditLogError> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?)
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut file_name = OsString::from(path.as_os_str());
+    file_name.push(format!(".{}", n));
+    PathBuf::from(file_name)
+}
+
+/// Adds each record to a Redis stream, as JSON in the `record` field. The
+/// stream is never trimmed.
+pub struct RedisAuditSink {
+    con: ConnectionManager,
+    stream_key: String,
+}
+
+impl RedisAuditSink {
+    pub async fn new(redis_url: &str, stream_key: String) -> Result<Self, AuditLogError> {
+        let client = redis::Client::open(redis_url)?;
+        let con = client.get_connection_manager().await?;
+
+        Ok(RedisAuditSink { con, stream_key })
+    }
+}
+
+#[async_trait]
+impl AuditSink for RedisAuditSink {
+    async fn append(&self, records: &[AuditRecord]) -> Result<(), AuditLogError> {
+        let mut pipe = redis::pipe();
+        for record in records {
+            pipe.xadd(
+                &self.stream_key,
+                "*",
+                &[("record", serde_json::to_string(record)?)],
+            )
+            .ignore();
+        }
+
+        let mut con = self.con.clone();
+        pipe.query_async::<()>(&mut con).await?;
+        Ok(())
+    }
+
+    async fn query(&self, query: &AuditQuery) -> Result<Vec<AuditRecord>, AuditLogError> {
+        let mut con = self.con.clone();
+        let mut matches = Vec::new();
+        let mut start = "-".to_string();
+
+        loop {
+            let reply: StreamRangeReply = con
+                .xrange_count(&self.stream_key, &start, "+", QUERY_PAGE_SIZE)
+                .await?;
+
+            for stream_id in &reply.ids {
+                let Some(Value::BulkString(bytes)) = stream_id.map.get("record") else {
+                    continue;
+                };
+
+                let record: AuditRecord = match serde_json::from_slice(bytes) {
+                    Ok(record) => record,
+                    Err(e) => {
+                        warn!("Skipping corrupt audit record {}: {}", stream_id.id, e);
+                        continue;
+                    }
+                };
+                if query.matches(&record) {
+                    matches.push(record);
+                }
+            }
+
+            match reply.ids.last() {
+                Some(last) if reply.ids.len() == QUERY_PAGE_SIZE => {
+                    start = format!("({}", last.id);
+                }
+                _ => break,
+            }
+        }
+
+        Ok(matches)
+    }
+}
+
+/// Audit log options shared by the binaries that delete events
+#[derive(clap::Args, Debug, Clone)]
+pub struct AuditArgs {
+    /// Appends a JSON line per deletion to this file
+    #[arg(long)]
+    pub audit_file: Option<PathBuf>,
+
+    /// Size (in bytes) after which the audit file is rotated
+    #[arg(long, default_value_t = 100 * 1024 * 1024)]
+    pub audit_max_bytes: u64,
+
+    /// Number of rotated audit files kept
+    #[arg(long, default_value_t = 10)]
+    pub audit_max_files: usize,
+
+    /// Also adds every deletion to a Redis stream on this server,
+    /// --redis-url when no URL is given
+    #[arg(long, num_args = 0..=1, require_equals = true)]
+    pub audit_redis_url: Option<Option<String>>,
+
+    /// Stream the deletions are added to
+    #[arg(long, default_value = DEFAULT_AUDIT_STREAM_KEY)]
+    pub audit_stream_key: String,
+}
+
+impl AuditArgs {
+    pub async fn sinks(
+        &self,
+        settings: &Settings,
+    ) -> Result<Vec<Arc<dyn AuditSink>>, AuditLogError> {
+        let mut sinks: Vec<Arc<dyn AuditSink>> = Vec::new();
+
+        if let Some(path) = &self.audit_file {
+            sinks.push(Arc::new(
+                FileAuditSink::open(path, self.audit_max_bytes, self.audit_max_files).await?,
+            ));
+        }
+
+        if let Some(redis_url) = settings.feature_redis_url(&self.audit_redis_url)? {
+            sinks.push(Arc::new(
+                RedisAuditSink::new(&redis_url, self.audit_stream_key.clone()).await?,
+            ));
+        }
+
+        Ok(sinks)
+    }
+
+    pub async fn audit_log(&self, settings: &Settings) -> Result<AuditLog, AuditLogError> {
+        Ok(AuditLog::new(self.sinks(settings).await?))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AuditLogError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[error("Settings error: {0}")]
+    Settings(#[from] SettingsError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay_commander::{RawCommanderTrait, RelayCommander};
+
+    struct CountingCommander;
+
+    #[async_trait]
+    impl RawCommanderTrait for CountingCommander {
+        async fn delete_from_filter(
+            &self,
+            filter: Filter,
+            _dry_run: bool,
+        ) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+            Ok(Some(
+                filter.ids.map(|ids| ids.len()).unwrap_or_default() * 10,
+            ))
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "{}-{}.jsonl",
+            name,
+            Keys::generate().public_key().to_hex()
+        ))
+    }
+
+    fn remove_all(path: &Path, max_files: usize) {
+        let _ = std::fs::remove_file(path);
+        for n in 1..=max_files {
+            let _ = std::fs::remove_file(rotated_path(path, n));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deletions_are_audited() {
+        let path = temp_path("audit");
+        let sink = Arc::new(FileAuditSink::open(&path, 1024 * 1024, 2).await.unwrap());
+        let relay_commander = RelayCommander::new(CountingCommander)
+            .with_audit_log(AuditLog::new(vec![sink.clone()]));
+
+        let spammer = Keys::generate().public_key();
+        let event_id = EventId::all_zeros();
+        relay_commander
+            .execute_delete(
+                vec![
+                    DeleteRequest::TagSpam(event_id),
+                    DeleteRequest::Vanish("1-0".to_string(), spammer, None),
+                ],
+                true,
+            )
+            .await
+            .unwrap();
+
+        let by_event = sink
+            .query(&AuditQuery {
+                event_id: Some(event_id),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(by_event.len(), 1);
+        assert_eq!(by_event[0].request_type, "tag_spam");
+        assert_eq!(by_event[0].actor, Actor::Rule("tag_limits".to_string()));
+        assert_eq!(by_event[0].commands.len(), 1);
+        assert_eq!(by_event[0].commands[0].deleted, Some(10));
+        assert_eq!(by_event[0].batch_size, 2);
+        assert!(by_event[0].dry_run);
+
+        let by_pubkey = sink
+            .query(&AuditQuery {
+                pubkey: Some(spammer),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(by_pubkey.len(), 1);
+        assert_eq!(by_pubkey[0].actor, Actor::Vanish("1-0".to_string()));
+        // Authors and gift wraps
+        assert_eq!(by_pubkey[0].commands.len(), 2);
+
+        remove_all(&path, 2);
+    }
+
+    #[tokio::test]
+    async fn test_operator_changes_are_found_by_pubkey() {
+        let path = temp_path("audit-operator");
+        let sink = FileAuditSink::open(&path, 1024 * 1024, 1).await.unwrap();
+        let pubkey = Keys::generate().public_key();
+        sink.append(&[AuditRecord::operator(
+            "alice",
+            "exemption_add",
+            "Appeal #12".to_string(),
+            vec![],
+            vec![pubkey],
+        )])
+        .await
+        .unwrap();
+
+        let records = sink
+            .query(&AuditQuery {
+                pubkey: Some(pubkey),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].actor, Actor::Operator("alice".to_string()));
+        assert_eq!(
+            serde_json::to_value(&records[0].actor).unwrap(),
+            serde_json::json!({"type": "operator", "id": "alice"})
+        );
+
+        remove_all(&path, 1);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_lines_are_skipped() {
+        let path = temp_path("audit-corrupt");
+        let sink = FileAuditSink::open(&path, 1024 * 1024, 1).await.unwrap();
+        let record = AuditRecord::new(
+            &DeleteRequest::SpamScore(EventId::all_zeros()),
+            vec![],
+            1,
+            false,
+        );
+
+        sink.append(std::slice::from_ref(&record)).await.unwrap();
+        {
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap();
+            std::io::Write::write_all(&mut file, b"{\"timestamp\": 17\n").unwrap();
+        }
+        sink.append(std::slice::from_ref(&record)).await.unwrap();
+
+        let all = sink.query(&AuditQuery::default()).await.unwrap();
+        assert_eq!(all, vec![record.clone(), record]);
+
+        remove_all(&path, 1);
+    }
+
+    #[tokio::test]
+    async fn test_file_rotation_keeps_max_files() {
+        let path = temp_path("audit-rotation");
+        let record = AuditRecord::new(
+            &DeleteRequest::SpamScore(EventId::all_zeros()),
+            vec![],
+            1,
+            false,
+        );
+        let record_size = serde_json::to_vec(&record).unwrap().len() as u64 + 1;
+
+        // Two records per file
+        let sink = FileAuditSink::open(&path, record_size * 2, 2)
+            .await
+            .unwrap();
+        for _ in 0..7 {
+            sink.append(std::slice::from_ref(&record)).await.unwrap();
+        }
+
+        assert!(rotated_path(&path, 2).exists());
+        assert!(!rotated_path(&path, 3).exists());
+        // 7 written, the oldest 2 rotated out
+        let all = sink.query(&AuditQuery::default()).await.unwrap();
+        assert_eq!(all.len(), 5);
+
+        remove_all(&path, 3);
+    }
+}
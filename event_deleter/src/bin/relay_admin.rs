@@ -0,0 +1,329 @@
+use clap::{Parser, Subcommand};
+use event_deleter::audit_log::{AuditArgs, AuditLog, AuditQuery, AuditRecord};
+use event_deleter::exemptions::{Exemption, ExemptionArgs, ExemptionSource};
+use event_deleter::quarantine::{QuarantineStore, RestoreQuery};
+use event_deleter::relay_commander::RelayCommander;
+use event_deleter::settings::SettingsArgs;
+use nostr_sdk::prelude::*;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::info;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Operator tools for the deletions done by spam_cleaner and vanish_subscriber",
+    long_about = None
+)]
+// Leave the comments, they are used for the --help message
+struct Args {
+    /// Who is running the command, recorded in the audit log with restores
+    /// and exemption changes. Defaults to $USER
+    #[arg(long, global = true)]
+    operator: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Prints the audit records of the deletions affecting a pubkey or an
+    /// event, one JSON per line, oldest first. Reads a single log, pass
+    /// either --audit-file or --audit-redis-url
+    Audit {
+        /// Hex or bech32 public key
+        #[arg(long, required_unless_present = "event_id")]
+        pubkey: Option<String>,
+
+        /// Hex or bech32 event id
+        #[arg(long)]
+        event_id: Option<String>,
+
+        #[command(flatten)]
+        settings: SettingsArgs,
+
+        #[command(flatten)]
+        audit: AuditArgs,
+    },
+
+    /// Imports quarantined events back into strfry. Run it from the strfry
+    /// directory, like the other binaries
+    Restore {
+        /// Directory spam_cleaner was given with --quarantine-dir
+        #[arg(long)]
+        quarantine_dir: PathBuf,
+
+        /// Only events by this author, hex or bech32
+        #[arg(long)]
+        pubkey: Option<String>,
+
+        /// Only events deleted by this rule
+        #[arg(long)]
+        rule: Option<String>,
+
+        /// Only events quarantined at or after this unix timestamp
+        #[arg(long)]
+        since: Option<u64>,
+
+        /// Only events quarantined at or before this unix timestamp
+        #[arg(long)]
+        until: Option<u64>,
+
+        /// Print the events that would be restored instead of importing them
+        #[arg(short = 'd', long)]
+        dry_run: bool,
+
+        #[command(flatten)]
+        settings: SettingsArgs,
+
+        #[command(flatten)]
+        audit: AuditArgs,
+    },
+
+    /// Manages the pubkeys rules must never delete as a whole
+    Exemption {
+        #[command(subcommand)]
+        command: ExemptionCommand,
+
+        #[command(flatten)]
+        exemptions: ExemptionArgs,
+
+        #[command(flatten)]
+        settings: SettingsArgs,
+
+        #[command(flatten)]
+        audit: AuditArgs,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ExemptionCommand {
+    /// Exempts a pubkey, replacing any previous exemption
+    Add {
+        /// Hex or bech32 public key
+        pubkey: String,
+
+        /// Why it's exempt, e.g. the appeal it comes from
+        #[arg(long)]
+        reason: String,
+
+        /// Days until the exemption expires, it never does when not set
+        #[arg(long)]
+        expires_in_days: Option<u64>,
+    },
+    /// Removes the exemption of a pubkey
+    Remove {
+        /// Hex or bech32 public key
+        pubkey: String,
+    },
+    /// Prints every exemption, one JSON per line
+    List,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Logs go to stderr so the output can be piped
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_writer(std::io::stderr))
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    let args = Args::parse();
+    let operator = args
+        .operator
+        .or_else(|| std::env::var("USER").ok())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    match args.command {
+        Command::Audit {
+            pubkey,
+            event_id,
+            settings: settings_args,
+            audit,
+        } => {
+            let settings = settings_args
+                .settings()?
+                .with_feature_redis_url("audit", &audit.audit_redis_url)?;
+            if settings_args.print_config(&settings)? {
+                return Ok(());
+            }
+
+            let query = AuditQuery {
+                pubkey: pubkey.as_deref().map(PublicKey::parse).transpose()?,
+                event_id: event_id.as_deref().map(EventId::parse).transpose()?,
+            };
+
+            let sinks = audit.sinks(&settings).await?;
+            let [sink] = sinks.as_slice() else {
+                return Err("Pass either --audit-file or --audit-redis-url".into());
+            };
+
+            for record in sink.query(&query).await? {
+                println!("{}", serde_json::to_string(&record)?);
+            }
+        }
+        Command::Restore {
+            quarantine_dir,
+            pubkey,
+            rule,
+            since,
+            until,
+            dry_run,
+            settings: settings_args,
+            audit,
+        } => {
+            let settings = settings_args
+                .settings()?
+                .with_feature_redis_url("audit", &audit.audit_redis_url)?;
+            if settings_args.print_config(&settings)? {
+                return Ok(());
+            }
+
+            if pubkey.is_none() && rule.is_none() && since.is_none() && until.is_none() {
+                return Err("Pass at least one of --pubkey, --rule, --since or --until".into());
+            }
+
+            let query = RestoreQuery {
+                pubkey: pubkey.as_deref().map(PublicKey::parse).transpose()?,
+                rule_id: rule,
+                since: since.map(Timestamp::from),
+                until: until.map(Timestamp::from),
+            };
+
+            // Retention only applies when archiving, this just reads
+            let store = QuarantineStore::new(&quarantine_dir, 0)?;
+            let events: Vec<Event> = store
+                .find(query)
+                .await?
+                .into_iter()
+                .map(|quarantined| quarantined.event)
+                .collect();
+
+            if dry_run {
+                for event in &events {
+                    println!("{}", event.as_json());
+                }
+                info!("{} events would be restored", events.len());
+            } else {
+                let event_ids = events.iter().map(|event| event.id).collect();
+                let mut pubkeys: Vec<PublicKey> = events.iter().map(|event| event.pubkey).collect();
+                pubkeys.sort();
+                pubkeys.dedup();
+
+                let count = events.len();
+                RelayCommander::from_settings(&settings)
+                    .restore(events)
+                    .await?;
+                info!("Restored {} events", count);
+
+                audit
+                    .audit_log(&settings)
+                    .await?
+                    .append(&[AuditRecord::operator(
+                        &operator,
+                        "restore",
+                        format!("Restored {} quarantined events", count),
+                        event_ids,
+                        pubkeys,
+                    )])
+                    .await;
+            }
+        }
+        Command::Exemption {
+            command,
+            exemptions,
+            settings: settings_args,
+            audit,
+        } => {
+            let settings = settings_args
+                .settings()?
+                .with_feature_redis_url("exemptions", &exemptions.exemptions_redis_url)?
+                .with_feature_redis_url("audit", &audit.audit_redis_url)?;
+            if settings_args.print_config(&settings)? {
+                return Ok(());
+            }
+
+            let exemptions = exemptions.exemptions(&settings).await?;
+            if exemptions.is_empty() {
+                return Err("Pass --exemptions-file or --exemptions-redis-url".into());
+            }
+
+            let audit_log = audit.audit_log(&settings).await?;
+            run_exemption_command(command, exemptions.sources(), &audit_log, &operator).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_exemption_command(
+    command: ExemptionCommand,
+    sources: &[Arc<dyn ExemptionSource>],
+    audit_log: &AuditLog,
+    operator: &str,
+) -> Result<(), Box<dyn Error>> {
+    match command {
+        ExemptionCommand::Add {
+            pubkey,
+            reason,
+            expires_in_days,
+        } => {
+            let created_at = Timestamp::now();
+            let exemption = Exemption {
+                pubkey: PublicKey::parse(&pubkey)?,
+                reason,
+                created_at,
+                expires_at: expires_in_days.map(|days| created_at + days * 24 * 60 * 60),
+            };
+
+            for source in sources {
+                source.add(&exemption).await?;
+            }
+            info!("Exempted {}", exemption.pubkey);
+
+            audit_log
+                .append(&[AuditRecord::operator(
+                    operator,
+                    "exemption_add",
+                    exemption.reason,
+                    vec![],
+                    vec![exemption.pubkey],
+                )])
+                .await;
+        }
+        ExemptionCommand::Remove { pubkey } => {
+            let pubkey = PublicKey::parse(&pubkey)?;
+            let mut removed = false;
+            for source in sources {
+                removed |= source.remove(&pubkey).await?;
+            }
+
+            if removed {
+                info!("Removed the exemption of {}", pubkey);
+                audit_log
+                    .append(&[AuditRecord::operator(
+                        operator,
+                        "exemption_remove",
+                        "Exemption removed".to_string(),
+                        vec![],
+                        vec![pubkey],
+                    )])
+                    .await;
+            } else {
+                info!("{} wasn't exempt", pubkey);
+            }
+        }
+        ExemptionCommand::List => {
+            for source in sources {
+                for exemption in source.list().await? {
+                    println!("{}", serde_json::to_string(&exemption)?);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
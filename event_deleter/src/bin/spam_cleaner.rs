@@ -1,19 +1,37 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use event_deleter::{
     analyzer_worker::ValidationWorker,
-    deletion_task::spawn_deletion_task,
-    event_analyzer::{DeleteRequest, Validator},
-    relay_commander,
-    worker_pool::WorkerPool,
+    audit_log::AuditArgs,
+    deletion_queue::DeletionQueueArgs,
+    deletion_task::{spawn_deletion_task, DeletionPolicy, DeletionPolicyArgs},
+    event_analyzer::{
+        kind_policy::{KindPolicy, KindPolicyConfig, DEFAULT_KIND_POLICY_FILE},
+        rules_config::RulesConfig,
+        DeleteRequest, Validator,
+    },
+    exemptions::ExemptionArgs,
+    http_server::spawn_http_server,
+    metrics::{install_recorder, MetricsHandler},
+    quarantine::QuarantineArgs,
+    relay_commander::{self, rate_limit::RateLimitArgs},
+    settings::SettingsArgs,
+    shadow_log::{FileShadowSink, RedisShadowSink, ShadowSink, DEFAULT_SHADOW_STREAM_KEY},
+    worker_pool::{concurrency_limit::AdaptiveConcurrency, RetryPolicy, ShutdownMode, WorkerPool},
 };
 use nonzero_ext::nonzero;
-use nostr_sdk::Event;
+use nostr_sdk::{Event, JsonUtil};
 use serde_json::Deserializer;
 use std::error::Error;
 use std::io;
+use std::net::SocketAddr;
 use std::num::NonZeroU16;
+use std::num::NonZeroU32;
 use std::num::NonZeroU64;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::sync::mpsc;
+use tokio::time::Duration;
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::{debug, error, info};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
@@ -26,21 +44,137 @@ use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 )]
 // Leave the comments, they are used for the --help message
 struct Args {
-    /// Buffer size for batching delete commands
-    #[arg(short, long, default_value_t = nonzero!(10u16))]
-    buffer_size: NonZeroU16,
-
     /// Maximum number of concurrent validation tasks
     #[arg(short = 'c', long, default_value_t = nonzero!(10u16))]
     concurrency_limit: NonZeroU16,
 
+    /// Adapts the number of concurrent validation tasks to the validation
+    /// latency and timeouts, between --min-concurrency and
+    /// --concurrency-limit
+    #[arg(long)]
+    adaptive_concurrency: bool,
+
+    /// Lowest number of concurrent validation tasks in adaptive mode
+    #[arg(long, default_value_t = nonzero!(1u16))]
+    min_concurrency: NonZeroU16,
+
+    /// Validations slower than this (in milliseconds) lower the concurrency
+    /// in adaptive mode
+    #[arg(long, default_value_t = 1000)]
+    latency_target_millis: u64,
+
     /// Timeout (in seconds) for validating each event
     #[arg(short = 't', long, default_value_t = nonzero!(10u64))]
     validation_timeout: NonZeroU64,
 
+    /// Attempts to validate each event, failed validations are retried with
+    /// an exponential backoff
+    #[arg(long, default_value_t = nonzero!(3u32))]
+    validation_attempts: NonZeroU32,
+
+    /// Wait (in milliseconds) before the first validation retry
+    #[arg(long, default_value_t = 1000)]
+    retry_backoff_millis: u64,
+
+    /// Events that couldn't be validated are written to this JSONL file on
+    /// exit, with the last error and the number of attempts
+    #[arg(long)]
+    poison_file: Option<PathBuf>,
+
+    /// What happens to the events still being validated on Ctrl+C
+    #[arg(long, value_enum, default_value_t = Shutdown::Drain)]
+    shutdown: Shutdown,
+
+    /// Seconds a drain has to finish before the validations left are aborted
+    #[arg(long, default_value_t = 30)]
+    drain_deadline_secs: u64,
+
+    /// Author deletions are split so no strfry delete command deletes more
+    /// events than this
+    #[arg(long, default_value_t = nonzero!(10_000usize))]
+    max_events_per_command: NonZeroUsize,
+
     /// Dry run mode. If set, events will not be deleted
     #[arg(short = 'd', long)]
     dry_run: bool,
+
+    /// YAML file enabling and configuring the optional validation rules
+    #[arg(short = 'r', long)]
+    rules_config: Option<PathBuf>,
+
+    /// Enables the rules for a specific sweep, on top of the rules config.
+    /// Feed it the events matched by the `scan-filter` filters of the same preset
+    #[arg(short = 'p', long, value_enum)]
+    preset: Option<Preset>,
+
+    /// Kind allow-list shared with nos_policy.ts
+    #[arg(long, default_value = DEFAULT_KIND_POLICY_FILE)]
+    kind_policy_file: PathBuf,
+
+    /// Shadow mode. Rejections are appended to this JSONL file with the rule
+    /// and evidence that triggered them, nothing is deleted
+    #[arg(long, conflicts_with = "shadow_redis_url")]
+    shadow_file: Option<PathBuf>,
+
+    /// Shadow mode, recording rejections in a Redis stream instead of a
+    /// file, on --redis-url when no URL is given
+    #[arg(long, num_args = 0..=1, require_equals = true)]
+    shadow_redis_url: Option<Option<String>>,
+
+    /// Stream the shadow rejections are added to
+    #[arg(long, default_value = DEFAULT_SHADOW_STREAM_KEY)]
+    shadow_stream_key: String,
+
+    /// Serves Prometheus metrics on /metrics at this port
+    #[arg(long)]
+    http_port: Option<u16>,
+
+    #[command(flatten)]
+    settings: SettingsArgs,
+
+    #[command(flatten)]
+    audit: AuditArgs,
+
+    #[command(flatten)]
+    quarantine: QuarantineArgs,
+
+    #[command(flatten)]
+    exemptions: ExemptionArgs,
+
+    #[command(flatten)]
+    rate_limit: RateLimitArgs,
+
+    #[command(flatten)]
+    deletion_policy: DeletionPolicyArgs,
+
+    #[command(flatten)]
+    deletion_queue: DeletionQueueArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Prints the `strfry scan` filters selecting the events a preset cleans, one per line
+    ScanFilter {
+        #[arg(value_enum)]
+        preset: Preset,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Shutdown {
+    /// Validate the events already read, up to the drain deadline
+    Drain,
+    /// Stop validating right away
+    Abort,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Preset {
+    /// Stored events of kinds nos_policy.ts doesn't allow anymore
+    DisallowedKinds,
 }
 
 #[tokio::main]
@@ -51,14 +185,50 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .init();
 
     let args = Args::parse();
+    let mut rules_config = match &args.rules_config {
+        Some(path) => RulesConfig::from_file(path)?,
+        None => RulesConfig::default(),
+    };
+    let duplicates_redis_url = rules_config
+        .duplicate_cluster
+        .as_ref()
+        .map(|config| config.redis_url.clone());
+    let settings = args
+        .settings
+        .settings()?
+        .with_feature_redis_url("audit", &args.audit.audit_redis_url)?
+        .with_feature_redis_url(
+            "vanish_priority",
+            &args.rate_limit.vanish_priority_redis_url,
+        )?
+        .with_feature_redis_url("exemptions", &args.exemptions.exemptions_redis_url)?
+        .with_feature_redis_url("shadow", &args.shadow_redis_url)?
+        .with_feature_redis_url(
+            "deletion_queue",
+            &args.deletion_queue.deletion_queue_redis_url,
+        )?
+        .with_feature_redis_url("duplicate_cluster", &duplicates_redis_url)?;
+    if args.settings.print_config(&settings)? {
+        return Ok(());
+    }
+
+    if let Some(Command::ScanFilter { preset }) = args.command {
+        print_scan_filters(preset, &args)?;
+        return Ok(());
+    }
+
+    if args.adaptive_concurrency && args.min_concurrency > args.concurrency_limit {
+        return Err("--min-concurrency can't be above --concurrency-limit".into());
+    }
+
     let tracker = TaskTracker::new();
     let cancellation_token = CancellationToken::new();
     let shutdown_token = cancellation_token.clone();
 
     info!("Starting spam cleaner...");
     info!(
-        "Buffer size: {}, Concurrency limit: {}, Validation timeout: {}, Dry run: {}",
-        args.buffer_size, args.concurrency_limit, args.validation_timeout, args.dry_run
+        "Concurrency limit: {}, Validation timeout: {}, Dry run: {}",
+        args.concurrency_limit, args.validation_timeout, args.dry_run
     );
 
     tokio::spawn(async move {
@@ -69,25 +239,95 @@ async fn main() -> Result<(), Box<dyn Error>> {
         info!("Shutdown signal received. Initiating graceful shutdown...");
     });
 
+    if let Some(http_port) = args.http_port {
+        let metrics_handler = MetricsHandler::new(install_recorder()?);
+        spawn_http_server(
+            SocketAddr::from(([0, 0, 0, 0], http_port)),
+            vec![Arc::new(metrics_handler)],
+            cancellation_token.clone(),
+        )
+        .await?;
+    }
+
     let (validation_sender, validation_receiver) = mpsc::channel::<Event>(100);
-    let (deletion_sender, deletion_receiver) = mpsc::channel::<DeleteRequest>(100);
+    // Small batches by default, so a sweep stopped halfway has deleted most
+    // of what it validated
+    let deletion_policy = args.deletion_policy.policy(DeletionPolicy {
+        max_batch_size: nonzero!(10u16),
+        channel_size: nonzero!(100usize),
+        ..Default::default()
+    })?;
+    let (deletion_sender, deletion_receiver) =
+        mpsc::channel::<DeleteRequest>(deletion_policy.channel_size.get());
+
+    if let Some(Preset::DisallowedKinds) = args.preset {
+        rules_config.kind_policy = Some(KindPolicyConfig {
+            policy_file: args.kind_policy_file.clone(),
+        });
+    }
 
-    let validator = Validator::new().await?;
-    let validator_worker =
+    let shadow_redis_url = settings.feature_redis_url(&args.shadow_redis_url)?;
+    let shadow_sink: Option<Arc<dyn ShadowSink>> = match (&args.shadow_file, shadow_redis_url) {
+        (Some(path), _) => Some(Arc::new(FileShadowSink::open(path).await?)),
+        (None, Some(redis_url)) => Some(Arc::new(
+            RedisShadowSink::new(&redis_url, args.shadow_stream_key.clone()).await?,
+        )),
+        (None, None) => None,
+    };
+    if shadow_sink.is_some() {
+        rules_config.shadow();
+    }
+
+    let exemptions = args.exemptions.exemptions(&settings).await?;
+    let validator = Validator::new(&rules_config, &settings, &tracker, &cancellation_token)
+        .await?
+        .with_exemptions(exemptions.clone());
+    let mut validator_worker =
         ValidationWorker::new(validator, deletion_sender, args.validation_timeout);
+    if let Some(shadow_sink) = shadow_sink {
+        info!("Shadow mode, rejections are recorded and not deleted");
+        validator_worker = validator_worker.with_shadow_sink(shadow_sink);
+    }
 
     // Spawn the validation WorkerPool
-    WorkerPool::start(
-        &tracker,
+    let mut validation_pool = WorkerPool::new(
         "validation_pool",
         args.concurrency_limit,
         args.validation_timeout,
-        validation_receiver,
-        cancellation_token.clone(),
-        validator_worker,
     );
+    if args.adaptive_concurrency {
+        validation_pool = validation_pool.with_adaptive_concurrency(AdaptiveConcurrency::new(
+            args.min_concurrency,
+            Duration::from_millis(args.latency_target_millis),
+        ));
+    }
+    let validation_pool = validation_pool
+        .with_retry_policy(RetryPolicy::new(
+            args.validation_attempts,
+            Duration::from_millis(args.retry_backoff_millis),
+        ))
+        .with_shutdown_mode(match args.shutdown {
+            Shutdown::Drain => ShutdownMode::Drain {
+                deadline: Duration::from_secs(args.drain_deadline_secs),
+            },
+            Shutdown::Abort => ShutdownMode::Abort,
+        })
+        .start(
+            &tracker,
+            validation_receiver,
+            None,
+            cancellation_token.clone(),
+            validator_worker,
+        );
 
-    let relay_commander = relay_commander::RelayCommander::default();
+    let mut relay_commander = relay_commander::RelayCommander::from_settings(&settings)
+        .with_audit_log(args.audit.audit_log(&settings).await?)
+        .with_exemptions(exemptions)
+        .with_rate_limiter(args.rate_limit.rate_limiter(&settings).await?)
+        .with_max_events_per_command(args.max_events_per_command);
+    if let Some(quarantine) = args.quarantine.store()? {
+        relay_commander = relay_commander.with_quarantine(quarantine);
+    }
 
     // Spawn the deletion task with dry_run flag
     spawn_deletion_task(
@@ -95,8 +335,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
         deletion_receiver,
         None,
         relay_commander,
-        args.buffer_size,
+        deletion_policy,
         args.dry_run,
+        None,
+        args.deletion_queue.queue(&settings).await?,
     );
 
     tracker.close();
@@ -134,7 +376,42 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     tracker.wait().await;
 
+    let report = validation_pool.report().await;
+    info!("Validation pool shutdown report: {}", report);
+    if !report.poisoned.is_empty() {
+        error!("{} events couldn't be validated", report.poisoned.len());
+
+        if let Some(path) = &args.poison_file {
+            let mut jsonl = String::new();
+            for poisoned in &report.poisoned {
+                let line = serde_json::json!({
+                    "event": poisoned.item,
+                    "error": poisoned.error,
+                    "attempts": poisoned.attempts,
+                });
+                jsonl.push_str(&line.to_string());
+                jsonl.push('\n');
+            }
+            tokio::fs::write(path, jsonl).await?;
+            info!("Wrote them to {}", path.display());
+        }
+    }
+
     debug!("Exiting main function");
 
     Ok(())
 }
+
+fn print_scan_filters(preset: Preset, args: &Args) -> Result<(), Box<dyn Error>> {
+    let filters = match preset {
+        Preset::DisallowedKinds => {
+            KindPolicy::from_file(&args.kind_policy_file)?.disallowed_kinds_filters()
+        }
+    };
+
+    for filter in filters {
+        println!("{}", filter.as_json());
+    }
+
+    Ok(())
+}
@@ -1,23 +1,34 @@
 use clap::Parser;
 use event_deleter::{
-    deletion_task::spawn_deletion_task,
+    audit_log::AuditArgs,
+    deletion_queue::DeletionQueueArgs,
+    deletion_task::{spawn_deletion_task, DeletionPolicy, DeletionPolicyArgs},
     event_analyzer::DeleteRequest,
-    relay_commander::RelayCommander,
+    health::{HealthHandler, HealthState},
+    http_server::spawn_http_server,
+    metrics::{install_recorder, MetricsHandler},
+    redis_supervisor::{RedisSupervisor, RedisSupervisorArgs},
+    relay_commander::{
+        chunked_delete::{RedisCheckpointStore, DEFAULT_CHECKPOINT_KEY},
+        rate_limit::RateLimitArgs,
+        RelayCommander,
+    },
+    settings::SettingsArgs,
     vanish_subscriber_task::{spawn_vanish_subscriber, RedisClient},
 };
 use nonzero_ext::nonzero;
 use std::error::Error;
-use std::{env, sync::LazyLock};
+use std::net::SocketAddr;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::sync::Arc;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
+use tokio::time::Duration;
 use tokio_rustls::rustls::crypto::ring;
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::info;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-static REDIS_URL: LazyLock<String> =
-    LazyLock::new(|| env::var("REDIS_URL").expect("REDIS_URL must be set"));
-
 #[derive(Parser, Debug)]
 #[command(
     version,
@@ -29,6 +40,45 @@ struct Args {
     /// Dry run mode. If set, events will not be deleted
     #[arg(short = 'd', long)]
     dry_run: bool,
+
+    /// Serves Prometheus metrics on /metrics, and the /healthz and /readyz
+    /// checks, at this port
+    #[arg(long)]
+    http_port: Option<u16>,
+
+    /// Author deletions are split so no strfry delete command deletes more
+    /// events than this. The progress of a split vanish request is saved in
+    /// Redis and resumed after a restart
+    #[arg(long, default_value_t = nonzero!(10_000usize))]
+    max_events_per_command: NonZeroUsize,
+
+    /// Seconds without a successful read of the vanish stream before
+    /// /healthz fails
+    #[arg(long, default_value_t = nonzero!(60u64))]
+    max_xread_age_secs: NonZeroU64,
+
+    /// Seconds a single flush of the deletion task may run before /healthz
+    /// fails, even though the stream reader is only waiting on it
+    #[arg(long, default_value_t = nonzero!(1800u64))]
+    max_flush_secs: NonZeroU64,
+
+    #[command(flatten)]
+    settings: SettingsArgs,
+
+    #[command(flatten)]
+    redis_supervisor: RedisSupervisorArgs,
+
+    #[command(flatten)]
+    audit: AuditArgs,
+
+    #[command(flatten)]
+    rate_limit: RateLimitArgs,
+
+    #[command(flatten)]
+    deletion_policy: DeletionPolicyArgs,
+
+    #[command(flatten)]
+    deletion_queue: DeletionQueueArgs,
 }
 
 #[tokio::main]
@@ -43,6 +93,25 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .expect("Failed to install ring crypto provider");
 
     let args = Args::parse();
+    let settings = args
+        .settings
+        .settings()?
+        .with_feature_redis_url("audit", &args.audit.audit_redis_url)?
+        .with_feature_redis_url(
+            "vanish_priority",
+            &args.rate_limit.vanish_priority_redis_url,
+        )?
+        .with_feature_redis_url(
+            "deletion_queue",
+            &args.deletion_queue.deletion_queue_redis_url,
+        )?;
+    if args.settings.print_config(&settings)? {
+        return Ok(());
+    }
+    // Checked before anything starts, the subscriber needs both
+    let redis_url = settings.redis_url()?;
+    settings.relay_host()?;
+
     let tracker = TaskTracker::new();
     let cancellation_token = CancellationToken::new();
 
@@ -70,13 +139,43 @@ async fn main() -> Result<(), Box<dyn Error>> {
         token.cancel();
     });
 
-    // We may never need to change these constants so for the moment lets leave them hardcoded
-    let delete_command_batch_size = nonzero!(50u16);
-    let vanish_channel_size = 10;
-    let (deletion_sender, deletion_receiver) = mpsc::channel::<DeleteRequest>(vanish_channel_size);
-    let (ack_sender, ack_receiver) = mpsc::channel::<DeleteRequest>(vanish_channel_size);
-    let redis_client = RedisClient::new(&REDIS_URL);
-    let relay_commander = RelayCommander::default();
+    let health = HealthState::default();
+    let supervisor =
+        RedisSupervisor::new(args.redis_supervisor.policy(), cancellation_token.clone());
+    if let Some(http_port) = args.http_port {
+        let metrics_handler = MetricsHandler::new(install_recorder()?);
+        let health_handler = HealthHandler::new(
+            health.clone(),
+            RedisClient::new(redis_url)?,
+            Duration::from_secs(args.max_xread_age_secs.get()),
+        )
+        .with_max_flush_duration(Duration::from_secs(args.max_flush_secs.get()))
+        .with_strfry_path(&settings.strfry_path)
+        .with_redis_supervisor(supervisor.clone());
+        spawn_http_server(
+            SocketAddr::from(([0, 0, 0, 0], http_port)),
+            vec![Arc::new(metrics_handler), Arc::new(health_handler)],
+            cancellation_token.clone(),
+        )
+        .await?;
+    }
+
+    let deletion_policy = args.deletion_policy.policy(DeletionPolicy::default())?;
+    let (deletion_sender, deletion_receiver) =
+        mpsc::channel::<DeleteRequest>(deletion_policy.channel_size.get());
+    let (ack_sender, ack_receiver) =
+        mpsc::channel::<DeleteRequest>(deletion_policy.channel_size.get());
+    let redis_client = RedisClient::new(redis_url)?;
+    // Waits for Redis, up to the startup timeout, before the checkpoint
+    // store connects to it
+    supervisor.connect(&redis_client).await?;
+    let relay_commander = RelayCommander::from_settings(&settings)
+        .with_audit_log(args.audit.audit_log(&settings).await?)
+        .with_rate_limiter(args.rate_limit.rate_limiter(&settings).await?)
+        .with_max_events_per_command(args.max_events_per_command)
+        .with_checkpoints(Arc::new(
+            RedisCheckpointStore::new(redis_url, DEFAULT_CHECKPOINT_KEY.to_string()).await?,
+        ));
 
     // Batches the delete requests and sends them to the strfry delete command.
     // Sends ack messages to the Redis vanish stream listener
@@ -85,24 +184,35 @@ async fn main() -> Result<(), Box<dyn Error>> {
         deletion_receiver,
         Some(ack_sender),
         relay_commander,
-        delete_command_batch_size,
+        deletion_policy,
         args.dry_run,
+        Some(health.clone()),
+        args.deletion_queue.queue(&settings).await?,
     );
 
     // Read the Redis stream and send the delete requests to the deletion task
     // On ack, we update the last id processed. It's safe/idempotent to process
     // the same id multiple times but we want to avoid that
-    spawn_vanish_subscriber(
+    let subscriber = spawn_vanish_subscriber(
         &tracker,
         deletion_sender,
         ack_receiver,
         redis_client,
+        health,
+        supervisor.clone(),
+        &settings,
         cancellation_token,
     )
-    .await?;
+    .await;
 
+    // The deletion task finishes what it got once the subscriber stops
     tracker.close();
     tracker.wait().await;
+    subscriber?;
+
+    if supervisor.gave_up() {
+        return Err("Exiting after too many Redis failures".into());
+    }
 
     info!("Exiting vanish listener");
 
@@ -0,0 +1,46 @@
+use clap::Parser;
+use event_deleter::{
+    event_analyzer::{pow::PowPolicy, rules_config::RulesConfig},
+    write_policy::run_write_policy,
+};
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+use tracing::info;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "strfry write policy plugin rejecting events without enough proof of work",
+    long_about = None
+)]
+// Leave the comments, they are used for the --help message
+struct Args {
+    /// YAML rules file, the `proof_of_work` section is used
+    #[arg(short = 'r', long)]
+    rules_config: PathBuf,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // stdout belongs to the plugin protocol, logs go to stderr
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_writer(io::stderr))
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    let args = Args::parse();
+    let rules_config = RulesConfig::from_file(&args.rules_config)?;
+    let policy = match &rules_config.proof_of_work {
+        Some(proof_of_work_config) => PowPolicy::from_config(proof_of_work_config)?,
+        None => {
+            return Err("The rules config has no proof_of_work section".into());
+        }
+    };
+
+    info!("Starting proof of work write policy...");
+
+    run_write_policy(io::stdin().lock(), io::stdout().lock(), &policy)?;
+
+    Ok(())
+}
@@ -0,0 +1,284 @@
+use crate::event_analyzer::DeleteRequest;
+use crate::settings::{Settings, SettingsError};
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+pub static DEFAULT_DELETION_QUEUE_KEY: &str = "deletion_queue";
+
+/// Durable copy of the requests the deletion task is batching, replayed on
+/// startup so a crash doesn't lose them. Requests are flushed in the order
+/// they were pushed, so flushing drops the oldest ones, after those whose
+/// deletion failed, which stay for the next start.
+#[async_trait]
+pub trait DeletionQueue: Send + Sync {
+    async fn push(&self, delete_request: &DeleteRequest) -> Result<(), DeletionQueueError>;
+
+    /// Requests pushed and not flushed yet, oldest first
+    async fn pending(&self) -> Result<Vec<DeleteRequest>, DeletionQueueError>;
+
+    /// Drops `count` requests, the oldest after the `skip` first ones
+    async fn remove_flushed(&self, skip: usize, count: usize) -> Result<(), DeletionQueueError>;
+}
+
+/// Append-only JSONL file, rewritten with the requests left after each flush
+pub struct FileDeletionQueue {
+    path: PathBuf,
+    state: Mutex<(File, VecDeque<String>)>,
+}
+
+impl FileDeletionQueue {
+    pub async fn open(path: &Path) -> Result<Self, DeletionQueueError> {
+        let mut pending = VecDeque::new();
+        if fs::try_exists(path).await? {
+            for line in fs::read_to_string(path).await?.lines() {
+                // The last line is cut short if the process died writing it
+                match serde_json::from_str::<DeleteRequest>(line) {
+                    Ok(_) => pending.push_back(line.to_string()),
+                    Err(e) => warn!("Skipping broken deletion queue entry: {}", e),
+                }
+            }
+        }
+
+        // Rewritten so the next request doesn't follow a broken line
+        let file = rewrite(path, &pending).await?;
+        Ok(FileDeletionQueue {
+            path: path.to_path_buf(),
+            state: Mutex::new((file, pending)),
+        })
+    }
+}
+
+#[async_trait]
+impl DeletionQueue for FileDeletionQueue {
+    async fn push(&self, delete_request: &DeleteRequest) -> Result<(), DeletionQueueError> {
+        let line = serde_json::to_string(delete_request)?;
+
+        let mut state = self.state.lock().await;
+        let (file, pending) = &mut *state;
+        file.write_all(format!("{}\n", line).as_bytes()).await?;
+        file.flush().await?;
+        pending.push_back(line);
+        Ok(())
+    }
+
+    async fn pending(&self) -> Result<Vec<DeleteRequest>, DeletionQueueError> {
+        let state = self.state.lock().await;
+        state
+            .1
+            .iter()
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+
+    async fn remove_flushed(&self, skip: usize, count: usize) -> Result<(), DeletionQueueError> {
+        let mut state = self.state.lock().await;
+        let skip = skip.min(state.1.len());
+        let count = count.min(state.1.len() - skip);
+        state.1.drain(skip..skip + count);
+
+        let file = rewrite(&self.path, &state.1).await?;
+        state.0 = file;
+        Ok(())
+    }
+}
+
+/// Redis list, trimmed after each flush
+pub struct RedisDeletionQueue {
+    con: ConnectionManager,
+    key: String,
+}
+
+impl RedisDeletionQueue {
+    pub async fn new(redis_url: &str, key: String) -> Result<Self, DeletionQueueError> {
+        let client = redis::Client::open(redis_url)?;
+        let con = client.get_connection_manager().await?;
+
+        Ok(RedisDeletionQueue { con, key })
+    }
+}
+
+#[async_trait]
+impl DeletionQueue for RedisDeletionQueue {
+    async fn push(&self, delete_request: &DeleteRequest) -> Result<(), DeletionQueueError> {
+        let mut con = self.con.clone();
+        con.rpush::<_, _, ()>(&self.key, serde_json::to_string(delete_request)?)
+            .await?;
+        Ok(())
+    }
+
+    async fn pending(&self) -> Result<Vec<DeleteRequest>, DeletionQueueError> {
+        let mut con = self.con.clone();
+        let lines: Vec<String> = con.lrange(&self.key, 0, -1).await?;
+
+        Ok(lines
+            .iter()
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(delete_request) => Some(delete_request),
+                Err(e) => {
+                    warn!("Skipping broken deletion queue entry: {}", e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    async fn remove_flushed(&self, skip: usize, count: usize) -> Result<(), DeletionQueueError> {
+        let mut con = self.con.clone();
+        if skip == 0 {
+            con.ltrim::<_, ()>(&self.key, count as isize, -1).await?;
+            return Ok(());
+        }
+
+        // The skipped requests are put back in front, the deletion task is
+        // the only one writing the list
+        let skipped: Vec<String> = con.lrange(&self.key, 0, skip as isize - 1).await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .ltrim(&self.key, (skip + count) as isize, -1)
+            .ignore();
+        for line in skipped.iter().rev() {
+            pipe.lpush(&self.key, line).ignore();
+        }
+        pipe.query_async::<()>(&mut con).await?;
+        Ok(())
+    }
+}
+
+// Replaces the file with the pending requests, returning it opened to append
+async fn rewrite(path: &Path, pending: &VecDeque<String>) -> Result<File, DeletionQueueError> {
+    let mut tmp_path = path.as_os_str().to_os_string();
+    tmp_path.push(".tmp");
+
+    let mut contents = String::new();
+    for line in pending {
+        contents.push_str(line);
+        contents.push('\n');
+    }
+    fs::write(&tmp_path, contents).await?;
+    fs::rename(&tmp_path, path).await?;
+
+    open_append(path).await
+}
+
+async fn open_append(path: &Path) -> Result<File, DeletionQueueError> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?)
+}
+
+/// Durable deletion queue options. Each running binary needs its own file or
+/// key, the queue assumes a single deletion task.
+#[derive(clap::Args, Debug, Clone)]
+pub struct DeletionQueueArgs {
+    /// Keeps the requests waiting to be deleted in this file, they are
+    /// deleted on the next start if the process dies
+    #[arg(long, conflicts_with = "deletion_queue_redis_url")]
+    pub deletion_queue_file: Option<PathBuf>,
+
+    /// Keeps the requests waiting to be deleted in a Redis list instead,
+    /// on --redis-url when no URL is given
+    #[arg(long, num_args = 0..=1, require_equals = true)]
+    pub deletion_queue_redis_url: Option<Option<String>>,
+
+    /// List the waiting requests are kept in
+    #[arg(long, default_value = DEFAULT_DELETION_QUEUE_KEY)]
+    pub deletion_queue_key: String,
+}
+
+impl DeletionQueueArgs {
+    pub async fn queue(
+        &self,
+        settings: &Settings,
+    ) -> Result<Option<Arc<dyn DeletionQueue>>, DeletionQueueError> {
+        if let Some(path) = &self.deletion_queue_file {
+            return Ok(Some(Arc::new(FileDeletionQueue::open(path).await?)));
+        }
+
+        if let Some(redis_url) = settings.feature_redis_url(&self.deletion_queue_redis_url)? {
+            return Ok(Some(Arc::new(
+                RedisDeletionQueue::new(&redis_url, self.deletion_queue_key.clone()).await?,
+            )));
+        }
+
+        Ok(None)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DeletionQueueError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[error("Settings error: {0}")]
+    Settings(#[from] SettingsError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::prelude::*;
+
+    #[tokio::test]
+    async fn test_file_queue_survives_a_restart() {
+        let path = std::env::temp_dir().join(format!(
+            "deletion_queue-{}.jsonl",
+            Keys::generate().public_key().to_hex()
+        ));
+        let requests: Vec<DeleteRequest> = (0..3)
+            .map(|_| DeleteRequest::SpamAuthor(Keys::generate().public_key()))
+            .collect();
+
+        let queue = FileDeletionQueue::open(&path).await.unwrap();
+        for request in &requests {
+            queue.push(request).await.unwrap();
+        }
+        queue.remove_flushed(0, 1).await.unwrap();
+        queue.push(&requests[0]).await.unwrap();
+        drop(queue);
+
+        // Died writing a request
+        let mut file = open_append(&path).await.unwrap();
+        file.write_all(b"{\"spam_auth").await.unwrap();
+        drop(file);
+
+        let queue = FileDeletionQueue::open(&path).await.unwrap();
+        assert_eq!(
+            queue.pending().await.unwrap(),
+            vec![
+                requests[1].clone(),
+                requests[2].clone(),
+                requests[0].clone()
+            ]
+        );
+
+        // The first one failed to delete
+        queue.remove_flushed(1, 1).await.unwrap();
+        assert_eq!(
+            queue.pending().await.unwrap(),
+            vec![requests[1].clone(), requests[0].clone()]
+        );
+
+        queue.remove_flushed(0, 3).await.unwrap();
+        assert!(queue.pending().await.unwrap().is_empty());
+        assert_eq!(fs::read_to_string(&path).await.unwrap(), "");
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
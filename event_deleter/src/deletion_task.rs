@@ -1,78 +1,355 @@
+use crate::deletion_queue::DeletionQueue;
 use crate::event_analyzer::DeleteRequest;
+use crate::health::HealthState;
 use crate::relay_commander::{RawCommanderTrait, RelayCommander};
-use std::num::NonZeroU16;
+use config::{Config, File};
+use nonzero_ext::nonzero;
+use nostr_sdk::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::num::{NonZeroU16, NonZeroUsize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use thiserror::Error;
 use tokio::sync::mpsc;
-use tokio::time;
+use tokio::time::{self, Duration, Instant};
 use tokio_util::task::TaskTracker;
 use tracing::{debug, error, info};
 
-static FLUSH_PERIOD_SECONDS: u64 = 10;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlushPriority {
+    /// Flushes the batch as soon as the request arrives
+    Immediate,
+    /// Waits for the batch to fill up or its latency to run out
+    Batched,
+}
+
+/// When the deletion task sends its batch of requests to strfry. Whichever
+/// limit is reached first flushes the batch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeletionPolicy {
+    pub max_batch_size: NonZeroU16,
+    /// Longest a request waits in the batch
+    pub max_latency_millis: u64,
+    /// The batch is flushed before a request brings it over this many
+    /// distinct authors
+    pub max_distinct_authors: Option<NonZeroUsize>,
+    /// By request type (`vanish`, `tag_spam`...), the ones left out are
+    /// batched
+    pub priorities: HashMap<String, FlushPriority>,
+    /// Requests queued for the deletion task
+    pub channel_size: NonZeroUsize,
+}
+
+impl Default for DeletionPolicy {
+    fn default() -> Self {
+        DeletionPolicy {
+            max_batch_size: nonzero!(50u16),
+            max_latency_millis: 10_000,
+            max_distinct_authors: None,
+            // Vanish requests have a compliance deadline
+            priorities: HashMap::from([("vanish".to_string(), FlushPriority::Immediate)]),
+            channel_size: nonzero!(10usize),
+        }
+    }
+}
+
+impl DeletionPolicy {
+    pub fn max_latency(&self) -> Duration {
+        Duration::from_millis(self.max_latency_millis)
+    }
+
+    fn priority(&self, delete_request: &DeleteRequest) -> FlushPriority {
+        self.priorities
+            .get(delete_request.request_type())
+            .copied()
+            .unwrap_or(FlushPriority::Batched)
+    }
+
+    fn validate(self) -> Result<Self, DeletionPolicyError> {
+        for request_type in self.priorities.keys() {
+            if !DeleteRequest::REQUEST_TYPES.contains(&request_type.as_str()) {
+                return Err(DeletionPolicyError::UnknownRequestType(
+                    request_type.clone(),
+                ));
+            }
+        }
+
+        Ok(self)
+    }
+}
+
+/// Deletion policy options for the binaries running a deletion task. The
+/// flags override the policy file, which overrides the binary defaults.
+#[derive(clap::Args, Debug, Clone)]
+pub struct DeletionPolicyArgs {
+    /// YAML file with the deletion policy
+    #[arg(long)]
+    pub deletion_policy: Option<PathBuf>,
+
+    /// Requests sent to strfry in the same batch
+    #[arg(short = 'b', long, alias = "buffer-size")]
+    pub max_batch_size: Option<NonZeroU16>,
+
+    /// Longest (in milliseconds) a request waits in the batch
+    #[arg(long)]
+    pub max_batch_latency_millis: Option<u64>,
+
+    /// Distinct authors in the same batch
+    #[arg(long)]
+    pub max_batch_authors: Option<NonZeroUsize>,
+
+    /// Request types flushed as soon as they arrive, replacing the ones of
+    /// the policy
+    #[arg(long, value_delimiter = ',')]
+    pub flush_immediately: Option<Vec<String>>,
+
+    /// Requests queued for the deletion task
+    #[arg(long)]
+    pub deletion_channel_size: Option<NonZeroUsize>,
+}
+
+impl DeletionPolicyArgs {
+    pub fn policy(&self, defaults: DeletionPolicy) -> Result<DeletionPolicy, DeletionPolicyError> {
+        let mut builder = Config::builder().add_source(Config::try_from(&defaults)?);
+        if let Some(path) = &self.deletion_policy {
+            builder = builder.add_source(File::from(path.as_path()));
+        }
+        let mut policy: DeletionPolicy = builder.build()?.try_deserialize()?;
+
+        if let Some(max_batch_size) = self.max_batch_size {
+            policy.max_batch_size = max_batch_size;
+        }
+        if let Some(max_latency_millis) = self.max_batch_latency_millis {
+            policy.max_latency_millis = max_latency_millis;
+        }
+        if let Some(max_distinct_authors) = self.max_batch_authors {
+            policy.max_distinct_authors = Some(max_distinct_authors);
+        }
+        if let Some(request_types) = &self.flush_immediately {
+            policy.priorities = request_types
+                .iter()
+                .map(|request_type| (request_type.clone(), FlushPriority::Immediate))
+                .collect();
+        }
+        if let Some(channel_size) = self.deletion_channel_size {
+            policy.channel_size = channel_size;
+        }
+
+        policy.validate()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DeletionPolicyError {
+    #[error("Config error: {0}")]
+    Config(#[from] config::ConfigError),
+
+    #[error("Unknown request type: {0}")]
+    UnknownRequestType(String),
+}
+
+// Requests waiting to be flushed
+struct Batch {
+    requests: Vec<DeleteRequest>,
+    authors: HashSet<PublicKey>,
+    oldest: Option<Instant>,
+    // Requests written to the durable queue, the others failed to
+    queued: usize,
+}
+
+// A batch being flushed
+struct Chunk {
+    requests: Vec<DeleteRequest>,
+    queued: usize,
+}
+
+impl Batch {
+    fn new() -> Self {
+        Batch {
+            requests: Vec::new(),
+            authors: HashSet::new(),
+            oldest: None,
+            queued: 0,
+        }
+    }
+
+    fn push(&mut self, delete_request: DeleteRequest, queued: bool) {
+        self.authors.extend(delete_request.authors());
+        self.oldest.get_or_insert_with(Instant::now);
+        self.requests.push(delete_request);
+        if queued {
+            self.queued += 1;
+        }
+    }
+
+    fn take(&mut self) -> Chunk {
+        self.authors.clear();
+        self.oldest = None;
+        Chunk {
+            requests: std::mem::take(&mut self.requests),
+            queued: std::mem::take(&mut self.queued),
+        }
+    }
+
+    fn new_authors(&self, delete_request: &DeleteRequest) -> usize {
+        delete_request
+            .authors()
+            .iter()
+            .filter(|author| !self.authors.contains(author))
+            .count()
+    }
+}
+
+// The durable queue, the requests that failed to delete stay at its front
+// until the next start
+struct FlushedQueue {
+    queue: Arc<dyn DeletionQueue>,
+    failed: usize,
+}
+
+impl FlushedQueue {
+    // `count` is how many requests of the flushed batch are in the queue,
+    // requests that failed to be written aren't
+    async fn flushed(&mut self, count: usize, deleted: bool) {
+        if count == 0 {
+            return;
+        }
+        if !deleted {
+            self.failed += count;
+            return;
+        }
+
+        if let Err(e) = self.queue.remove_flushed(self.failed, count).await {
+            error!("Failed to compact the deletion queue: {}", e);
+        }
+    }
+}
 
+#[allow(clippy::too_many_arguments)]
 pub fn spawn_deletion_task<T: RawCommanderTrait>(
     tracker: &TaskTracker,
     mut deletion_receiver: mpsc::Receiver<DeleteRequest>,
     ack_sender: Option<mpsc::Sender<DeleteRequest>>,
     relay_commander: RelayCommander<T>,
-    buffer_size: NonZeroU16,
+    policy: DeletionPolicy,
     dry_run: bool,
+    health: Option<HealthState>,
+    queue: Option<Arc<dyn DeletionQueue>>,
 ) {
     tracker.spawn(async move {
-        let mut buffer = Vec::with_capacity(buffer_size.get() as usize);
-        let flush_period = time::Duration::from_secs(FLUSH_PERIOD_SECONDS);
+        let mut batch = Batch::new();
+        let max_batch_size = policy.max_batch_size.get() as usize;
 
-        info!("Publishing messages every {} seconds", FLUSH_PERIOD_SECONDS);
+        info!("Deletion policy: {:?}", policy);
 
-        let mut interval = time::interval(flush_period);
+        // Left over by a previous run that didn't get to flush them
+        let pending = match &queue {
+            Some(queue) => queue.pending().await,
+            None => Ok(Vec::new()),
+        };
+        let mut queue = queue.map(|queue| FlushedQueue { queue, failed: 0 });
+        match pending {
+            Ok(pending) if !pending.is_empty() => {
+                info!("Replaying {} queued delete requests", pending.len());
+                for delete_request in pending {
+                    batch.push(delete_request, true);
+                }
+                flush_buffer(&relay_commander, batch.take(), &ack_sender, dry_run, &health, &mut queue).await;
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to read the deletion queue: {}", e),
+        }
 
         loop {
+            let deadline = batch.oldest.map(|oldest| oldest + policy.max_latency());
+
             tokio::select! {
-                // The first condition to send the current buffer is the
-                // time interval. We wait a max of `flush_period_seconds`
-                // seconds, after that the buffer is cleared and sent
-                _ = interval.tick() => {
-                    flush_buffer(&relay_commander, &mut buffer, &ack_sender, dry_run).await;
+                // Nothing waits in the batch longer than the max latency
+                _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    flush_buffer(&relay_commander, batch.take(), &ack_sender, dry_run, &health, &mut queue).await;
                 }
 
                 recv_result = deletion_receiver.recv() => {
-                    match recv_result {
-                        Some(reject_reason) => {
-                            buffer.push(reject_reason);
+                    let Some(delete_request) = recv_result else {
+                        break;
+                    };
 
-                            // We also check if the buffer is full based on the buffer size
-                            if buffer.len() >= buffer_size.get() as usize {
-                                flush_buffer(&relay_commander, &mut buffer, &ack_sender, dry_run).await;
-                            }
-                        }
-                        None => {
-                            break;
+                    // Keeps the number of authors, and the size of the author
+                    // deletions, in check
+                    if let Some(max_distinct_authors) = policy.max_distinct_authors {
+                        let authors = batch.authors.len() + batch.new_authors(&delete_request);
+                        if !batch.requests.is_empty() && authors > max_distinct_authors.get() {
+                            flush_buffer(&relay_commander, batch.take(), &ack_sender, dry_run, &health, &mut queue).await;
                         }
                     }
+
+                    // Still deleted when it can't be queued, it just won't
+                    // survive a crash
+                    let queued = match &queue {
+                        Some(queue) => match queue.queue.push(&delete_request).await {
+                            Ok(()) => true,
+                            Err(e) => {
+                                error!("Failed to queue delete request: {}", e);
+                                false
+                            }
+                        },
+                        None => false,
+                    };
+
+                    let priority = policy.priority(&delete_request);
+                    batch.push(delete_request, queued);
+
+                    if priority == FlushPriority::Immediate || batch.requests.len() >= max_batch_size {
+                        flush_buffer(&relay_commander, batch.take(), &ack_sender, dry_run, &health, &mut queue).await;
+                    }
                 }
             }
         }
 
         // Flush any pending items before exiting
-        flush_buffer(&relay_commander, &mut buffer, &ack_sender, dry_run).await;
+        flush_buffer(&relay_commander, batch.take(), &ack_sender, dry_run, &health, &mut queue).await;
         debug!("Deletion task finished");
     });
 }
 
 async fn flush_buffer<T: RawCommanderTrait>(
     relay_commander: &RelayCommander<T>,
-    buffer: &mut Vec<DeleteRequest>,
+    chunk: Chunk,
     ack_sender: &Option<mpsc::Sender<DeleteRequest>>,
     dry_run: bool,
+    health: &Option<HealthState>,
+    queue: &mut Option<FlushedQueue>,
 ) {
-    debug!("Flushing delete command buffer, {} items", buffer.len());
+    let Chunk { requests, queued } = chunk;
+    debug!("Flushing delete command buffer, {} items", requests.len());
 
-    if !buffer.is_empty() {
-        let chunk_clone = buffer.clone();
-        let chunk = std::mem::take(buffer);
+    if !requests.is_empty() {
+        let chunk_clone = requests.clone();
 
-        if let Err(e) = relay_commander.execute_delete(chunk, dry_run).await {
+        if let Some(health) = health {
+            health.record_flush_started();
+        }
+
+        let error = relay_commander
+            .execute_delete(requests, dry_run)
+            .await
+            .err()
+            .map(|e| e.to_string());
+        if let Some(e) = &error {
             error!("{}", e);
         }
 
+        if let Some(queue) = queue {
+            queue.flushed(queued, error.is_none()).await;
+        }
+
+        if let Some(health) = health {
+            health.record_flush(error);
+        }
+
         if let Some(ack_sender) = ack_sender {
             for item in chunk_clone {
                 if let Err(e) = ack_sender.send(item).await {
@@ -86,13 +363,12 @@ async fn flush_buffer<T: RawCommanderTrait>(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::deletion_queue::{DeletionQueueError, FileDeletionQueue};
     use nostr_sdk::prelude::*;
     use std::collections::BTreeSet;
-    use std::num::NonZeroU16;
+    use std::sync::atomic::{AtomicBool, Ordering};
     use std::sync::{Arc, Mutex};
-    use tokio::sync::mpsc;
     use tokio::time::{self, Duration};
-    use tokio_util::task::TaskTracker;
 
     #[derive(Debug)]
     struct CommandRun {
@@ -112,17 +388,20 @@ mod tests {
             &self,
             filter: Filter,
             dry_run: bool,
-        ) -> Result<(), Box<dyn std::error::Error>> {
+        ) -> Result<Option<usize>, Box<dyn std::error::Error>> {
             let command_run = CommandRun { filter, dry_run };
             let mut executed_deletes = self.executed_deletes.lock().unwrap();
             executed_deletes.push(command_run);
-            Ok(())
+            Ok(None)
         }
     }
 
     #[tokio::test(start_paused = true)]
     async fn test_deletion_task() {
-        let buffer_size = NonZeroU16::new(3).unwrap(); // Small buffer size for testing
+        let policy = DeletionPolicy {
+            max_batch_size: nonzero!(3u16), // Small buffer size for testing
+            ..Default::default()
+        };
         let dry_run = false;
         let tracker = TaskTracker::new();
         let (deletion_sender, deletion_receiver) = mpsc::channel(10);
@@ -138,8 +417,10 @@ mod tests {
             deletion_receiver,
             Some(ack_sender),
             relay_commander,
-            buffer_size,
+            policy.clone(),
             dry_run,
+            None,
+            None,
         );
         tracker.close();
 
@@ -157,31 +438,34 @@ mod tests {
         deletion_sender.send(reply_copy.clone()).await.unwrap();
         deletion_sender.send(vanish.clone()).await.unwrap();
 
-        // Wait an interval cycle
-        time::advance(Duration::from_secs(FLUSH_PERIOD_SECONDS)).await;
+        // Wait for the max latency
+        time::advance(policy.max_latency()).await;
 
         // Check that execute_delete was called with the correct filters
         assert_executed_deletes(
             &executed_deletes,
             dry_run,
             vec![
+                // Vanish commands go first
                 CommandExpectation {
-                    expected_ids: Some(BTreeSet::from([event_id])),
-                    expected_authors: None,
+                    expected_ids: None,
+                    expected_authors: Some(BTreeSet::from([vanish_public_key])),
                     expected_pubkeys: None,
                 },
                 CommandExpectation {
                     expected_ids: None,
-                    expected_authors: Some(BTreeSet::from([
-                        forbidden_public_key,
-                        vanish_public_key,
-                    ])),
+                    expected_authors: None,
+                    expected_pubkeys: Some(BTreeSet::from([vanish_public_key])),
+                },
+                CommandExpectation {
+                    expected_ids: Some(BTreeSet::from([event_id])),
+                    expected_authors: None,
                     expected_pubkeys: None,
                 },
                 CommandExpectation {
                     expected_ids: None,
-                    expected_authors: None,
-                    expected_pubkeys: Some(BTreeSet::from([vanish_public_key])),
+                    expected_authors: Some(BTreeSet::from([forbidden_public_key])),
+                    expected_pubkeys: None,
                 },
             ],
         );
@@ -193,6 +477,276 @@ mod tests {
         tracker.wait().await;
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_policy() {
+        let tracker = TaskTracker::new();
+        let (deletion_sender, deletion_receiver) = mpsc::channel(10);
+        let (ack_sender, mut ack_receiver) = mpsc::channel(10);
+        let relay_commander = RelayCommander::new(MockRelayCommander {
+            executed_deletes: Arc::new(Mutex::new(Vec::new())),
+        });
+        let policy = DeletionPolicy {
+            max_batch_size: nonzero!(10u16),
+            max_latency_millis: 1000,
+            max_distinct_authors: Some(nonzero!(2usize)),
+            ..Default::default()
+        };
+
+        spawn_deletion_task(
+            &tracker,
+            deletion_receiver,
+            Some(ack_sender),
+            relay_commander,
+            policy,
+            false,
+            None,
+            None,
+        );
+        tracker.close();
+
+        let spam_authors: Vec<DeleteRequest> = (0..3)
+            .map(|_| DeleteRequest::SpamAuthor(Keys::generate().public_key()))
+            .collect();
+        for spam_author in &spam_authors {
+            deletion_sender.send(spam_author.clone()).await.unwrap();
+        }
+        time::sleep(Duration::from_millis(10)).await;
+
+        // The third author flushes the first two
+        assert_acks_received(&mut ack_receiver, spam_authors[..2].to_vec()).await;
+
+        time::sleep(Duration::from_millis(1000)).await;
+        assert_acks_received(&mut ack_receiver, spam_authors[2..].to_vec()).await;
+
+        // Vanish requests don't wait
+        let vanish =
+            DeleteRequest::Vanish("streamid".to_string(), Keys::generate().public_key(), None);
+        deletion_sender.send(vanish.clone()).await.unwrap();
+        time::sleep(Duration::from_millis(10)).await;
+        assert_acks_received(&mut ack_receiver, vec![vanish]).await;
+
+        drop(deletion_sender);
+        tracker.wait().await;
+    }
+
+    #[tokio::test]
+    async fn test_replays_the_queue() {
+        let path = std::env::temp_dir().join(format!(
+            "deletion_task_queue-{}.jsonl",
+            Keys::generate().public_key().to_hex()
+        ));
+        let queue = Arc::new(FileDeletionQueue::open(&path).await.unwrap());
+        let spam_author = DeleteRequest::SpamAuthor(Keys::generate().public_key());
+        queue.push(&spam_author).await.unwrap();
+
+        let tracker = TaskTracker::new();
+        let (deletion_sender, deletion_receiver) = mpsc::channel(10);
+        let (ack_sender, mut ack_receiver) = mpsc::channel(10);
+        let relay_commander = RelayCommander::new(MockRelayCommander {
+            executed_deletes: Arc::new(Mutex::new(Vec::new())),
+        });
+
+        spawn_deletion_task(
+            &tracker,
+            deletion_receiver,
+            Some(ack_sender),
+            relay_commander,
+            DeletionPolicy::default(),
+            false,
+            None,
+            Some(queue.clone()),
+        );
+        tracker.close();
+
+        assert_eq!(ack_receiver.recv().await, Some(spam_author));
+        assert!(queue.pending().await.unwrap().is_empty());
+
+        drop(deletion_sender);
+        tracker.wait().await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    // Fails deleting the events of one author
+    struct FailingCommander(PublicKey);
+
+    #[async_trait::async_trait]
+    impl RawCommanderTrait for FailingCommander {
+        async fn delete_from_filter(
+            &self,
+            filter: Filter,
+            _dry_run: bool,
+        ) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+            match filter.authors {
+                Some(authors) if authors.contains(&self.0) => Err("strfry failed".into()),
+                _ => Ok(None),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_deletes_stay_queued() {
+        let path = std::env::temp_dir().join(format!(
+            "deletion_task_failed-{}.jsonl",
+            Keys::generate().public_key().to_hex()
+        ));
+        let queue = Arc::new(FileDeletionQueue::open(&path).await.unwrap());
+        let failing_author = Keys::generate().public_key();
+
+        let tracker = TaskTracker::new();
+        let (deletion_sender, deletion_receiver) = mpsc::channel(10);
+        let (ack_sender, mut ack_receiver) = mpsc::channel(10);
+        spawn_deletion_task(
+            &tracker,
+            deletion_receiver,
+            Some(ack_sender),
+            RelayCommander::new(FailingCommander(failing_author)),
+            DeletionPolicy {
+                max_batch_size: nonzero!(1u16),
+                ..Default::default()
+            },
+            false,
+            None,
+            Some(queue.clone()),
+        );
+        tracker.close();
+
+        let failed = DeleteRequest::SpamAuthor(failing_author);
+        let requests = [
+            DeleteRequest::SpamAuthor(Keys::generate().public_key()),
+            failed.clone(),
+            DeleteRequest::SpamAuthor(Keys::generate().public_key()),
+            DeleteRequest::SpamAuthor(Keys::generate().public_key()),
+        ];
+        for request in &requests {
+            deletion_sender.send(request.clone()).await.unwrap();
+            assert_eq!(ack_receiver.recv().await.as_ref(), Some(request));
+        }
+
+        // Only the failed request is left for the next start
+        assert_eq!(queue.pending().await.unwrap(), vec![failed]);
+
+        drop(deletion_sender);
+        tracker.wait().await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    // Fails the first push
+    struct FlakyQueue {
+        inner: FileDeletionQueue,
+        fail_push: AtomicBool,
+    }
+
+    #[async_trait::async_trait]
+    impl DeletionQueue for FlakyQueue {
+        async fn push(&self, delete_request: &DeleteRequest) -> Result<(), DeletionQueueError> {
+            if self.fail_push.swap(false, Ordering::SeqCst) {
+                return Err(std::io::Error::other("disk full").into());
+            }
+            self.inner.push(delete_request).await
+        }
+
+        async fn pending(&self) -> Result<Vec<DeleteRequest>, DeletionQueueError> {
+            self.inner.pending().await
+        }
+
+        async fn remove_flushed(
+            &self,
+            skip: usize,
+            count: usize,
+        ) -> Result<(), DeletionQueueError> {
+            self.inner.remove_flushed(skip, count).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unqueued_requests_are_not_removed_from_queue() {
+        let path = std::env::temp_dir().join(format!(
+            "deletion_task_flaky-{}.jsonl",
+            Keys::generate().public_key().to_hex()
+        ));
+        let queue = Arc::new(FlakyQueue {
+            inner: FileDeletionQueue::open(&path).await.unwrap(),
+            fail_push: AtomicBool::new(true),
+        });
+        let failing_author = Keys::generate().public_key();
+
+        let tracker = TaskTracker::new();
+        let (deletion_sender, deletion_receiver) = mpsc::channel(10);
+        let (ack_sender, mut ack_receiver) = mpsc::channel(10);
+        spawn_deletion_task(
+            &tracker,
+            deletion_receiver,
+            Some(ack_sender),
+            RelayCommander::new(FailingCommander(failing_author)),
+            DeletionPolicy {
+                max_batch_size: nonzero!(2u16),
+                ..Default::default()
+            },
+            false,
+            None,
+            Some(queue.clone()),
+        );
+        tracker.close();
+
+        // The first request isn't queued and its batch fails
+        let kept = DeleteRequest::SpamAuthor(Keys::generate().public_key());
+        let batches = [
+            [DeleteRequest::SpamAuthor(failing_author), kept.clone()],
+            [
+                DeleteRequest::SpamAuthor(Keys::generate().public_key()),
+                DeleteRequest::SpamAuthor(Keys::generate().public_key()),
+            ],
+        ];
+        for batch in &batches {
+            for request in batch {
+                deletion_sender.send(request.clone()).await.unwrap();
+            }
+            for request in batch {
+                assert_eq!(ack_receiver.recv().await.as_ref(), Some(request));
+            }
+        }
+
+        // Only the failed request that was queued is left
+        assert_eq!(queue.pending().await.unwrap(), vec![kept]);
+
+        drop(deletion_sender);
+        tracker.wait().await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_policy_layers() {
+        let args = DeletionPolicyArgs {
+            deletion_policy: None,
+            max_batch_size: Some(nonzero!(200u16)),
+            max_batch_latency_millis: None,
+            max_batch_authors: None,
+            flush_immediately: None,
+            deletion_channel_size: None,
+        };
+        let defaults = DeletionPolicy {
+            channel_size: nonzero!(100usize),
+            ..Default::default()
+        };
+
+        let policy = args.policy(defaults).unwrap();
+        assert_eq!(policy.max_batch_size, nonzero!(200u16));
+        assert_eq!(policy.channel_size, nonzero!(100usize));
+        assert_eq!(
+            policy.priorities.get("vanish"),
+            Some(&FlushPriority::Immediate)
+        );
+
+        let args = DeletionPolicyArgs {
+            flush_immediately: Some(vec!["vanihs".to_string()]),
+            ..args
+        };
+        assert!(matches!(
+            args.policy(DeletionPolicy::default()),
+            Err(DeletionPolicyError::UnknownRequestType(_))
+        ));
+    }
+
     struct CommandExpectation {
         expected_ids: Option<BTreeSet<EventId>>,
         expected_authors: Option<BTreeSet<PublicKey>>,
@@ -209,16 +763,12 @@ mod tests {
 
         const P_TAG: SingleLetterTag = SingleLetterTag::lowercase(Alphabet::P);
         for (command_run, expectation) in executed.iter().zip(expected_commands.iter()) {
-            let pubkeys = if let Some(p_tag) = command_run.filter.generic_tags.get(&P_TAG) {
-                Some(
-                    p_tag
-                        .iter()
-                        .filter_map(|p| PublicKey::from_hex(p).ok())
-                        .collect::<BTreeSet<_>>(),
-                )
-            } else {
-                None
-            };
+            let pubkeys = command_run.filter.generic_tags.get(&P_TAG).map(|p_tag| {
+                p_tag
+                    .iter()
+                    .filter_map(|p| PublicKey::from_hex(p).ok())
+                    .collect::<BTreeSet<_>>()
+            });
 
             assert_eq!(command_run.dry_run, expected_dry_run);
             assert_eq!(&command_run.filter.ids, &expectation.expected_ids);
@@ -1,49 +1,206 @@
+pub mod classifier;
+pub mod duplicates;
+pub mod impersonation;
+pub mod kind_policy;
+pub mod links;
+pub mod nip05;
+pub mod pow;
+pub mod rules_config;
+pub mod tag_limits;
+
+use crate::exemptions::Exemptions;
+use crate::settings::Settings;
+use async_trait::async_trait;
 use nostr_sdk::prelude::*;
 use redis::{streams::StreamId, Value};
 use regex::Regex;
-use std::env;
+use rules_config::RulesConfig;
+use serde::{Deserialize, Serialize};
 use std::fmt::Display;
-use std::sync::LazyLock;
+use std::sync::{Arc, LazyLock};
 use thiserror::Error as ThisError;
 use tokio::time::Duration;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::debug;
 
-// TODO: get port from args
-static LOCAL_RELAY_URL: &str = "ws://localhost:7777";
+pub const REPLY_COPY_RULE_ID: &str = "reply_copy";
+pub const FORBIDDEN_NAME_RULE_ID: &str = "forbidden_name";
 
 static REJECTED_NAME_REGEXES: LazyLock<Vec<Regex>> =
     LazyLock::new(|| vec![Regex::new(r".*Reply.*(Guy|Girl|Gal).*").unwrap()]);
 
-static RELAY_URL: LazyLock<String> =
-    LazyLock::new(|| env::var("RELAY_URL").expect("RELAY_URL must be set"));
-
 #[derive(Debug, Clone)]
 pub enum EventAnalysisResult {
     Accept,
-    Reject(DeleteRequest),
+    Reject(Box<Rejection>),
+}
+
+/// Why an event was rejected: the rule that matched, what it wants deleted
+/// and, when the rule can tell, what it matched on.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Rejection {
+    pub rule_id: &'static str,
+    pub delete_request: DeleteRequest,
+    pub evidence: Option<Evidence>,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+impl Rejection {
+    pub fn new(rule_id: &'static str, delete_request: DeleteRequest) -> Self {
+        Rejection {
+            rule_id,
+            delete_request,
+            evidence: None,
+        }
+    }
+
+    pub fn with_evidence(mut self, evidence: Evidence) -> Self {
+        self.evidence = Some(evidence);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Evidence {
+    /// The pattern a name matched
+    Regex(String),
+    /// The event this one is too similar to
+    SimilarEvent(EventId),
+    /// The protected account a profile copies and the signals that matched
+    Impersonates {
+        pubkey: PublicKey,
+        signals: Vec<&'static str>,
+    },
+    /// What an external classifier answered
+    Score { score: f64, labels: Vec<String> },
+    /// Anything else a rule wants to explain itself with
+    Detail(String),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum DeleteRequest {
     ReplyCopy(EventId),
     ForbiddenName(PublicKey),
+    Impersonation(PublicKey),
+    BlockedDomain(EventId, String),
+    InsufficientPow(EventId),
+    TagSpam(EventId),
+    DisallowedKind(EventId),
+    DuplicateCluster(Vec<EventId>, Vec<PublicKey>),
+    SpamScore(EventId),
+    SpamAuthor(PublicKey),
     Vanish(String, PublicKey, Option<String>),
 }
 
+impl DeleteRequest {
+    /// Events deleted by id
+    pub fn event_ids(&self) -> Vec<EventId> {
+        match self {
+            DeleteRequest::ReplyCopy(id)
+            | DeleteRequest::BlockedDomain(id, _)
+            | DeleteRequest::InsufficientPow(id)
+            | DeleteRequest::TagSpam(id)
+            | DeleteRequest::DisallowedKind(id)
+            | DeleteRequest::SpamScore(id) => vec![*id],
+            DeleteRequest::DuplicateCluster(ids, _) => ids.clone(),
+            DeleteRequest::ForbiddenName(_)
+            | DeleteRequest::Impersonation(_)
+            | DeleteRequest::SpamAuthor(_)
+            | DeleteRequest::Vanish(_, _, _) => vec![],
+        }
+    }
+
+    /// Authors whose events are all deleted
+    pub fn authors(&self) -> Vec<PublicKey> {
+        match self {
+            DeleteRequest::ForbiddenName(pubkey)
+            | DeleteRequest::Impersonation(pubkey)
+            | DeleteRequest::SpamAuthor(pubkey)
+            | DeleteRequest::Vanish(_, pubkey, _) => vec![*pubkey],
+            DeleteRequest::DuplicateCluster(_, authors) => authors.clone(),
+            DeleteRequest::ReplyCopy(_)
+            | DeleteRequest::BlockedDomain(_, _)
+            | DeleteRequest::InsufficientPow(_)
+            | DeleteRequest::TagSpam(_)
+            | DeleteRequest::DisallowedKind(_)
+            | DeleteRequest::SpamScore(_) => vec![],
+        }
+    }
+
+    /// The rule producing this kind of request, vanish requests come from users
+    pub fn rule_id(&self) -> Option<&'static str> {
+        match self {
+            DeleteRequest::ReplyCopy(_) => Some(REPLY_COPY_RULE_ID),
+            DeleteRequest::ForbiddenName(_) => Some(FORBIDDEN_NAME_RULE_ID),
+            DeleteRequest::Impersonation(_) => Some(impersonation::RULE_ID),
+            DeleteRequest::BlockedDomain(_, _) => Some(links::RULE_ID),
+            DeleteRequest::InsufficientPow(_) => Some(pow::RULE_ID),
+            DeleteRequest::TagSpam(_) => Some(tag_limits::RULE_ID),
+            DeleteRequest::DisallowedKind(_) => Some(kind_policy::RULE_ID),
+            DeleteRequest::DuplicateCluster(_, _) => Some(duplicates::RULE_ID),
+            DeleteRequest::SpamScore(_) | DeleteRequest::SpamAuthor(_) => Some(classifier::RULE_ID),
+            DeleteRequest::Vanish(_, _, _) => None,
+        }
+    }
+
+    /// Every `request_type`, for the configs keyed by it
+    pub const REQUEST_TYPES: &'static [&'static str] = &[
+        "reply_copy",
+        "forbidden_name",
+        "impersonation",
+        "blocked_domain",
+        "insufficient_pow",
+        "tag_spam",
+        "disallowed_kind",
+        "duplicate_cluster",
+        "spam_score",
+        "spam_author",
+        "vanish",
+    ];
+
+    pub fn request_type(&self) -> &'static str {
+        match self {
+            DeleteRequest::ReplyCopy(_) => "reply_copy",
+            DeleteRequest::ForbiddenName(_) => "forbidden_name",
+            DeleteRequest::Impersonation(_) => "impersonation",
+            DeleteRequest::BlockedDomain(_, _) => "blocked_domain",
+            DeleteRequest::InsufficientPow(_) => "insufficient_pow",
+            DeleteRequest::TagSpam(_) => "tag_spam",
+            DeleteRequest::DisallowedKind(_) => "disallowed_kind",
+            DeleteRequest::DuplicateCluster(_, _) => "duplicate_cluster",
+            DeleteRequest::SpamScore(_) => "spam_score",
+            DeleteRequest::SpamAuthor(_) => "spam_author",
+            DeleteRequest::Vanish(_, _, _) => "vanish",
+        }
+    }
+}
+
 impl Display for DeleteRequest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             DeleteRequest::ReplyCopy(_) => write!(f, "Reply copy"),
             DeleteRequest::ForbiddenName(_) => write!(f, "Forbidden nip05"),
+            DeleteRequest::Impersonation(_) => write!(f, "Impersonation"),
+            DeleteRequest::BlockedDomain(_, domain) => write!(f, "Blocked domain {}", domain),
+            DeleteRequest::InsufficientPow(_) => write!(f, "Insufficient proof of work"),
+            DeleteRequest::TagSpam(_) => write!(f, "Tag spam"),
+            DeleteRequest::DisallowedKind(_) => write!(f, "Disallowed kind"),
+            DeleteRequest::DuplicateCluster(_, _) => write!(f, "Duplicate content cluster"),
+            DeleteRequest::SpamScore(_) => write!(f, "Classified as spam"),
+            DeleteRequest::SpamAuthor(_) => write!(f, "Author classified as spammer"),
             DeleteRequest::Vanish(_, _, _) => write!(f, "Request to vanish"),
         }
     }
 }
 
-impl TryFrom<&StreamId> for DeleteRequest {
-    type Error = EventAnalysisError;
-
-    fn try_from(stream_id: &StreamId) -> Result<Self, Self::Error> {
+impl DeleteRequest {
+    /// Reads a vanish request pushed to the stream by the strfry plugin. It
+    /// must be tagged with `all_relays` or with this relay's URL
+    pub fn from_vanish_stream_id(
+        stream_id: &StreamId,
+        relay_url: &str,
+    ) -> Result<Self, EventAnalysisError> {
         let mut reason = Option::<String>::None;
         let mut public_key = Option::<PublicKey>::None;
         let mut has_matching_tag = false;
@@ -85,7 +242,7 @@ impl TryFrom<&StreamId> for DeleteRequest {
                             .map_err(|_| EventAnalysisError::ConversionError)?;
 
                         has_matching_tag = tags.split(',').any(|tag| {
-                            tag.to_lowercase() == "all_relays" || tag.to_lowercase() == *RELAY_URL
+                            tag.to_lowercase() == "all_relays" || tag.to_lowercase() == relay_url
                         });
                     }
                 }
@@ -110,13 +267,30 @@ impl TryFrom<&StreamId> for DeleteRequest {
     }
 }
 
+/// A check the `Validator` runs on every event after the built-in ones. Rules
+/// are tried in order and the first one returning a `DeleteRequest` wins.
+#[async_trait]
+pub trait Rule: Send + Sync + 'static {
+    /// The module's `RULE_ID`, so `DeleteRequest::rule_id` agrees with it
+    fn id(&self) -> &'static str;
+
+    async fn evaluate(&self, event: &Event) -> Result<Option<Rejection>, EventAnalysisError>;
+}
+
 #[derive(Clone)]
 pub struct Validator {
     nostr_client: Client,
+    rules: Arc<Vec<Box<dyn Rule>>>,
+    exemptions: Exemptions,
 }
 
 impl Validator {
-    pub async fn new() -> Result<Self, EventAnalysisError> {
+    pub async fn new(
+        rules_config: &RulesConfig,
+        settings: &Settings,
+        tracker: &TaskTracker,
+        cancellation_token: &CancellationToken,
+    ) -> Result<Self, EventAnalysisError> {
         let opts = Options::default()
             .skip_disconnected_relays(true)
             .wait_for_send(false)
@@ -125,13 +299,28 @@ impl Validator {
             .wait_for_subscription(true);
 
         let nostr_client = ClientBuilder::default().opts(opts).build();
-        if let Err(e) = nostr_client.add_relay(LOCAL_RELAY_URL).await {
+        if let Err(e) = nostr_client.add_relay(&settings.local_relay_url).await {
             return Err(EventAnalysisError::ConnectionError(e));
         }
 
         nostr_client.connect().await;
 
-        Ok(Validator { nostr_client })
+        let rules = rules_config
+            .build_rules(&nostr_client, settings, tracker, cancellation_token)
+            .await?;
+
+        Ok(Validator {
+            nostr_client,
+            rules: Arc::new(rules),
+            exemptions: Exemptions::default(),
+        })
+    }
+
+    /// Author-level rejections of exempt pubkeys are skipped, the next rules
+    /// still get a chance to reject the event itself
+    pub fn with_exemptions(mut self, exemptions: Exemptions) -> Self {
+        self.exemptions = exemptions;
+        self
     }
 
     pub async fn validate_event(
@@ -143,25 +332,62 @@ impl Validator {
         let (is_reply_copy_res, is_forbidden_name_res) =
             tokio::join!(self.is_reply_copy(&event), self.is_forbidden_name(&event));
 
-        let is_reply_copy = is_reply_copy_res?;
-        let is_forbidden_name = is_forbidden_name_res?;
+        let copied_event = is_reply_copy_res?;
+        let forbidden_name = is_forbidden_name_res?;
 
-        if is_reply_copy {
-            return Ok(EventAnalysisResult::Reject(DeleteRequest::ReplyCopy(
-                event.id,
+        if let Some(copied_event) = copied_event {
+            return Ok(EventAnalysisResult::Reject(Box::new(
+                Rejection::new(REPLY_COPY_RULE_ID, DeleteRequest::ReplyCopy(event.id))
+                    .with_evidence(Evidence::SimilarEvent(copied_event)),
             )));
         }
 
-        if is_forbidden_name {
-            return Ok(EventAnalysisResult::Reject(DeleteRequest::ForbiddenName(
-                event.pubkey,
-            )));
+        if let Some(regex) = forbidden_name {
+            let rejection = Rejection::new(
+                FORBIDDEN_NAME_RULE_ID,
+                DeleteRequest::ForbiddenName(event.pubkey),
+            )
+            .with_evidence(Evidence::Regex(regex));
+            if let Some(rejection) = self.apply_exemptions(rejection).await? {
+                return Ok(EventAnalysisResult::Reject(Box::new(rejection)));
+            }
+        }
+
+        for rule in self.rules.iter() {
+            if let Some(rejection) = rule.evaluate(&event).await? {
+                debug!("Rule {} matched event {}", rule.id(), event.id);
+                if let Some(rejection) = self.apply_exemptions(rejection).await? {
+                    return Ok(EventAnalysisResult::Reject(Box::new(rejection)));
+                }
+            }
         }
 
         Ok(EventAnalysisResult::Accept)
     }
 
-    async fn is_forbidden_name(&self, event: &Event) -> Result<bool, EventAnalysisError> {
+    // Fails when the exemptions can't be read, so the event is retried
+    // instead of deleting an author who may be exempt
+    async fn apply_exemptions(
+        &self,
+        rejection: Rejection,
+    ) -> Result<Option<Rejection>, EventAnalysisError> {
+        if rejection.delete_request.authors().is_empty() {
+            return Ok(Some(rejection));
+        }
+
+        let delete_request = self
+            .exemptions
+            .apply(rejection.delete_request)
+            .await
+            .map_err(|e| EventAnalysisError::ExemptionError(e.to_string()))?;
+        Ok(delete_request.map(|delete_request| Rejection {
+            delete_request,
+            ..rejection
+        }))
+    }
+
+    /// The regex matched by the author's names, if any
+    async fn is_forbidden_name(&self, event: &Event) -> Result<Option<String>, EventAnalysisError> {
         let filters: Vec<Filter> = vec![Filter::new()
             .author(event.pubkey)
             .kind(Kind::Metadata)
@@ -172,31 +398,28 @@ impl Validator {
             .get_events_of(filters, EventSource::both(None))
             .await
         else {
-            return Ok(false);
+            return Ok(None);
         };
 
         let Some(metadata_event) = events.pop() else {
-            return Ok(false);
+            return Ok(None);
         };
 
         let Ok(metadata) = Metadata::from_json(metadata_event.content) else {
-            return Ok(false);
+            return Ok(None);
         };
 
         let forbidden = [metadata.nip05, metadata.name, metadata.display_name]
             .iter()
-            .any(|name| {
-                if let Some(name) = name {
-                    REJECTED_NAME_REGEXES.iter().any(|re| re.is_match(name))
-                } else {
-                    false
-                }
-            });
+            .flatten()
+            .find_map(|name| REJECTED_NAME_REGEXES.iter().find(|re| re.is_match(name)))
+            .map(|re| re.as_str().to_string());
 
         Ok(forbidden)
     }
 
-    async fn is_reply_copy(&self, event: &Event) -> Result<bool, EventAnalysisError> {
+    /// The event this one copies the content of, if any
+    async fn is_reply_copy(&self, event: &Event) -> Result<Option<EventId>, EventAnalysisError> {
         for event_id in event_ids(event) {
             let filters = vec![Filter::new().id(*event_id)];
 
@@ -225,11 +448,11 @@ impl Validator {
                     "Event {} is a copy of event {}",
                     event.id, referenced_event.id
                 );
-                return Ok(true);
+                return Ok(Some(referenced_event.id));
             }
         }
 
-        Ok(false)
+        Ok(None)
     }
 }
 
@@ -252,10 +475,10 @@ pub enum EventAnalysisError {
     ValidationError(String),
 
     #[error("Connection error: {0}")]
-    ConnectionError(Error),
+    ConnectionError(nostr_sdk::client::Error),
 
     #[error("Nostr error: {0}")]
-    NostrError(Error),
+    NostrError(nostr_sdk::client::Error),
 
     #[error("Conversion error")]
     ConversionError,
@@ -268,4 +491,66 @@ pub enum EventAnalysisError {
 
     #[error("No matching tag")]
     NoMatchingTag,
+
+    #[error("Config error: {0}")]
+    ConfigError(String),
+
+    #[error("NIP-05 error: {0}")]
+    Nip05Error(String),
+
+    #[error("Classifier error: {0}")]
+    ClassifierError(String),
+
+    #[error("Exemption error: {0}")]
+    ExemptionError(String),
+
+    #[error("Redis error: {0}")]
+    RedisError(redis::RedisError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_types_lists_every_variant() {
+        let id = EventId::all_zeros();
+        let pubkey = Keys::generate().public_key();
+        let requests = [
+            DeleteRequest::ReplyCopy(id),
+            DeleteRequest::ForbiddenName(pubkey),
+            DeleteRequest::Impersonation(pubkey),
+            DeleteRequest::BlockedDomain(id, "example.com".to_string()),
+            DeleteRequest::InsufficientPow(id),
+            DeleteRequest::TagSpam(id),
+            DeleteRequest::DisallowedKind(id),
+            DeleteRequest::DuplicateCluster(vec![id], vec![pubkey]),
+            DeleteRequest::SpamScore(id),
+            DeleteRequest::SpamAuthor(pubkey),
+            DeleteRequest::Vanish("1-0".to_string(), pubkey, None),
+        ];
+
+        for request in &requests {
+            // Fails to build when a variant is added, add it to the list above
+            match request {
+                DeleteRequest::ReplyCopy(_)
+                | DeleteRequest::ForbiddenName(_)
+                | DeleteRequest::Impersonation(_)
+                | DeleteRequest::BlockedDomain(_, _)
+                | DeleteRequest::InsufficientPow(_)
+                | DeleteRequest::TagSpam(_)
+                | DeleteRequest::DisallowedKind(_)
+                | DeleteRequest::DuplicateCluster(_, _)
+                | DeleteRequest::SpamScore(_)
+                | DeleteRequest::SpamAuthor(_)
+                | DeleteRequest::Vanish(_, _, _) => {}
+            }
+            assert!(
+                DeleteRequest::REQUEST_TYPES.contains(&request.request_type()),
+                "{} is missing from REQUEST_TYPES",
+                request.request_type()
+            );
+        }
+        assert_eq!(DeleteRequest::REQUEST_TYPES.len(), requests.len());
+    }
 }
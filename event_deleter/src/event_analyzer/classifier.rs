@@ -0,0 +1,644 @@
+use super::links::event_links;
+use super::{DeleteRequest, EventAnalysisError, EventAnalysisResult, Evidence, Rejection, Rule};
+use async_trait::async_trait;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::time::{timeout, Duration, Instant};
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClassifierConfig {
+    pub endpoint: ClassifierEndpoint,
+
+    /// What is sent for each event, the whole event or just its features
+    #[serde(default)]
+    pub payload: ClassifierPayload,
+
+    /// Past this the event is accepted, a slow classifier must not hold up
+    /// the worker pool
+    #[serde(default = "default_timeout_millis")]
+    pub timeout_millis: u64,
+
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+
+    #[serde(default)]
+    pub thresholds: ClassifierThresholds,
+}
+
+fn default_timeout_millis() -> u64 {
+    500
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClassifierEndpoint {
+    /// JSON POSTed to the url, the response body is the classification
+    Http(Url),
+    /// One JSON line written per event, one JSON line read back
+    UnixSocket(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClassifierPayload {
+    #[default]
+    Event,
+    Features,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures, timeouts included, that open the circuit
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+
+    /// How long the classifier is skipped once the circuit opens
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: default_failure_threshold(),
+            cooldown_secs: default_cooldown_secs(),
+        }
+    }
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_cooldown_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ClassifierThresholds {
+    /// Events scoring at least this are deleted
+    #[serde(default = "default_reject_event")]
+    pub reject_event: f64,
+
+    /// Authors of events scoring at least this are deleted altogether.
+    /// Disabled when not set
+    pub reject_author: Option<f64>,
+}
+
+impl Default for ClassifierThresholds {
+    fn default() -> Self {
+        ClassifierThresholds {
+            reject_event: default_reject_event(),
+            reject_author: None,
+        }
+    }
+}
+
+fn default_reject_event() -> f64 {
+    0.9
+}
+
+impl ClassifierThresholds {
+    pub fn result(&self, event: &Event, classification: &Classification) -> EventAnalysisResult {
+        if self
+            .reject_author
+            .is_some_and(|threshold| classification.score >= threshold)
+        {
+            return EventAnalysisResult::Reject(Box::new(
+                Rejection::new(RULE_ID, DeleteRequest::SpamAuthor(event.pubkey))
+                    .with_evidence(classification.evidence()),
+            ));
+        }
+
+        if classification.score >= self.reject_event {
+            return EventAnalysisResult::Reject(Box::new(
+                Rejection::new(RULE_ID, DeleteRequest::SpamScore(event.id))
+                    .with_evidence(classification.evidence()),
+            ));
+        }
+
+        EventAnalysisResult::Accept
+    }
+}
+
+/// What the classifier answers for each event, a score between 0 (ham) and 1
+/// (spam) and whatever labels it wants to explain it with.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Classification {
+    pub score: f64,
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+impl Classification {
+    fn evidence(&self) -> Evidence {
+        Evidence::Score {
+            score: self.score,
+            labels: self.labels.clone(),
+        }
+    }
+}
+
+/// A compact summary of an event for classifiers that don't need the content
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EventFeatures {
+    pub pubkey: PublicKey,
+    pub kind: u16,
+    pub created_at: u64,
+    pub content_length: usize,
+    pub tag_count: usize,
+    pub p_tag_count: usize,
+    pub e_tag_count: usize,
+    pub link_count: usize,
+}
+
+impl EventFeatures {
+    pub fn from_event(event: &Event) -> Self {
+        let count_tags = |name: &str| {
+            event
+                .tags
+                .iter()
+                .filter(|tag| tag.as_slice().first().map(String::as_str) == Some(name))
+                .count()
+        };
+
+        EventFeatures {
+            pubkey: event.pubkey,
+            kind: event.kind.as_u16(),
+            created_at: event.created_at.as_u64(),
+            content_length: event.content.chars().count(),
+            tag_count: event.tags.len(),
+            p_tag_count: count_tags("p"),
+            e_tag_count: count_tags("e"),
+            link_count: event_links(event).count(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ClassifierRequest<'a> {
+    Event(&'a Event),
+    Features(EventFeatures),
+}
+
+impl<'a> ClassifierRequest<'a> {
+    fn new(payload: ClassifierPayload, event: &'a Event) -> Self {
+        match payload {
+            ClassifierPayload::Event => ClassifierRequest::Event(event),
+            ClassifierPayload::Features => {
+                ClassifierRequest::Features(EventFeatures::from_event(event))
+            }
+        }
+    }
+}
+
+/// Scores an event, usually by asking a model running outside this process.
+#[async_trait]
+pub trait Classifier: Send + Sync + 'static {
+    async fn classify(&self, event: &Event) -> Result<Classification, EventAnalysisError>;
+}
+
+pub struct HttpClassifier {
+    http_client: reqwest::Client,
+    url: Url,
+    payload: ClassifierPayload,
+}
+
+impl HttpClassifier {
+    pub fn new(url: Url, payload: ClassifierPayload) -> Result<Self, EventAnalysisError> {
+        let http_client = reqwest::Client::builder()
+            .build()
+            .map_err(|e| EventAnalysisError::ClassifierError(e.to_string()))?;
+
+        Ok(HttpClassifier {
+            http_client,
+            url,
+            payload,
+        })
+    }
+}
+
+#[async_trait]
+impl Classifier for HttpClassifier {
+    async fn classify(&self, event: &Event) -> Result<Classification, EventAnalysisError> {
+        self.http_client
+            .post(self.url.clone())
+            .json(&ClassifierRequest::new(self.payload, event))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| EventAnalysisError::ClassifierError(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| EventAnalysisError::ClassifierError(e.to_string()))
+    }
+}
+
+pub struct UnixSocketClassifier {
+    path: PathBuf,
+    payload: ClassifierPayload,
+}
+
+impl UnixSocketClassifier {
+    pub fn new(path: PathBuf, payload: ClassifierPayload) -> Self {
+        UnixSocketClassifier { path, payload }
+    }
+}
+
+#[async_trait]
+impl Classifier for UnixSocketClassifier {
+    async fn classify(&self, event: &Event) -> Result<Classification, EventAnalysisError> {
+        let classifier_error =
+            |e: std::io::Error| EventAnalysisError::ClassifierError(e.to_string());
+
+        let mut request = serde_json::to_string(&ClassifierRequest::new(self.payload, event))
+            .map_err(|e| EventAnalysisError::ClassifierError(e.to_string()))?;
+        request.push('\n');
+
+        // A connection per event keeps a crashed classifier from leaving us
+        // with a broken stream
+        let stream = UnixStream::connect(&self.path)
+            .await
+            .map_err(classifier_error)?;
+        let (reader, mut writer) = stream.into_split();
+        writer
+            .write_all(request.as_bytes())
+            .await
+            .map_err(classifier_error)?;
+
+        let mut line = String::new();
+        BufReader::new(reader)
+            .read_line(&mut line)
+            .await
+            .map_err(classifier_error)?;
+
+        serde_json::from_str(&line).map_err(|e| EventAnalysisError::ClassifierError(e.to_string()))
+    }
+}
+
+struct CircuitState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    // When the half-open probe was let through, if it hasn't finished
+    probe_started: Option<Instant>,
+}
+
+/// Stops calling a classifier that keeps failing. Once the cooldown is over
+/// a single probe call is let through: it closes the circuit if it succeeds
+/// and reopens it for another cooldown if it fails.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<CircuitState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            failure_threshold: config.failure_threshold.max(1),
+            cooldown: Duration::from_secs(config.cooldown_secs),
+            state: Mutex::new(CircuitState {
+                consecutive_failures: 0,
+                open_until: None,
+                probe_started: None,
+            }),
+        }
+    }
+
+    /// Whether calls are being skipped, during the cooldown or while the
+    /// probe is in flight
+    pub fn is_open(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        let Some(open_until) = state.open_until else {
+            return false;
+        };
+        Instant::now() < open_until || state.probe_started.is_some()
+    }
+
+    /// Whether a call can go through. Past the cooldown the first caller
+    /// gets the probe, the others are skipped until it reports back. A
+    /// probe that never does is replaced after another cooldown
+    pub fn try_call(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(open_until) = state.open_until else {
+            return true;
+        };
+
+        let now = Instant::now();
+        if now < open_until {
+            return false;
+        }
+        if let Some(probe_started) = state.probe_started {
+            if now < probe_started + self.cooldown {
+                return false;
+            }
+        }
+
+        state.probe_started = Some(now);
+        true
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = 0;
+        state.open_until = None;
+        state.probe_started = None;
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        if state.probe_started.take().is_some()
+            || state.consecutive_failures >= self.failure_threshold
+        {
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+/// Runs the configured classifier and maps its score through the thresholds.
+/// Any failure accepts the event, the classifier is only a helper.
+pub struct ClassifierRule {
+    classifier: Box<dyn Classifier>,
+    timeout: Duration,
+    circuit_breaker: CircuitBreaker,
+    thresholds: ClassifierThresholds,
+}
+
+impl ClassifierRule {
+    pub fn new(
+        classifier: Box<dyn Classifier>,
+        timeout: Duration,
+        circuit_breaker: CircuitBreaker,
+        thresholds: ClassifierThresholds,
+    ) -> Self {
+        ClassifierRule {
+            classifier,
+            timeout,
+            circuit_breaker,
+            thresholds,
+        }
+    }
+
+    pub fn from_config(config: &ClassifierConfig) -> Result<Self, EventAnalysisError> {
+        let classifier: Box<dyn Classifier> = match &config.endpoint {
+            ClassifierEndpoint::Http(url) => {
+                Box::new(HttpClassifier::new(url.clone(), config.payload)?)
+            }
+            ClassifierEndpoint::UnixSocket(path) => {
+                Box::new(UnixSocketClassifier::new(path.clone(), config.payload))
+            }
+        };
+        info!("Classifier rule enabled, endpoint {:?}", config.endpoint);
+
+        Ok(ClassifierRule::new(
+            classifier,
+            Duration::from_millis(config.timeout_millis),
+            CircuitBreaker::new(&config.circuit_breaker),
+            config.thresholds,
+        ))
+    }
+}
+
+pub const RULE_ID: &str = "classifier";
+
+#[async_trait]
+impl Rule for ClassifierRule {
+    fn id(&self) -> &'static str {
+        RULE_ID
+    }
+
+    async fn evaluate(&self, event: &Event) -> Result<Option<Rejection>, EventAnalysisError> {
+        if !self.circuit_breaker.try_call() {
+            debug!("Classifier circuit is open, skipping event {}", event.id);
+            return Ok(None);
+        }
+
+        let classification = match timeout(self.timeout, self.classifier.classify(event)).await {
+            Ok(Ok(classification)) => {
+                self.circuit_breaker.record_success();
+                classification
+            }
+            Ok(Err(e)) => {
+                warn!("Classifier failed for event {}: {}", event.id, e);
+                self.circuit_breaker.record_failure();
+                return Ok(None);
+            }
+            Err(_) => {
+                warn!("Classifier timed out for event {}", event.id);
+                self.circuit_breaker.record_failure();
+                return Ok(None);
+            }
+        };
+
+        debug!(
+            "Classifier scored event {} {} {:?}",
+            event.id, classification.score, classification.labels
+        );
+
+        match self.thresholds.result(event, &classification) {
+            EventAnalysisResult::Accept => Ok(None),
+            EventAnalysisResult::Reject(rejection) => Ok(Some(*rejection)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, UnixListener};
+    use tokio::time::{self, Duration};
+
+    // Anything mentioning spam scores 0.95, everything else 0.1
+    fn score(body: &str) -> String {
+        let score = if body.contains("spam") { 0.95 } else { 0.1 };
+        serde_json::json!({ "score": score, "labels": ["test"] }).to_string()
+    }
+
+    // Answers every request with its score after waiting `delay`
+    async fn spawn_http_stub(delay: Duration) -> (Url, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/classify",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let requests_clone = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                requests_clone.fetch_add(1, Ordering::SeqCst);
+
+                tokio::spawn(async move {
+                    let mut buffer = vec![0; 16384];
+                    let read = socket.read(&mut buffer).await.unwrap_or_default();
+                    let body = score(&String::from_utf8_lossy(&buffer[..read]));
+
+                    tokio::time::sleep(delay).await;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn note(content: &str) -> Event {
+        EventBuilder::text_note(content, [])
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    fn http_rule(url: Url, timeout: Duration, thresholds: ClassifierThresholds) -> ClassifierRule {
+        ClassifierRule::new(
+            Box::new(HttpClassifier::new(url, ClassifierPayload::Event).unwrap()),
+            timeout,
+            CircuitBreaker::new(&CircuitBreakerConfig {
+                failure_threshold: 2,
+                cooldown_secs: 60,
+            }),
+            thresholds,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_scores_map_to_results() {
+        let (url, _) = spawn_http_stub(Duration::ZERO).await;
+        let rule = http_rule(url, Duration::from_secs(2), ClassifierThresholds::default());
+
+        let spam = note("buy spam now");
+        assert_eq!(
+            rule.evaluate(&spam)
+                .await
+                .unwrap()
+                .map(|rejection| rejection.delete_request),
+            Some(DeleteRequest::SpamScore(spam.id))
+        );
+        assert_eq!(rule.evaluate(&note("hello")).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_author_threshold() {
+        let (url, _) = spawn_http_stub(Duration::ZERO).await;
+        let rule = http_rule(
+            url,
+            Duration::from_secs(2),
+            ClassifierThresholds {
+                reject_event: 0.5,
+                reject_author: Some(0.9),
+            },
+        );
+
+        let spam = note("buy spam now");
+        assert_eq!(
+            rule.evaluate(&spam)
+                .await
+                .unwrap()
+                .map(|rejection| rejection.delete_request),
+            Some(DeleteRequest::SpamAuthor(spam.pubkey))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_classifier_opens_the_circuit() {
+        let (url, requests) = spawn_http_stub(Duration::from_secs(5)).await;
+        let rule = http_rule(
+            url,
+            Duration::from_millis(50),
+            ClassifierThresholds::default(),
+        );
+
+        for _ in 0..4 {
+            assert_eq!(rule.evaluate(&note("buy spam now")).await.unwrap(), None);
+        }
+
+        // Two timeouts opened the circuit, the rest didn't reach the server
+        assert!(rule.circuit_breaker.is_open());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_one_probe_after_the_cooldown() {
+        let circuit_breaker = CircuitBreaker::new(&CircuitBreakerConfig {
+            failure_threshold: 2,
+            cooldown_secs: 60,
+        });
+        circuit_breaker.record_failure();
+        circuit_breaker.record_failure();
+        assert!(!circuit_breaker.try_call());
+
+        // A failed probe opens the circuit for another cooldown
+        time::advance(Duration::from_secs(61)).await;
+        assert!(circuit_breaker.try_call());
+        assert!(!circuit_breaker.try_call());
+        circuit_breaker.record_failure();
+        assert!(circuit_breaker.is_open());
+        time::advance(Duration::from_secs(30)).await;
+        assert!(!circuit_breaker.try_call());
+
+        // A successful one closes it
+        time::advance(Duration::from_secs(31)).await;
+        assert!(circuit_breaker.try_call());
+        assert!(!circuit_breaker.try_call());
+        circuit_breaker.record_success();
+        assert!(!circuit_breaker.is_open());
+        assert!(circuit_breaker.try_call());
+        assert!(circuit_breaker.try_call());
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket_classifier() {
+        let path =
+            std::env::temp_dir().join(format!("classifier-{}.sock", Keys::generate().public_key()));
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let mut line = String::new();
+                BufReader::new(reader).read_line(&mut line).await.unwrap();
+
+                // Features don't carry the content, score on the link count
+                let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+                let links = request["features"]["link_count"].as_u64().unwrap();
+                let score = if links > 1 { 0.99 } else { 0.0 };
+                let response = serde_json::json!({ "score": score }).to_string();
+                writer
+                    .write_all(format!("{}\n", response).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let classifier = UnixSocketClassifier::new(path.clone(), ClassifierPayload::Features);
+        let classification = classifier
+            .classify(&note("https://a.example https://b.example"))
+            .await
+            .unwrap();
+        assert_eq!(
+            classification,
+            Classification {
+                score: 0.99,
+                labels: vec![]
+            }
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+}
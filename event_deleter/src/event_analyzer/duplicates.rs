@@ -0,0 +1,535 @@
+use super::{DeleteRequest, EventAnalysisError, Evidence, Rejection, Rule};
+use crate::settings::Settings;
+use async_trait::async_trait;
+use nostr_sdk::hashes::{sha256::Hash as Sha256Hash, Hash};
+use nostr_sdk::prelude::*;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::Deserialize;
+use tracing::{debug, info};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DuplicateClusterConfig {
+    /// Shared by every spam_cleaner instance so clusters are counted
+    /// globally. The `redis_url` setting when not set
+    pub redis_url: Option<String>,
+
+    #[serde(default = "default_key_prefix")]
+    pub key_prefix: String,
+
+    /// Events with the same content are clustered when their timestamps are
+    /// at most this far apart
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+
+    /// Shorter content is too generic ("gm", "+") to mean anything
+    #[serde(default = "default_min_length")]
+    pub min_length: usize,
+
+    /// Distinct authors posting the same content before it's a cluster
+    #[serde(default = "default_min_authors")]
+    pub min_authors: usize,
+
+    /// When a cluster reaches this many distinct authors they are all
+    /// deleted too, not just the clustered events. Disabled when not set
+    pub author_removal_threshold: Option<usize>,
+
+    /// Events and authors tracked per content, the oldest are dropped first
+    #[serde(default = "default_max_cluster_size")]
+    pub max_cluster_size: usize,
+
+    #[serde(default = "default_kinds")]
+    pub kinds: Vec<u16>,
+}
+
+impl DuplicateClusterConfig {
+    /// Shadow runs count and flag clusters under their own keys, so they
+    /// never stop a live cleaner from deleting a cluster
+    pub fn shadow(&mut self) {
+        self.key_prefix = format!("shadow:{}", self.key_prefix);
+    }
+}
+
+fn default_key_prefix() -> String {
+    "duplicates".to_string()
+}
+
+// Same window and minimum length as the antiDuplicationPolicy in policies.ts
+fn default_window_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_min_length() -> usize {
+    30
+}
+
+fn default_min_authors() -> usize {
+    5
+}
+
+fn default_max_cluster_size() -> usize {
+    10_000
+}
+
+fn default_kinds() -> Vec<u16> {
+    vec![Kind::TextNote.as_u16()]
+}
+
+/// Where content hashes are tracked between events and spam_cleaner instances.
+#[async_trait]
+pub trait DuplicateStore: Send + Sync + 'static {
+    /// Records the event under its content hash and returns how many distinct
+    /// authors posted the same content within the window around it. Entries
+    /// older than the window are forgotten.
+    async fn record(
+        &self,
+        content_hash: &str,
+        event: &Event,
+        window_secs: u64,
+    ) -> Result<usize, EventAnalysisError>;
+
+    /// Marks the cluster as flagged. Returns false if it already was, so only
+    /// the first instance to flag it deletes the whole cluster.
+    async fn flag(&self, content_hash: &str, window_secs: u64) -> Result<bool, EventAnalysisError>;
+
+    /// The event ids and authors recorded within the window around `created_at`
+    async fn cluster(
+        &self,
+        content_hash: &str,
+        created_at: Timestamp,
+        window_secs: u64,
+    ) -> Result<(Vec<EventId>, Vec<PublicKey>), EventAnalysisError>;
+}
+
+pub struct RedisDuplicateStore {
+    con: ConnectionManager,
+    key_prefix: String,
+    max_members: usize,
+}
+
+impl RedisDuplicateStore {
+    pub async fn new(
+        redis_url: &str,
+        key_prefix: String,
+        max_members: usize,
+    ) -> Result<Self, EventAnalysisError> {
+        let client = redis::Client::open(redis_url).map_err(EventAnalysisError::RedisError)?;
+        let con = client
+            .get_connection_manager()
+            .await
+            .map_err(EventAnalysisError::RedisError)?;
+
+        Ok(RedisDuplicateStore {
+            con,
+            key_prefix,
+            max_members,
+        })
+    }
+
+    fn key(&self, content_hash: &str, suffix: &str) -> String {
+        format!("{}:{}:{}", self.key_prefix, content_hash, suffix)
+    }
+}
+
+#[async_trait]
+impl DuplicateStore for RedisDuplicateStore {
+    async fn record(
+        &self,
+        content_hash: &str,
+        event: &Event,
+        window_secs: u64,
+    ) -> Result<usize, EventAnalysisError> {
+        let events_key = self.key(content_hash, "events");
+        let authors_key = self.key(content_hash, "authors");
+        let created_at = event.created_at.as_u64();
+        // Idle clusters expire on their own, active ones keep being refreshed
+        let ttl = window_secs as i64 * 2;
+        let expired = format!("({}", Timestamp::now().as_u64().saturating_sub(window_secs));
+        // Ranks are by timestamp, keeps the newest `max_members`
+        let overflow = -(self.max_members as isize) - 1;
+
+        let mut con = self.con.clone();
+        let (count,): (usize,) = redis::pipe()
+            .atomic()
+            .zadd(&events_key, event.id.to_hex(), created_at)
+            .ignore()
+            .zadd(&authors_key, event.pubkey.to_hex(), created_at)
+            .ignore()
+            .zrembyscore(&events_key, "-inf", &expired)
+            .ignore()
+            .zrembyscore(&authors_key, "-inf", &expired)
+            .ignore()
+            .zremrangebyrank(&events_key, 0, overflow)
+            .ignore()
+            .zremrangebyrank(&authors_key, 0, overflow)
+            .ignore()
+            .expire(&events_key, ttl)
+            .ignore()
+            .expire(&authors_key, ttl)
+            .ignore()
+            .zcount(
+                &authors_key,
+                created_at.saturating_sub(window_secs),
+                created_at.saturating_add(window_secs),
+            )
+            .query_async(&mut con)
+            .await
+            .map_err(EventAnalysisError::RedisError)?;
+
+        Ok(count)
+    }
+
+    async fn flag(&self, content_hash: &str, window_secs: u64) -> Result<bool, EventAnalysisError> {
+        let mut con = self.con.clone();
+        let flagged: Option<String> = redis::cmd("SET")
+            .arg(self.key(content_hash, "flagged"))
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(window_secs * 2)
+            .query_async(&mut con)
+            .await
+            .map_err(EventAnalysisError::RedisError)?;
+
+        Ok(flagged.is_some())
+    }
+
+    async fn cluster(
+        &self,
+        content_hash: &str,
+        created_at: Timestamp,
+        window_secs: u64,
+    ) -> Result<(Vec<EventId>, Vec<PublicKey>), EventAnalysisError> {
+        let min = created_at.as_u64().saturating_sub(window_secs);
+        let max = created_at.as_u64().saturating_add(window_secs);
+
+        let mut con = self.con.clone();
+        let event_ids: Vec<String> = con
+            .zrangebyscore(self.key(content_hash, "events"), min, max)
+            .await
+            .map_err(EventAnalysisError::RedisError)?;
+        let authors: Vec<String> = con
+            .zrangebyscore(self.key(content_hash, "authors"), min, max)
+            .await
+            .map_err(EventAnalysisError::RedisError)?;
+
+        Ok((
+            event_ids
+                .iter()
+                .filter_map(|id| EventId::from_hex(id).ok())
+                .collect(),
+            authors
+                .iter()
+                .filter_map(|public_key| PublicKey::from_hex(public_key).ok())
+                .collect(),
+        ))
+    }
+}
+
+/// Finds the same text posted by many distinct pubkeys, the usual footprint
+/// of a botnet, and deletes the whole cluster at once.
+pub struct DuplicateClusterRule<S: DuplicateStore> {
+    store: S,
+    config: DuplicateClusterConfig,
+}
+
+impl<S: DuplicateStore> DuplicateClusterRule<S> {
+    pub fn new(store: S, config: DuplicateClusterConfig) -> Self {
+        DuplicateClusterRule { store, config }
+    }
+}
+
+impl DuplicateClusterRule<RedisDuplicateStore> {
+    pub async fn from_config(
+        config: &DuplicateClusterConfig,
+        settings: &Settings,
+    ) -> Result<Self, EventAnalysisError> {
+        let redis_url = settings
+            .redis_url_or(config.redis_url.as_deref())
+            .map_err(|e| EventAnalysisError::ConfigError(format!("duplicate_cluster: {}", e)))?;
+        let store = RedisDuplicateStore::new(
+            redis_url,
+            config.key_prefix.clone(),
+            config.max_cluster_size,
+        )
+        .await?;
+        info!(
+            "Duplicate cluster rule enabled, {} authors within {} seconds",
+            config.min_authors, config.window_secs
+        );
+        Ok(DuplicateClusterRule::new(store, config.clone()))
+    }
+}
+
+pub const RULE_ID: &str = "duplicate_cluster";
+
+#[async_trait]
+impl<S: DuplicateStore> Rule for DuplicateClusterRule<S> {
+    fn id(&self) -> &'static str {
+        RULE_ID
+    }
+
+    async fn evaluate(&self, event: &Event) -> Result<Option<Rejection>, EventAnalysisError> {
+        if !self.config.kinds.contains(&event.kind.as_u16()) {
+            return Ok(None);
+        }
+
+        let content = normalize_content(&event.content);
+        if content.chars().count() < self.config.min_length {
+            return Ok(None);
+        }
+
+        let content_hash = Sha256Hash::hash(content.as_bytes()).to_string();
+        let authors = self
+            .store
+            .record(&content_hash, event, self.config.window_secs)
+            .await?;
+
+        if authors < self.config.min_authors {
+            return Ok(None);
+        }
+
+        // Whoever flags the cluster first deletes everything seen so far,
+        // afterwards each new copy is deleted as it shows up
+        let (mut event_ids, mut public_keys) = if self
+            .store
+            .flag(&content_hash, self.config.window_secs)
+            .await?
+        {
+            self.store
+                .cluster(&content_hash, event.created_at, self.config.window_secs)
+                .await?
+        } else {
+            (vec![event.id], vec![event.pubkey])
+        };
+
+        if !event_ids.contains(&event.id) {
+            event_ids.push(event.id);
+        }
+
+        let remove_authors = self
+            .config
+            .author_removal_threshold
+            .is_some_and(|threshold| authors >= threshold);
+        if !remove_authors {
+            public_keys.clear();
+        }
+
+        debug!(
+            "Event {} belongs to a cluster of {} authors, deleting {} events and {} authors",
+            event.id,
+            authors,
+            event_ids.len(),
+            public_keys.len()
+        );
+
+        event_ids.sort();
+        public_keys.sort();
+        Ok(Some(
+            Rejection::new(
+                self.id(),
+                DeleteRequest::DuplicateCluster(event_ids, public_keys),
+            )
+            .with_evidence(Evidence::Detail(format!(
+                "{} authors posted content {}",
+                authors, content_hash
+            ))),
+        ))
+    }
+}
+
+/// Case and whitespace don't make two copies different
+fn normalize_content(content: &str) -> String {
+    content
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::sync::Mutex;
+
+    // Same semantics as the Redis store, kept in memory
+    #[derive(Default)]
+    struct MemoryDuplicateStore {
+        events: Mutex<HashMap<String, BTreeMap<EventId, u64>>>,
+        authors: Mutex<HashMap<String, BTreeMap<PublicKey, u64>>>,
+        flagged: Mutex<HashSet<String>>,
+    }
+
+    #[async_trait]
+    impl DuplicateStore for MemoryDuplicateStore {
+        async fn record(
+            &self,
+            content_hash: &str,
+            event: &Event,
+            window_secs: u64,
+        ) -> Result<usize, EventAnalysisError> {
+            let created_at = event.created_at.as_u64();
+            let expired = Timestamp::now().as_u64().saturating_sub(window_secs);
+
+            let mut events = self.events.lock().unwrap();
+            let events = events.entry(content_hash.to_string()).or_default();
+            events.insert(event.id, created_at);
+            events.retain(|_, timestamp| *timestamp >= expired);
+
+            let mut authors = self.authors.lock().unwrap();
+            let authors = authors.entry(content_hash.to_string()).or_default();
+            authors.insert(event.pubkey, created_at);
+            authors.retain(|_, timestamp| *timestamp >= expired);
+
+            Ok(authors
+                .values()
+                .filter(|timestamp| created_at.abs_diff(**timestamp) <= window_secs)
+                .count())
+        }
+
+        async fn flag(
+            &self,
+            content_hash: &str,
+            _window_secs: u64,
+        ) -> Result<bool, EventAnalysisError> {
+            Ok(self
+                .flagged
+                .lock()
+                .unwrap()
+                .insert(content_hash.to_string()))
+        }
+
+        async fn cluster(
+            &self,
+            content_hash: &str,
+            created_at: Timestamp,
+            window_secs: u64,
+        ) -> Result<(Vec<EventId>, Vec<PublicKey>), EventAnalysisError> {
+            let in_window =
+                |timestamp: &u64| created_at.as_u64().abs_diff(*timestamp) <= window_secs;
+
+            let event_ids = self.events.lock().unwrap()[content_hash]
+                .iter()
+                .filter(|(_, timestamp)| in_window(timestamp))
+                .map(|(id, _)| *id)
+                .collect();
+            let authors = self.authors.lock().unwrap()[content_hash]
+                .iter()
+                .filter(|(_, timestamp)| in_window(timestamp))
+                .map(|(public_key, _)| *public_key)
+                .collect();
+
+            Ok((event_ids, authors))
+        }
+    }
+
+    fn config(author_removal_threshold: Option<usize>) -> DuplicateClusterConfig {
+        DuplicateClusterConfig {
+            redis_url: None,
+            key_prefix: default_key_prefix(),
+            window_secs: 3600,
+            min_length: 10,
+            min_authors: 3,
+            author_removal_threshold,
+            max_cluster_size: default_max_cluster_size(),
+            kinds: default_kinds(),
+        }
+    }
+
+    fn note(content: &str, created_at: u64) -> Event {
+        EventBuilder::text_note(content, [])
+            .custom_created_at(Timestamp::from(created_at))
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cluster_is_deleted_once_enough_authors_post_it() {
+        let rule = DuplicateClusterRule::new(MemoryDuplicateStore::default(), config(None));
+        let spam = "Claim your free sats at this totally legit site";
+        let now = Timestamp::now().as_u64();
+
+        let first = note(spam, now);
+        let second = note(&spam.to_uppercase(), now + 100);
+        assert_eq!(rule.evaluate(&first).await.unwrap(), None);
+        assert_eq!(rule.evaluate(&second).await.unwrap(), None);
+
+        let third = note(&format!("  {}  ", spam), now + 200);
+        let mut cluster = vec![first.id, second.id, third.id];
+        cluster.sort();
+        assert_eq!(
+            rule.evaluate(&third)
+                .await
+                .unwrap()
+                .map(|rejection| rejection.delete_request),
+            Some(DeleteRequest::DuplicateCluster(cluster, vec![]))
+        );
+
+        let fourth = note(spam, now + 300);
+        assert_eq!(
+            rule.evaluate(&fourth)
+                .await
+                .unwrap()
+                .map(|rejection| rejection.delete_request),
+            Some(DeleteRequest::DuplicateCluster(vec![fourth.id], vec![]))
+        );
+
+        // Outside the window of the rest of the cluster
+        let much_later = note(spam, now + 100_000);
+        assert_eq!(rule.evaluate(&much_later).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_authors_are_removed_above_threshold() {
+        let rule = DuplicateClusterRule::new(MemoryDuplicateStore::default(), config(Some(3)));
+        let spam = "Claim your free sats at this totally legit site";
+
+        let now = Timestamp::now().as_u64();
+        let events = [note(spam, now), note(spam, now + 1), note(spam, now + 2)];
+        let mut results = Vec::new();
+        for event in &events {
+            results.push(
+                rule.evaluate(event)
+                    .await
+                    .unwrap()
+                    .map(|rejection| rejection.delete_request),
+            );
+        }
+
+        let mut authors: Vec<PublicKey> = events.iter().map(|event| event.pubkey).collect();
+        authors.sort();
+        let Some(DeleteRequest::DuplicateCluster(_, removed_authors)) = &results[2] else {
+            panic!("Expected the third copy to flag the cluster");
+        };
+        assert_eq!(removed_authors, &authors);
+    }
+
+    #[tokio::test]
+    async fn test_copies_older_than_the_window_are_forgotten() {
+        let rule = DuplicateClusterRule::new(MemoryDuplicateStore::default(), config(None));
+        let spam = "Claim your free sats at this totally legit site";
+        let now = Timestamp::now().as_u64();
+
+        // Within the window of the last copy, but not of now
+        assert_eq!(rule.evaluate(&note(spam, now - 3650)).await.unwrap(), None);
+        assert_eq!(rule.evaluate(&note(spam, now - 3650)).await.unwrap(), None);
+        assert_eq!(rule.evaluate(&note(spam, now - 100)).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_shadow_mode_counts_clusters_apart() {
+        let mut config = config(None);
+        config.shadow();
+        assert_eq!(config.key_prefix, "shadow:duplicates");
+    }
+
+    #[tokio::test]
+    async fn test_short_content_is_ignored() {
+        let rule = DuplicateClusterRule::new(MemoryDuplicateStore::default(), config(None));
+
+        for _ in 0..5 {
+            assert_eq!(rule.evaluate(&note("gm", 1000)).await.unwrap(), None);
+        }
+    }
+}
@@ -0,0 +1,543 @@
+use super::nip05::{Nip05Status, Nip05Verifier};
+use super::{DeleteRequest, EventAnalysisError, Evidence, Rejection, Rule};
+use async_trait::async_trait;
+use nostr_sdk::prelude::*;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tracing::debug;
+
+// Names shorter than this are too common to say anything about impersonation
+const MIN_NAME_LENGTH: usize = 3;
+
+// Edit distance is only meaningful on identifiers long enough to not collide by chance
+const MIN_LOOKALIKE_NIP05_LENGTH: usize = 6;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImpersonationConfig {
+    /// Hex or bech32 public keys that are always protected
+    #[serde(default)]
+    pub protected_pubkeys: Vec<String>,
+
+    /// Pubkeys followed by at least this many contact lists in the local
+    /// relay are protected too. Discovery is skipped when not set
+    pub min_followers: Option<usize>,
+
+    /// How many contact lists to sample when looking for popular pubkeys
+    #[serde(default = "default_contact_list_sample")]
+    pub contact_list_sample: usize,
+
+    /// Number of matching signals (name, picture, nip05) needed to flag a profile
+    #[serde(default = "default_min_signals")]
+    pub min_signals: usize,
+}
+
+fn default_contact_list_sample() -> usize {
+    5000
+}
+
+fn default_min_signals() -> usize {
+    2
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImpersonationSignals {
+    pub name: bool,
+    pub picture: bool,
+    pub nip05: bool,
+}
+
+impl ImpersonationSignals {
+    pub fn count(&self) -> usize {
+        [self.name, self.picture, self.nip05]
+            .iter()
+            .filter(|signal| **signal)
+            .count()
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            ("name", self.name),
+            ("picture", self.picture),
+            ("nip05", self.nip05),
+        ]
+        .into_iter()
+        .filter_map(|(name, matched)| matched.then_some(name))
+        .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Nip05Parts {
+    local: String,
+    domain: String,
+}
+
+impl Nip05Parts {
+    fn parse(nip05: &str) -> Option<Self> {
+        let (local, domain) = nip05.trim().rsplit_once('@')?;
+        let local = local.to_lowercase();
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        if local.is_empty() || domain.is_empty() {
+            return None;
+        }
+
+        Some(Nip05Parts { local, domain })
+    }
+
+    fn identifier(&self) -> String {
+        format!("{}@{}", self.local, self.domain)
+    }
+
+    // Each part on its own, so the `@` isn't folded into a letter
+    fn skeleton(&self) -> String {
+        format!("{}@{}", skeleton(&self.local), skeleton(&self.domain))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ProtectedIdentity {
+    pubkey: PublicKey,
+    names: HashSet<String>,
+    nip05: Option<Nip05Parts>,
+    picture: Option<String>,
+}
+
+/// Profiles of well known accounts, keyed by the normalized values we compare
+/// new kind 0 events against.
+#[derive(Debug, Clone, Default)]
+pub struct ProtectedIdentityIndex {
+    pubkeys: HashSet<PublicKey>,
+    identities: Vec<ProtectedIdentity>,
+}
+
+impl ProtectedIdentityIndex {
+    pub async fn load(
+        nostr_client: &Client,
+        config: &ImpersonationConfig,
+    ) -> Result<Self, EventAnalysisError> {
+        let mut pubkeys = HashSet::new();
+        for public_key in &config.protected_pubkeys {
+            pubkeys.insert(
+                PublicKey::parse(public_key).map_err(|_| EventAnalysisError::PublicKeyError)?,
+            );
+        }
+
+        if let Some(min_followers) = config.min_followers {
+            let popular =
+                popular_pubkeys(nostr_client, min_followers, config.contact_list_sample).await?;
+            debug!("Found {} popular pubkeys to protect", popular.len());
+            pubkeys.extend(popular);
+        }
+
+        let mut index = ProtectedIdentityIndex::default();
+        if pubkeys.is_empty() {
+            return Ok(index);
+        }
+
+        let filters = vec![Filter::new()
+            .kind(Kind::Metadata)
+            .authors(pubkeys.iter().copied())];
+
+        let events = nostr_client
+            .get_events_of(filters, EventSource::both(None))
+            .await
+            .map_err(EventAnalysisError::NostrError)?;
+
+        // Metadata is replaceable, only the latest version of each profile counts
+        let mut latest: HashMap<PublicKey, Event> = HashMap::new();
+        for event in events {
+            match latest.get(&event.pubkey) {
+                Some(current) if current.created_at >= event.created_at => {}
+                _ => {
+                    latest.insert(event.pubkey, event);
+                }
+            }
+        }
+
+        for (public_key, event) in latest {
+            if let Ok(metadata) = Metadata::from_json(&event.content) {
+                index.insert(public_key, &metadata);
+            }
+        }
+
+        // Protected pubkeys without a profile are still never flagged
+        index.pubkeys.extend(pubkeys);
+
+        Ok(index)
+    }
+
+    pub fn insert(&mut self, pubkey: PublicKey, metadata: &Metadata) {
+        let names = [&metadata.name, &metadata.display_name]
+            .into_iter()
+            .flatten()
+            .map(|name| skeleton(name))
+            .filter(|name| name.chars().count() >= MIN_NAME_LENGTH)
+            .collect();
+
+        self.pubkeys.insert(pubkey);
+        self.identities.push(ProtectedIdentity {
+            pubkey,
+            names,
+            nip05: metadata.nip05.as_deref().and_then(Nip05Parts::parse),
+            picture: metadata.picture.as_deref().map(normalize_picture_url),
+        });
+    }
+
+    pub fn contains(&self, pubkey: &PublicKey) -> bool {
+        self.pubkeys.contains(pubkey)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pubkeys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pubkeys.is_empty()
+    }
+
+    /// Returns the protected identity sharing the most signals with the given
+    /// metadata, if any signal matched at all.
+    pub fn best_match(&self, metadata: &Metadata) -> Option<(PublicKey, ImpersonationSignals)> {
+        let names: HashSet<String> = [&metadata.name, &metadata.display_name]
+            .into_iter()
+            .flatten()
+            .map(|name| skeleton(name))
+            .filter(|name| name.chars().count() >= MIN_NAME_LENGTH)
+            .collect();
+        let nip05 = metadata.nip05.as_deref().and_then(Nip05Parts::parse);
+        let picture = metadata.picture.as_deref().map(normalize_picture_url);
+
+        self.identities
+            .iter()
+            .map(|identity| {
+                let signals = ImpersonationSignals {
+                    name: !identity.names.is_disjoint(&names),
+                    picture: picture.is_some() && identity.picture == picture,
+                    nip05: match (&nip05, &identity.nip05) {
+                        (Some(claimed), Some(protected)) => is_lookalike_nip05(claimed, protected),
+                        _ => false,
+                    },
+                };
+                (identity.pubkey, signals)
+            })
+            .filter(|(_, signals)| signals.count() > 0)
+            .max_by_key(|(_, signals)| signals.count())
+    }
+}
+
+pub struct ImpersonationRule {
+    index: ProtectedIdentityIndex,
+    min_signals: usize,
+    nip05_verifier: Option<Arc<Nip05Verifier>>,
+}
+
+impl ImpersonationRule {
+    /// When a verifier is given, a NIP-05 claim that fails verification also
+    /// counts as the nip05 signal.
+    pub fn new(
+        index: ProtectedIdentityIndex,
+        min_signals: usize,
+        nip05_verifier: Option<Arc<Nip05Verifier>>,
+    ) -> Self {
+        ImpersonationRule {
+            index,
+            min_signals,
+            nip05_verifier,
+        }
+    }
+}
+
+pub const RULE_ID: &str = "impersonation";
+
+#[async_trait]
+impl Rule for ImpersonationRule {
+    fn id(&self) -> &'static str {
+        RULE_ID
+    }
+
+    async fn evaluate(&self, event: &Event) -> Result<Option<Rejection>, EventAnalysisError> {
+        if event.kind != Kind::Metadata || self.index.contains(&event.pubkey) {
+            return Ok(None);
+        }
+
+        let Ok(metadata) = Metadata::from_json(&event.content) else {
+            return Ok(None);
+        };
+
+        let Some((protected_pubkey, mut signals)) = self.index.best_match(&metadata) else {
+            return Ok(None);
+        };
+
+        if !signals.nip05 && signals.count() < self.min_signals {
+            if let Some(nip05_verifier) = &self.nip05_verifier {
+                signals.nip05 = nip05_verifier
+                    .verify(&event.pubkey, metadata.nip05.as_deref())
+                    .await
+                    == Nip05Status::Failed;
+            }
+        }
+
+        if signals.count() < self.min_signals {
+            return Ok(None);
+        }
+
+        debug!(
+            "Profile {} impersonates {}: {:?}",
+            event.pubkey, protected_pubkey, signals
+        );
+
+        Ok(Some(
+            Rejection::new(self.id(), DeleteRequest::Impersonation(event.pubkey)).with_evidence(
+                Evidence::Impersonates {
+                    pubkey: protected_pubkey,
+                    signals: signals.names(),
+                },
+            ),
+        ))
+    }
+}
+
+async fn popular_pubkeys(
+    nostr_client: &Client,
+    min_followers: usize,
+    contact_list_sample: usize,
+) -> Result<HashSet<PublicKey>, EventAnalysisError> {
+    let filters = vec![Filter::new()
+        .kind(Kind::ContactList)
+        .limit(contact_list_sample)];
+
+    let contact_lists = nostr_client
+        .get_events_of(filters, EventSource::both(None))
+        .await
+        .map_err(EventAnalysisError::NostrError)?;
+
+    let mut followers: HashMap<PublicKey, HashSet<PublicKey>> = HashMap::new();
+    for contact_list in &contact_lists {
+        for followed in contact_list.public_keys() {
+            followers
+                .entry(*followed)
+                .or_default()
+                .insert(contact_list.pubkey);
+        }
+    }
+
+    Ok(followers
+        .into_iter()
+        .filter(|(_, followers)| followers.len() >= min_followers)
+        .map(|(public_key, _)| public_key)
+        .collect())
+}
+
+/// Lowercases the text, folds characters that look alike into a single
+/// representative and drops everything that isn't alphanumeric, so "Jack",
+/// "jасk " (Cyrillic) and "J4ck!" produce close or identical skeletons.
+pub fn skeleton(text: &str) -> String {
+    text.chars()
+        .flat_map(char::to_lowercase)
+        .map(fold_confusable)
+        .filter(|c| c.is_alphanumeric())
+        .collect()
+}
+
+fn fold_confusable(c: char) -> char {
+    match c {
+        // Fullwidth latin letters
+        'ａ'..='ｚ' => char::from_u32(c as u32 - 'ａ' as u32 + 'a' as u32).unwrap_or(c),
+        // Cyrillic and Greek homoglyphs
+        'а' | 'α' => 'a',
+        'в' | 'β' => 'b',
+        'с' | 'ϲ' => 'c',
+        'ԁ' => 'd',
+        'е' | 'ё' | 'ε' => 'e',
+        'һ' => 'h',
+        'і' | 'ї' | 'ι' | 'ı' => 'i',
+        'ј' => 'j',
+        'к' | 'κ' => 'k',
+        'м' => 'm',
+        'п' | 'η' => 'n',
+        'о' | 'ο' | 'σ' => 'o',
+        'р' | 'ρ' => 'p',
+        'ѕ' => 's',
+        'т' | 'τ' => 't',
+        'υ' | 'ս' => 'u',
+        'ν' => 'v',
+        'ѡ' | 'ω' => 'w',
+        'х' | 'χ' => 'x',
+        'у' | 'γ' => 'y',
+        // Latin letters with diacritics
+        'á' | 'à' | 'â' | 'ä' | 'ã' | 'å' | 'ā' => 'a',
+        'ç' | 'ć' | 'č' => 'c',
+        'é' | 'è' | 'ê' | 'ë' | 'ē' => 'e',
+        'í' | 'ì' | 'î' | 'ï' => 'i',
+        'ñ' | 'ń' => 'n',
+        'ó' | 'ò' | 'ô' | 'ö' | 'õ' | 'ø' => 'o',
+        'ś' | 'š' => 's',
+        'ú' | 'ù' | 'û' | 'ü' => 'u',
+        'ý' | 'ÿ' => 'y',
+        'ž' | 'ź' | 'ż' => 'z',
+        // Digits and symbols used as letters
+        '0' => 'o',
+        '1' | '|' => 'l',
+        '3' => 'e',
+        '4' | '@' => 'a',
+        '5' | '$' => 's',
+        '7' => 't',
+        _ => c,
+    }
+}
+
+// Compares the whole `local@domain`, another user of the same domain isn't a
+// lookalike but a confusable name on it is. Claiming the exact identifier is
+// left to NIP-05 verification
+fn is_lookalike_nip05(claimed: &Nip05Parts, protected: &Nip05Parts) -> bool {
+    if claimed == protected {
+        return false;
+    }
+
+    let protected_identifier = protected.identifier();
+    let same_skeleton = claimed.skeleton() == protected.skeleton();
+    let close_spelling = protected_identifier.chars().count() >= MIN_LOOKALIKE_NIP05_LENGTH
+        && edit_distance(&claimed.identifier(), &protected_identifier) <= 1;
+
+    same_skeleton || close_spelling
+}
+
+fn normalize_picture_url(picture: &str) -> String {
+    match Url::parse(picture.trim()) {
+        Ok(url) => format!(
+            "{}{}",
+            url.host_str().unwrap_or_default(),
+            url.path().trim_end_matches('/')
+        ),
+        Err(_) => picture.trim().to_lowercase(),
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protected_index() -> (ProtectedIdentityIndex, Keys) {
+        let protected_keys = Keys::generate();
+        let mut index = ProtectedIdentityIndex::default();
+        index.insert(
+            protected_keys.public_key(),
+            &Metadata::new()
+                .name("jack")
+                .display_name("Jack Dorsey")
+                .nip05("jack@primal.net")
+                .picture(Url::parse("https://example.com/jack.png?size=200").unwrap()),
+        );
+        (index, protected_keys)
+    }
+
+    fn metadata_event(metadata: &Metadata, keys: &Keys) -> Event {
+        EventBuilder::metadata(metadata).to_event(keys).unwrap()
+    }
+
+    #[test]
+    fn test_skeleton_folds_confusables() {
+        assert_eq!(skeleton("Jack Dorsey"), "jackdorsey");
+        assert_eq!(skeleton("Jасk Dоrsеy"), "jackdorsey");
+        assert_eq!(skeleton("J4ck_D0rsey!"), "jackdorsey");
+        assert_eq!(skeleton("ＪＡＣＫ"), "jack");
+    }
+
+    #[test]
+    fn test_lookalike_nip05() {
+        let protected = Nip05Parts::parse("jack@primal.net").unwrap();
+
+        assert!(is_lookalike_nip05(
+            &Nip05Parts::parse("jack@prima1.net").unwrap(),
+            &protected
+        ));
+        assert!(is_lookalike_nip05(
+            &Nip05Parts::parse("jack@primal.nett").unwrap(),
+            &protected
+        ));
+        assert!(is_lookalike_nip05(
+            &Nip05Parts::parse("j4ck@primal.net").unwrap(),
+            &protected
+        ));
+        assert!(!is_lookalike_nip05(
+            &Nip05Parts::parse("Jack@primal.net").unwrap(),
+            &protected
+        ));
+        assert!(!is_lookalike_nip05(
+            &Nip05Parts::parse("bob@primal.net").unwrap(),
+            &protected
+        ));
+        // Someone else on a lookalike domain
+        assert!(!is_lookalike_nip05(
+            &Nip05Parts::parse("bob@prima1.net").unwrap(),
+            &protected
+        ));
+        assert!(!is_lookalike_nip05(
+            &Nip05Parts::parse("jack@nos.social").unwrap(),
+            &protected
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rule_flags_copied_profile() {
+        let (index, protected_keys) = protected_index();
+        let rule = ImpersonationRule::new(index, 2, None);
+
+        let copied_metadata = Metadata::new()
+            .display_name("Jасk Dorsey")
+            .picture(Url::parse("https://example.com/jack.png").unwrap());
+        let copied = metadata_event(&copied_metadata, &Keys::generate());
+        assert_eq!(
+            rule.evaluate(&copied)
+                .await
+                .unwrap()
+                .map(|rejection| rejection.delete_request),
+            Some(DeleteRequest::Impersonation(copied.pubkey))
+        );
+
+        let same_name_only = metadata_event(&Metadata::new().name("Jack"), &Keys::generate());
+        assert_eq!(rule.evaluate(&same_name_only).await.unwrap(), None);
+
+        let original = metadata_event(&copied_metadata, &protected_keys);
+        assert_eq!(rule.evaluate(&original).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_rejection_names_the_impersonated_pubkey() {
+        let (index, protected_keys) = protected_index();
+        let rule = ImpersonationRule::new(index, 2, None);
+
+        let copied = metadata_event(
+            &Metadata::new()
+                .name("jack")
+                .nip05("jack@prima1.net")
+                .picture(Url::parse("https://example.com/other.png").unwrap()),
+            &Keys::generate(),
+        );
+        let rejection = rule.evaluate(&copied).await.unwrap().unwrap();
+        assert_eq!(
+            rejection.evidence,
+            Some(Evidence::Impersonates {
+                pubkey: protected_keys.public_key(),
+                signals: vec!["name", "nip05"],
+            })
+        );
+    }
+}
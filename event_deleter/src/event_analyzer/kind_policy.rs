@@ -0,0 +1,177 @@
+use super::{DeleteRequest, EventAnalysisError, Evidence, Rejection, Rule};
+use async_trait::async_trait;
+use nostr_sdk::prelude::*;
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+// Where the Dockerfile copies the file shared with nos_policy.ts
+pub static DEFAULT_KIND_POLICY_FILE: &str = "/app/plugins/kind_policy.json";
+
+// Keeps each scan filter well below the size limit of a single command line argument
+const KINDS_PER_SCAN_FILTER: usize = 5000;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KindPolicyConfig {
+    /// JSON file shared with nos_policy.ts
+    #[serde(default = "default_policy_file")]
+    pub policy_file: PathBuf,
+}
+
+fn default_policy_file() -> PathBuf {
+    PathBuf::from(DEFAULT_KIND_POLICY_FILE)
+}
+
+// Values are just descriptions for humans reading the file
+#[derive(Deserialize)]
+struct KindPolicyFile {
+    allowed_kinds: HashMap<u16, String>,
+    #[serde(default)]
+    allowed_pubkeys: HashMap<String, String>,
+}
+
+/// The kinds nos_policy.ts lets in, plus the pubkeys that can publish any kind
+#[derive(Debug, Clone)]
+pub struct KindPolicy {
+    allowed_kinds: BTreeSet<u16>,
+    allowed_pubkeys: HashSet<PublicKey>,
+}
+
+impl KindPolicy {
+    pub fn from_json(json: &str) -> Result<Self, EventAnalysisError> {
+        let file: KindPolicyFile = serde_json::from_str(json)
+            .map_err(|e| EventAnalysisError::ConfigError(e.to_string()))?;
+
+        let mut allowed_pubkeys = HashSet::new();
+        for public_key in file.allowed_pubkeys.keys() {
+            allowed_pubkeys.insert(
+                PublicKey::from_hex(public_key).map_err(|_| EventAnalysisError::PublicKeyError)?,
+            );
+        }
+
+        Ok(KindPolicy {
+            allowed_kinds: file.allowed_kinds.into_keys().collect(),
+            allowed_pubkeys,
+        })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, EventAnalysisError> {
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| EventAnalysisError::ConfigError(format!("{}: {}", path.display(), e)))?;
+        KindPolicy::from_json(&json)
+    }
+
+    pub fn is_allowed(&self, event: &Event) -> bool {
+        self.allowed_kinds.contains(&event.kind.as_u16())
+            || self.allowed_pubkeys.contains(&event.pubkey)
+    }
+
+    /// Filters that together match every kind outside the allow-list. They
+    /// are split so each one can be passed to `strfry scan`.
+    pub fn disallowed_kinds_filters(&self) -> Vec<Filter> {
+        let disallowed_kinds: Vec<Kind> = (0..=u16::MAX)
+            .filter(|kind| !self.allowed_kinds.contains(kind))
+            .map(Kind::from)
+            .collect();
+
+        disallowed_kinds
+            .chunks(KINDS_PER_SCAN_FILTER)
+            .map(|kinds| Filter::new().kinds(kinds.iter().copied()))
+            .collect()
+    }
+}
+
+pub struct KindPolicyRule {
+    policy: KindPolicy,
+}
+
+impl KindPolicyRule {
+    pub fn new(policy: KindPolicy) -> Self {
+        KindPolicyRule { policy }
+    }
+}
+
+pub const RULE_ID: &str = "kind_policy";
+
+#[async_trait]
+impl Rule for KindPolicyRule {
+    fn id(&self) -> &'static str {
+        RULE_ID
+    }
+
+    async fn evaluate(&self, event: &Event) -> Result<Option<Rejection>, EventAnalysisError> {
+        if self.policy.is_allowed(event) {
+            return Ok(None);
+        }
+
+        debug!("Event {} has disallowed kind {}", event.id, event.kind);
+        Ok(Some(
+            Rejection::new(self.id(), DeleteRequest::DisallowedKind(event.id))
+                .with_evidence(Evidence::Detail(format!("kind {}", event.kind.as_u16()))),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allowed_pubkey: &PublicKey) -> KindPolicy {
+        KindPolicy::from_json(&format!(
+            r#"{{
+                "allowed_pubkeys": {{ "{}": "Tagr" }},
+                "allowed_kinds": {{ "0": "Metadata", "1": "Short Text Note" }}
+            }}"#,
+            allowed_pubkey.to_hex()
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rule_rejects_disallowed_kinds() {
+        let allowed_keys = Keys::generate();
+        let rule = KindPolicyRule::new(policy(&allowed_keys.public_key()));
+        let keys = Keys::generate();
+
+        let note = EventBuilder::text_note("hello", [])
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(rule.evaluate(&note).await.unwrap(), None);
+
+        let reaction = EventBuilder::new(Kind::Reaction, "+", [])
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(
+            rule.evaluate(&reaction)
+                .await
+                .unwrap()
+                .map(|rejection| rejection.delete_request),
+            Some(DeleteRequest::DisallowedKind(reaction.id))
+        );
+
+        let exempt_reaction = EventBuilder::new(Kind::Reaction, "+", [])
+            .to_event(&allowed_keys)
+            .unwrap();
+        assert_eq!(rule.evaluate(&exempt_reaction).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_disallowed_kinds_filters_cover_the_complement() {
+        let policy = policy(&Keys::generate().public_key());
+        let filters = policy.disallowed_kinds_filters();
+
+        let kinds: BTreeSet<Kind> = filters
+            .iter()
+            .flat_map(|filter| filter.kinds.clone().unwrap_or_default())
+            .collect();
+
+        assert_eq!(kinds.len(), u16::MAX as usize + 1 - 2);
+        assert!(!kinds.contains(&Kind::Metadata));
+        assert!(!kinds.contains(&Kind::TextNote));
+        assert!(kinds.contains(&Kind::Reaction));
+        assert!(filters
+            .iter()
+            .all(|filter| filter.kinds.as_ref().unwrap().len() <= KINDS_PER_SCAN_FILTER));
+    }
+}
@@ -0,0 +1,539 @@
+use super::{DeleteRequest, EventAnalysisError, Evidence, Rejection, Rule};
+use async_trait::async_trait;
+use nostr_sdk::prelude::*;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, RwLock};
+use tokio::time::Duration;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error, info};
+
+// Links with a scheme, or hosts written without one: after `www.` or ending
+// in a known TLD, so "e.g." or "file.txt" aren't taken for links
+static URL_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!(
+        r#"(?i)https?://[^\s<>"'`]+|\bwww\.(?:[a-z0-9-]+\.)+[a-z]{{2,}}\b(?:[:/?#][^\s<>"'`]*)?|\b(?:[a-z0-9-]+\.)+(?:{})\b(?:[:/?#][^\s<>"'`]*)?"#,
+        KNOWN_TLDS.join("|")
+    ))
+    .unwrap()
+});
+
+// Common TLDs and the cheap ones spam campaigns favor
+static KNOWN_TLDS: &[&str] = &[
+    "com", "net", "org", "info", "biz", "io", "co", "me", "app", "dev", "xyz", "top", "site",
+    "online", "club", "shop", "store", "live", "link", "click", "win", "bid", "loan", "vip", "icu",
+    "buzz", "fun", "space", "website", "tech", "pro", "cc", "tk", "ml", "ga", "cf", "gq", "ru",
+    "cn", "su", "ua", "uk", "de", "fr", "nl", "eu", "us", "ca", "au", "in", "br", "jp", "kr", "it",
+    "es", "pl", "ch", "se", "social", "news", "to", "ly", "gg", "ai", "tv",
+];
+
+// Query parameters that only identify the campaign or the click
+static TRACKING_PARAMS: &[&str] = &[
+    "fbclid", "gclid", "dclid", "msclkid", "mc_eid", "mc_cid", "igshid", "si", "ref",
+];
+
+// Shortened links can point to other shorteners, don't follow them forever
+const MAX_SHORTENER_HOPS: usize = 3;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlocklistSource {
+    /// Text file with a domain per line, `#` starts a comment
+    File(PathBuf),
+    /// Redis set holding one domain per member
+    Redis { url: String, key: String },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LinkBlocklistConfig {
+    pub source: BlocklistSource,
+
+    /// How often the blocklist is reloaded from its source
+    #[serde(default = "default_refresh_secs")]
+    pub refresh_secs: u64,
+
+    /// Text file mapping shortened links to their target, one
+    /// `<short url> <target url>` pair per line
+    pub shorteners_file: Option<PathBuf>,
+}
+
+fn default_refresh_secs() -> u64 {
+    300
+}
+
+/// Blocked domains. A domain also blocks all of its subdomains.
+#[derive(Debug, Default)]
+pub struct DomainBlocklist {
+    domains: RwLock<HashSet<String>>,
+}
+
+impl DomainBlocklist {
+    pub fn new<I, S>(domains: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let blocklist = DomainBlocklist::default();
+        blocklist.replace(domains);
+        blocklist
+    }
+
+    pub fn replace<I, S>(&self, domains: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let domains = domains
+            .into_iter()
+            .filter_map(|domain| normalize_domain(domain.as_ref()))
+            .collect();
+        *self.domains.write().unwrap() = domains;
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.read().unwrap().is_empty()
+    }
+
+    /// Returns the blocklist entry matching the host or any of its parent domains
+    pub fn matching(&self, host: &str) -> Option<String> {
+        let domains = self.domains.read().unwrap();
+        let host = host.trim_end_matches('.');
+
+        let mut candidate = host;
+        loop {
+            if domains.contains(candidate) {
+                return Some(candidate.to_string());
+            }
+
+            match candidate.split_once('.') {
+                Some((_, parent)) => candidate = parent,
+                None => return None,
+            }
+        }
+    }
+
+    pub async fn reload(&self, loader: &BlocklistLoader) -> Result<(), EventAnalysisError> {
+        self.replace(loader.load().await?);
+        Ok(())
+    }
+}
+
+/// Reads the blocklist from its source, the Redis connection is opened once
+/// and reused by every reload
+pub enum BlocklistLoader {
+    File(PathBuf),
+    Redis {
+        con: Box<ConnectionManager>,
+        key: String,
+    },
+}
+
+impl BlocklistLoader {
+    pub async fn connect(source: &BlocklistSource) -> Result<Self, EventAnalysisError> {
+        match source {
+            BlocklistSource::File(path) => Ok(BlocklistLoader::File(path.clone())),
+            BlocklistSource::Redis { url, key } => {
+                let con = redis::Client::open(url.as_str())
+                    .map_err(|e| EventAnalysisError::ConfigError(e.to_string()))?
+                    .get_connection_manager()
+                    .await
+                    .map_err(EventAnalysisError::RedisError)?;
+                Ok(BlocklistLoader::Redis {
+                    con: Box::new(con),
+                    key: key.clone(),
+                })
+            }
+        }
+    }
+
+    async fn load(&self) -> Result<Vec<String>, EventAnalysisError> {
+        match self {
+            BlocklistLoader::File(path) => read_lines(path).await,
+            BlocklistLoader::Redis { con, key } => {
+                let mut con = ConnectionManager::clone(con);
+                con.smembers(key)
+                    .await
+                    .map_err(EventAnalysisError::RedisError)
+            }
+        }
+    }
+}
+
+/// Periodically reloads the blocklist from its source until the token is
+/// cancelled. A failed reload keeps the previous list.
+pub fn spawn_blocklist_refresh(
+    tracker: &TaskTracker,
+    blocklist: Arc<DomainBlocklist>,
+    loader: BlocklistLoader,
+    refresh_period: Duration,
+    cancellation_token: CancellationToken,
+) {
+    tracker.spawn(async move {
+        let mut interval = tokio::time::interval(refresh_period);
+        // The first tick completes immediately and the list was just loaded
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = cancellation_token.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+
+            match blocklist.reload(&loader).await {
+                Ok(()) => debug!("Reloaded blocklist, {} domains", blocklist.len()),
+                Err(e) => error!("Failed to reload blocklist: {}", e),
+            }
+        }
+    });
+}
+
+/// Rewrites links so equivalent URLs compare equal: punycode hosts, no
+/// tracking parameters or fragments, and known shortened links replaced by
+/// their target.
+#[derive(Debug, Default)]
+pub struct UrlNormalizer {
+    shorteners: HashMap<String, String>,
+}
+
+impl UrlNormalizer {
+    pub fn new(shorteners: HashMap<String, String>) -> Self {
+        let shorteners = shorteners
+            .into_iter()
+            .filter_map(|(short, target)| {
+                let short = Url::parse(&short).ok()?;
+                Some((shortener_key(&short)?, target))
+            })
+            .collect();
+
+        UrlNormalizer { shorteners }
+    }
+
+    pub async fn from_file(path: &Path) -> Result<Self, EventAnalysisError> {
+        let shorteners = read_lines(path)
+            .await?
+            .into_iter()
+            .filter_map(|line| {
+                let (short, target) = line.split_once(char::is_whitespace)?;
+                Some((short.to_string(), target.trim().to_string()))
+            })
+            .collect();
+
+        Ok(UrlNormalizer::new(shorteners))
+    }
+
+    /// Links without a scheme are taken as http
+    pub fn normalize(&self, url: &str) -> Option<Url> {
+        let mut url = if url.contains("://") {
+            Url::parse(url).ok()?
+        } else {
+            Url::parse(&format!("http://{}", url)).ok()?
+        };
+
+        for _ in 0..MAX_SHORTENER_HOPS {
+            let Some(target) = shortener_key(&url).and_then(|key| self.shorteners.get(&key)) else {
+                break;
+            };
+
+            url = Url::parse(target).ok()?;
+        }
+
+        url.set_fragment(None);
+        let params: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(name, _)| !is_tracking_param(name))
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+
+        if params.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut().clear().extend_pairs(params);
+        }
+
+        Some(url)
+    }
+}
+
+pub struct LinkBlocklistRule {
+    blocklist: Arc<DomainBlocklist>,
+    normalizer: UrlNormalizer,
+    // Stops the refresh task once the rule is dropped
+    _refresh_guard: Option<DropGuard>,
+}
+
+impl LinkBlocklistRule {
+    pub fn new(blocklist: Arc<DomainBlocklist>, normalizer: UrlNormalizer) -> Self {
+        LinkBlocklistRule {
+            blocklist,
+            normalizer,
+            _refresh_guard: None,
+        }
+    }
+
+    /// Loads the blocklist and keeps refreshing it on the tracker until the
+    /// token is cancelled or the rule is dropped
+    pub async fn from_config(
+        config: &LinkBlocklistConfig,
+        tracker: &TaskTracker,
+        cancellation_token: &CancellationToken,
+    ) -> Result<Self, EventAnalysisError> {
+        let blocklist = Arc::new(DomainBlocklist::default());
+        let loader = BlocklistLoader::connect(&config.source).await?;
+        blocklist.reload(&loader).await?;
+        info!("Loaded link blocklist, {} domains", blocklist.len());
+
+        let refresh_token = cancellation_token.child_token();
+        spawn_blocklist_refresh(
+            tracker,
+            blocklist.clone(),
+            loader,
+            Duration::from_secs(config.refresh_secs),
+            refresh_token.clone(),
+        );
+
+        let normalizer = match &config.shorteners_file {
+            Some(path) => UrlNormalizer::from_file(path).await?,
+            None => UrlNormalizer::default(),
+        };
+
+        let mut rule = LinkBlocklistRule::new(blocklist, normalizer);
+        rule._refresh_guard = Some(refresh_token.drop_guard());
+        Ok(rule)
+    }
+}
+
+pub const RULE_ID: &str = "link_blocklist";
+
+#[async_trait]
+impl Rule for LinkBlocklistRule {
+    fn id(&self) -> &'static str {
+        RULE_ID
+    }
+
+    async fn evaluate(&self, event: &Event) -> Result<Option<Rejection>, EventAnalysisError> {
+        for link in event_links(event) {
+            let Some(url) = self.normalizer.normalize(link) else {
+                continue;
+            };
+
+            let Some(host) = url.host_str() else {
+                continue;
+            };
+
+            if let Some(domain) = self.blocklist.matching(host) {
+                debug!("Event {} links to blocked domain {}", event.id, domain);
+                return Ok(Some(
+                    Rejection::new(self.id(), DeleteRequest::BlockedDomain(event.id, domain))
+                        .with_evidence(Evidence::Detail(link.to_string())),
+                ));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Links found in the content and in `r` tags
+pub(super) fn event_links(event: &Event) -> impl Iterator<Item = &str> {
+    let content_links = URL_REGEX
+        .find_iter(&event.content)
+        .map(|link| trim_trailing_punctuation(link.as_str()));
+
+    let tag_links = event.tags.iter().filter_map(|tag| match tag.as_slice() {
+        [kind, value, ..] if kind == "r" => Some(value.as_str()),
+        _ => None,
+    });
+
+    content_links.chain(tag_links)
+}
+
+// Links at the end of a sentence or inside parentheses pick up the punctuation
+fn trim_trailing_punctuation(link: &str) -> &str {
+    let mut link = link.trim_end_matches(['.', ',', ';', ':', '!', '?']);
+    if link.ends_with(')') && link.matches('(').count() < link.matches(')').count() {
+        link = &link[..link.len() - 1];
+    }
+    link
+}
+
+fn is_tracking_param(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name.starts_with("utm_") || TRACKING_PARAMS.contains(&name.as_str())
+}
+
+fn shortener_key(url: &Url) -> Option<String> {
+    Some(format!(
+        "{}{}",
+        url.host_str()?,
+        url.path().trim_end_matches('/')
+    ))
+}
+
+fn normalize_domain(domain: &str) -> Option<String> {
+    let domain = domain.trim().trim_start_matches("*.").trim_matches('.');
+    if domain.is_empty() {
+        return None;
+    }
+
+    // Let the url crate take care of lowercasing and punycode
+    let url = Url::parse(&format!("http://{}", domain)).ok()?;
+    url.host_str().map(str::to_string)
+}
+
+async fn read_lines(path: &Path) -> Result<Vec<String>, EventAnalysisError> {
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| EventAnalysisError::ConfigError(format!("{}: {}", path.display(), e)))?;
+
+    Ok(contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocklist_suffix_matching() {
+        let blocklist = DomainBlocklist::new(["evil.com", "*.spam.io", "bücher.example"]);
+
+        assert_eq!(blocklist.matching("evil.com"), Some("evil.com".to_string()));
+        assert_eq!(
+            blocklist.matching("cdn.links.evil.com"),
+            Some("evil.com".to_string())
+        );
+        assert_eq!(blocklist.matching("x.spam.io"), Some("spam.io".to_string()));
+        assert_eq!(
+            blocklist.matching("xn--bcher-kva.example"),
+            Some("xn--bcher-kva.example".to_string())
+        );
+        assert_eq!(blocklist.matching("notevil.com"), None);
+        assert_eq!(blocklist.matching("com"), None);
+    }
+
+    #[test]
+    fn test_normalize_url() {
+        let normalizer = UrlNormalizer::new(HashMap::from([(
+            "https://sho.rt/abc".to_string(),
+            "https://Evil.com/landing?utm_source=nostr&id=1".to_string(),
+        )]));
+
+        assert_eq!(
+            normalizer
+                .normalize("https://example.com/page?utm_medium=x&fbclid=y&q=1#top")
+                .unwrap()
+                .as_str(),
+            "https://example.com/page?q=1"
+        );
+        assert_eq!(
+            normalizer
+                .normalize("https://sho.rt/abc/")
+                .unwrap()
+                .as_str(),
+            "https://evil.com/landing?id=1"
+        );
+        assert_eq!(
+            normalizer
+                .normalize("https://bücher.example/")
+                .unwrap()
+                .host_str(),
+            Some("xn--bcher-kva.example")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rule_matches_content_and_r_tags() {
+        let blocklist = Arc::new(DomainBlocklist::new(["evil.com"]));
+        let rule = LinkBlocklistRule::new(blocklist, UrlNormalizer::default());
+        let keys = Keys::generate();
+
+        let in_content = EventBuilder::text_note("Free sats (https://www.EVIL.com/claim).", [])
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(
+            rule.evaluate(&in_content)
+                .await
+                .unwrap()
+                .map(|rejection| rejection.delete_request),
+            Some(DeleteRequest::BlockedDomain(
+                in_content.id,
+                "evil.com".to_string()
+            ))
+        );
+
+        let in_tag = EventBuilder::text_note(
+            "Look at this",
+            [Tag::parse(&["r", "https://promo.evil.com"]).unwrap()],
+        )
+        .to_event(&keys)
+        .unwrap();
+        assert!(rule.evaluate(&in_tag).await.unwrap().is_some());
+
+        let clean = EventBuilder::text_note("https://nos.social is nice", [])
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(rule.evaluate(&clean).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_links_without_scheme() {
+        let event = EventBuilder::text_note(
+            "Go to evil.com/claim, or www.evil.example. Read the file.txt e.g. now",
+            [],
+        )
+        .to_event(&Keys::generate())
+        .unwrap();
+        assert_eq!(
+            event_links(&event).collect::<Vec<_>>(),
+            vec!["evil.com/claim", "www.evil.example"]
+        );
+
+        assert_eq!(
+            UrlNormalizer::default()
+                .normalize("evil.com/claim?utm_source=x")
+                .unwrap()
+                .as_str(),
+            "http://evil.com/claim"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refresh_stops_with_the_rule() {
+        let path = std::env::temp_dir().join(format!("blocklist-{}.txt", std::process::id()));
+        std::fs::write(&path, "evil.com # spam campaign\n").unwrap();
+        let config = LinkBlocklistConfig {
+            source: BlocklistSource::File(path.clone()),
+            refresh_secs: 300,
+            shorteners_file: None,
+        };
+        let tracker = TaskTracker::new();
+        let cancellation_token = CancellationToken::new();
+
+        let rule = LinkBlocklistRule::from_config(&config, &tracker, &cancellation_token)
+            .await
+            .unwrap();
+        assert_eq!(rule.blocklist.len(), 1);
+
+        tracker.close();
+        drop(rule);
+        tokio::time::timeout(Duration::from_secs(1), tracker.wait())
+            .await
+            .unwrap();
+        assert!(!cancellation_token.is_cancelled());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
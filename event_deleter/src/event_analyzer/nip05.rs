@@ -0,0 +1,464 @@
+use super::EventAnalysisError;
+use async_trait::async_trait;
+use nostr_sdk::prelude::*;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+use tracing::debug;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Nip05Config {
+    /// How long a resolved identifier is trusted before asking the domain again
+    #[serde(default = "default_cache_ttl_secs")]
+    pub cache_ttl_secs: u64,
+
+    /// Timeout (in seconds) for each request to a domain's nostr.json
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+
+    /// Resolved identifiers kept in the cache, the oldest are dropped first
+    #[serde(default = "default_max_cache_entries")]
+    pub max_cache_entries: usize,
+}
+
+fn default_cache_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_request_timeout_secs() -> u64 {
+    5
+}
+
+fn default_max_cache_entries() -> usize {
+    10_000
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nip05Status {
+    NotClaimed,
+    Verified,
+    Failed,
+    // The domain couldn't be queried, we can't say anything about the claim
+    Unreachable,
+}
+
+/// Looks up the public key a domain publishes for a name in its nostr.json.
+#[async_trait]
+pub trait Nip05Resolver: Send + Sync + 'static {
+    async fn resolve(
+        &self,
+        name: &str,
+        domain: &str,
+    ) -> Result<Option<PublicKey>, EventAnalysisError>;
+}
+
+#[derive(Deserialize)]
+struct NostrJson {
+    #[serde(default)]
+    names: HashMap<String, String>,
+}
+
+pub struct HttpNip05Resolver {
+    http_client: reqwest::Client,
+    base_url: Option<Url>,
+}
+
+impl HttpNip05Resolver {
+    pub fn new(request_timeout: Duration) -> Result<Self, EventAnalysisError> {
+        // NIP-05 forbids following redirects
+        let http_client = reqwest::Client::builder()
+            .timeout(request_timeout)
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(Arc::new(PublicAddressResolver))
+            .build()
+            .map_err(|e| EventAnalysisError::Nip05Error(e.to_string()))?;
+
+        Ok(HttpNip05Resolver {
+            http_client,
+            base_url: None,
+        })
+    }
+
+    /// Sends every lookup to `base_url` instead of `https://<domain>`
+    pub fn with_base_url(mut self, base_url: Url) -> Self {
+        self.base_url = Some(base_url);
+        self
+    }
+}
+
+#[async_trait]
+impl Nip05Resolver for HttpNip05Resolver {
+    async fn resolve(
+        &self,
+        name: &str,
+        domain: &str,
+    ) -> Result<Option<PublicKey>, EventAnalysisError> {
+        let base_url = match &self.base_url {
+            Some(base_url) => base_url.clone(),
+            None => Url::parse(&format!("https://{}", domain))
+                .map_err(|e| EventAnalysisError::Nip05Error(e.to_string()))?,
+        };
+
+        let mut url = base_url
+            .join("/.well-known/nostr.json")
+            .map_err(|e| EventAnalysisError::Nip05Error(e.to_string()))?;
+        url.query_pairs_mut().append_pair("name", name);
+
+        let nostr_json: NostrJson = self
+            .http_client
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| EventAnalysisError::Nip05Error(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| EventAnalysisError::Nip05Error(e.to_string()))?;
+
+        Ok(nostr_json
+            .names
+            .get(name)
+            .and_then(|public_key| PublicKey::from_hex(public_key).ok()))
+    }
+}
+
+// Claimed domains are chosen by whoever publishes the metadata, they must not
+// point the verifier at our own network
+struct PublicAddressResolver;
+
+impl Resolve for PublicAddressResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+                return Err(format!("{} resolves to {}", name.as_str(), addr.ip()).into());
+            }
+
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    // Unique local, fc00::/7
+                    || (first & 0xfe00) == 0xfc00
+                    // Link local, fe80::/10
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+struct CachedResolution {
+    resolved_at: Instant,
+    public_key: Option<PublicKey>,
+}
+
+/// Checks NIP-05 claims against the claimed domain, caching what each
+/// identifier resolved to so popular domains are only asked once per TTL.
+pub struct Nip05Verifier {
+    resolver: Box<dyn Nip05Resolver>,
+    cache: Mutex<HashMap<String, CachedResolution>>,
+    cache_ttl: Duration,
+    max_cache_entries: usize,
+}
+
+impl Nip05Verifier {
+    pub fn new(resolver: Box<dyn Nip05Resolver>, cache_ttl: Duration) -> Self {
+        Nip05Verifier {
+            resolver,
+            cache: Mutex::new(HashMap::new()),
+            cache_ttl,
+            max_cache_entries: default_max_cache_entries(),
+        }
+    }
+
+    /// Spam rotating identifiers would grow the cache without a cap
+    pub fn with_max_cache_entries(mut self, max_cache_entries: usize) -> Self {
+        self.max_cache_entries = max_cache_entries;
+        self
+    }
+
+    pub fn from_config(config: &Nip05Config) -> Result<Self, EventAnalysisError> {
+        let resolver = HttpNip05Resolver::new(Duration::from_secs(config.request_timeout_secs))?;
+        Ok(Nip05Verifier::new(
+            Box::new(resolver),
+            Duration::from_secs(config.cache_ttl_secs),
+        )
+        .with_max_cache_entries(config.max_cache_entries))
+    }
+
+    pub async fn verify(&self, public_key: &PublicKey, nip05: Option<&str>) -> Nip05Status {
+        let Some(nip05) = nip05.map(str::trim).filter(|nip05| !nip05.is_empty()) else {
+            return Nip05Status::NotClaimed;
+        };
+
+        let Some((name, domain)) = parse_identifier(nip05) else {
+            return Nip05Status::Failed;
+        };
+
+        let identifier = format!("{}@{}", name, domain);
+        let resolved = match self.cached(&identifier) {
+            Some(resolved) => resolved,
+            None => match self.resolver.resolve(&name, &domain).await {
+                Ok(resolved) => {
+                    self.cache_resolution(identifier, resolved);
+                    resolved
+                }
+                Err(e) => {
+                    // Not cached, a domain being down says nothing about the claim
+                    debug!("Couldn't resolve {}: {}", identifier, e);
+                    return Nip05Status::Unreachable;
+                }
+            },
+        };
+
+        if resolved.as_ref() == Some(public_key) {
+            Nip05Status::Verified
+        } else {
+            Nip05Status::Failed
+        }
+    }
+
+    fn cache_resolution(&self, identifier: String, public_key: Option<PublicKey>) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.max_cache_entries {
+            cache.retain(|_, cached| cached.resolved_at.elapsed() < self.cache_ttl);
+        }
+        while cache.len() >= self.max_cache_entries.max(1) {
+            let oldest = cache
+                .iter()
+                .min_by_key(|(_, cached)| cached.resolved_at)
+                .map(|(identifier, _)| identifier.clone());
+            match oldest {
+                Some(oldest) => cache.remove(&oldest),
+                None => break,
+            };
+        }
+
+        cache.insert(
+            identifier,
+            CachedResolution {
+                resolved_at: Instant::now(),
+                public_key,
+            },
+        );
+    }
+
+    fn cached(&self, identifier: &str) -> Option<Option<PublicKey>> {
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(identifier) {
+            Some(cached) if cached.resolved_at.elapsed() < self.cache_ttl => {
+                Some(cached.public_key)
+            }
+            Some(_) => {
+                cache.remove(identifier);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+fn parse_identifier(nip05: &str) -> Option<(String, String)> {
+    let (name, domain) = match nip05.rsplit_once('@') {
+        Some((name, domain)) => (name, domain),
+        // A bare domain is shorthand for "_@domain"
+        None => ("_", nip05),
+    };
+
+    let name = name.to_lowercase();
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    if name.is_empty() || domain.is_empty() || domain.contains('/') {
+        return None;
+    }
+
+    // NIP-05 names a domain, an address or a local host is never a valid claim
+    let host = match domain.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => domain.as_str(),
+    };
+    let is_ip = |host: &str| {
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok()
+    };
+    if is_ip(&domain) || is_ip(host) || host == "localhost" || host.ends_with(".localhost") {
+        return None;
+    }
+
+    Some((name, domain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // Serves the same nostr.json to every request and counts them
+    async fn spawn_stub_server(names: Vec<(&str, PublicKey)>) -> (Url, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let names: HashMap<String, String> = names
+            .into_iter()
+            .map(|(name, public_key)| (name.to_string(), public_key.to_hex()))
+            .collect();
+        let body = serde_json::json!({ "names": names }).to_string();
+
+        let requests_clone = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                requests_clone.fetch_add(1, Ordering::SeqCst);
+
+                let mut buffer = vec![0; 4096];
+                let _ = socket.read(&mut buffer).await;
+
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn verifier(base_url: Url, cache_ttl: Duration) -> Nip05Verifier {
+        let resolver = HttpNip05Resolver::new(Duration::from_secs(2))
+            .unwrap()
+            .with_base_url(base_url);
+        Nip05Verifier::new(Box::new(resolver), cache_ttl)
+    }
+
+    #[tokio::test]
+    async fn test_verify_nip05_claims() {
+        let alice = Keys::generate().public_key();
+        let impostor = Keys::generate().public_key();
+        let (url, requests) = spawn_stub_server(vec![("alice", alice)]).await;
+        let verifier = verifier(url, Duration::from_secs(60));
+
+        assert_eq!(verifier.verify(&alice, None).await, Nip05Status::NotClaimed);
+        assert_eq!(
+            verifier.verify(&alice, Some("Alice@example.com")).await,
+            Nip05Status::Verified
+        );
+        assert_eq!(
+            verifier.verify(&impostor, Some("alice@example.com")).await,
+            Nip05Status::Failed
+        );
+        assert_eq!(
+            verifier.verify(&impostor, Some("bob@example.com")).await,
+            Nip05Status::Failed
+        );
+
+        // alice was cached after the first lookup
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cache_expires() {
+        let alice = Keys::generate().public_key();
+        let (url, requests) = spawn_stub_server(vec![("alice", alice)]).await;
+        let verifier = verifier(url, Duration::from_millis(100));
+
+        verifier.verify(&alice, Some("alice@example.com")).await;
+        verifier.verify(&alice, Some("alice@example.com")).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        verifier.verify(&alice, Some("alice@example.com")).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cache_is_bounded() {
+        let alice = Keys::generate().public_key();
+        let (url, requests) = spawn_stub_server(vec![("alice", alice)]).await;
+        let verifier = verifier(url, Duration::from_secs(60)).with_max_cache_entries(2);
+
+        for name in ["a", "b", "c", "d"] {
+            verifier
+                .verify(&alice, Some(&format!("{}@example.com", name)))
+                .await;
+        }
+        assert_eq!(verifier.cache.lock().unwrap().len(), 2);
+
+        // The oldest ones were dropped
+        verifier.verify(&alice, Some("d@example.com")).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+        verifier.verify(&alice, Some("a@example.com")).await;
+        assert_eq!(requests.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn test_local_hosts_are_not_valid_claims() {
+        for nip05 in [
+            "alice@127.0.0.1",
+            "alice@10.0.0.1:8080",
+            "alice@[::1]",
+            "alice@localhost",
+            "alice@redis.localhost",
+        ] {
+            assert_eq!(parse_identifier(nip05), None, "{}", nip05);
+        }
+
+        assert!(!is_public("192.168.1.10".parse().unwrap()));
+        assert!(!is_public("169.254.169.254".parse().unwrap()));
+        assert!(!is_public("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!is_public("fd00::1".parse().unwrap()));
+        assert!(is_public("93.184.216.34".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_domains_resolving_to_local_addresses_are_refused() {
+        let resolver = HttpNip05Resolver::new(Duration::from_secs(2)).unwrap();
+        assert!(resolver.resolve("alice", "localhost").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_domain_is_not_a_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+
+        let verifier = verifier(url, Duration::from_secs(60));
+        let alice = Keys::generate().public_key();
+
+        assert_eq!(
+            verifier.verify(&alice, Some("alice@example.com")).await,
+            Nip05Status::Unreachable
+        );
+    }
+}
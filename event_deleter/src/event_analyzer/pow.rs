@@ -0,0 +1,232 @@
+use super::{DeleteRequest, EventAnalysisError, Evidence, Rejection, Rule};
+use async_trait::async_trait;
+use nostr_sdk::nips::nip13::get_leading_zero_bits;
+use nostr_sdk::prelude::*;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use thiserror::Error as ThisError;
+use tracing::debug;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProofOfWorkConfig {
+    /// Minimum difficulty for kinds without their own entry, 0 disables it
+    #[serde(default)]
+    pub default_difficulty: u8,
+
+    /// Minimum difficulty per kind, overriding the default
+    #[serde(default)]
+    pub kinds: HashMap<u16, u8>,
+
+    /// Hex or bech32 public keys that don't need to do any work
+    #[serde(default)]
+    pub trusted_pubkeys: Vec<String>,
+}
+
+#[derive(ThisError, Debug, Clone, PartialEq, Eq)]
+pub enum PowError {
+    #[error("pow: difficulty {actual} is less than {required}")]
+    InsufficientDifficulty { actual: u8, required: u8 },
+
+    #[error("pow: committed target {committed} is less than {required}")]
+    InsufficientTarget { committed: u8, required: u8 },
+}
+
+/// NIP-13 requirements for authors outside the trusted set. Shared by the
+/// `ProofOfWorkRule` and the write policy so both enforce the same limits.
+#[derive(Debug, Clone, Default)]
+pub struct PowPolicy {
+    default_difficulty: u8,
+    kinds: HashMap<Kind, u8>,
+    trusted_pubkeys: HashSet<PublicKey>,
+}
+
+impl PowPolicy {
+    pub fn from_config(config: &ProofOfWorkConfig) -> Result<Self, EventAnalysisError> {
+        let mut trusted_pubkeys = HashSet::new();
+        for public_key in &config.trusted_pubkeys {
+            trusted_pubkeys.insert(
+                PublicKey::parse(public_key).map_err(|_| EventAnalysisError::PublicKeyError)?,
+            );
+        }
+
+        Ok(PowPolicy {
+            default_difficulty: config.default_difficulty,
+            kinds: config
+                .kinds
+                .iter()
+                .map(|(kind, difficulty)| (Kind::from(*kind), *difficulty))
+                .collect(),
+            trusted_pubkeys,
+        })
+    }
+
+    pub fn required_difficulty(&self, event: &Event) -> u8 {
+        if self.trusted_pubkeys.contains(&event.pubkey) {
+            return 0;
+        }
+
+        self.kinds
+            .get(&event.kind)
+            .copied()
+            .unwrap_or(self.default_difficulty)
+    }
+
+    pub fn check(&self, event: &Event) -> Result<(), PowError> {
+        let required = self.required_difficulty(event);
+        if required == 0 {
+            return Ok(());
+        }
+
+        // A committed target protects against spammers mining for a low
+        // target and getting lucky, so it has to meet the requirement too
+        if let Some(committed) = committed_target(event) {
+            if committed < required {
+                return Err(PowError::InsufficientTarget {
+                    committed,
+                    required,
+                });
+            }
+        }
+
+        let actual = get_leading_zero_bits(event.id.as_bytes());
+        if actual < required {
+            return Err(PowError::InsufficientDifficulty { actual, required });
+        }
+
+        Ok(())
+    }
+}
+
+/// The target difficulty committed in the third value of the `nonce` tag
+fn committed_target(event: &Event) -> Option<u8> {
+    event.tags.iter().find_map(|tag| match tag.as_slice() {
+        [kind, _nonce, target, ..] if kind == "nonce" => target.parse().ok(),
+        _ => None,
+    })
+}
+
+pub struct ProofOfWorkRule {
+    policy: PowPolicy,
+}
+
+impl ProofOfWorkRule {
+    pub fn new(policy: PowPolicy) -> Self {
+        ProofOfWorkRule { policy }
+    }
+}
+
+pub const RULE_ID: &str = "proof_of_work";
+
+#[async_trait]
+impl Rule for ProofOfWorkRule {
+    fn id(&self) -> &'static str {
+        RULE_ID
+    }
+
+    async fn evaluate(&self, event: &Event) -> Result<Option<Rejection>, EventAnalysisError> {
+        match self.policy.check(event) {
+            Ok(()) => Ok(None),
+            Err(e) => {
+                debug!("Event {} failed proof of work: {}", event.id, e);
+                Ok(Some(
+                    Rejection::new(self.id(), DeleteRequest::InsufficientPow(event.id))
+                        .with_evidence(Evidence::Detail(e.to_string())),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A note that didn't get 8 bits of work by chance
+    fn unmined_note(keys: &Keys) -> Event {
+        (0..)
+            .map(|i| {
+                EventBuilder::text_note(format!("hello {}", i), [])
+                    .to_event(keys)
+                    .unwrap()
+            })
+            .find(|event| get_leading_zero_bits(event.id.as_bytes()) < 8)
+            .unwrap()
+    }
+
+    fn policy(trusted: &Keys) -> PowPolicy {
+        PowPolicy::from_config(&ProofOfWorkConfig {
+            default_difficulty: 0,
+            kinds: HashMap::from([(1, 8)]),
+            trusted_pubkeys: vec![trusted.public_key().to_hex()],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_difficulty_per_kind() {
+        let keys = Keys::generate();
+        let policy = policy(&Keys::generate());
+
+        let mined = EventBuilder::text_note("hello", [])
+            .pow(10)
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(policy.check(&mined), Ok(()));
+
+        let reaction = EventBuilder::new(Kind::Reaction, "+", [])
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(policy.check(&reaction), Ok(()));
+
+        let unmined = unmined_note(&keys);
+        assert!(matches!(
+            policy.check(&unmined),
+            Err(PowError::InsufficientDifficulty { required: 8, .. })
+        ));
+    }
+
+    #[test]
+    fn test_committed_target_must_meet_requirement() {
+        let keys = Keys::generate();
+        let policy = policy(&Keys::generate());
+
+        // Mine for 8 bits while only committing to 4
+        let lucky = (0u64..)
+            .map(|nonce| {
+                EventBuilder::text_note(
+                    "hello",
+                    [Tag::parse(&["nonce", &nonce.to_string(), "4"]).unwrap()],
+                )
+                .to_event(&keys)
+                .unwrap()
+            })
+            .find(|event| get_leading_zero_bits(event.id.as_bytes()) >= 8)
+            .unwrap();
+
+        assert_eq!(
+            policy.check(&lucky),
+            Err(PowError::InsufficientTarget {
+                committed: 4,
+                required: 8
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trusted_authors_skip_the_check() {
+        let trusted = Keys::generate();
+        let rule = ProofOfWorkRule::new(policy(&trusted));
+
+        let trusted_note = unmined_note(&trusted);
+        let untrusted_note = unmined_note(&Keys::generate());
+
+        assert_eq!(rule.evaluate(&trusted_note).await.unwrap(), None);
+        assert_eq!(
+            rule.evaluate(&untrusted_note)
+                .await
+                .unwrap()
+                .map(|rejection| rejection.delete_request),
+            Some(DeleteRequest::InsufficientPow(untrusted_note.id))
+        );
+    }
+}
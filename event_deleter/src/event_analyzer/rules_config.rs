@@ -0,0 +1,118 @@
+use super::classifier::{ClassifierConfig, ClassifierRule};
+use super::duplicates::{DuplicateClusterConfig, DuplicateClusterRule};
+use super::impersonation::{ImpersonationConfig, ImpersonationRule, ProtectedIdentityIndex};
+use super::kind_policy::{KindPolicy, KindPolicyConfig, KindPolicyRule};
+use super::links::{LinkBlocklistConfig, LinkBlocklistRule};
+use super::nip05::{Nip05Config, Nip05Verifier};
+use super::pow::{PowPolicy, ProofOfWorkConfig, ProofOfWorkRule};
+use super::tag_limits::{TagLimitsConfig, TagLimitsRule};
+use super::{EventAnalysisError, Rule};
+use crate::settings::Settings;
+use config::{Config, File};
+use nostr_sdk::prelude::*;
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::info;
+
+/// Optional rules run by the `Validator` on top of the built-in checks. Each
+/// section enables its rule when present in the YAML file.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RulesConfig {
+    /// Verifies NIP-05 claims for the rules that use them as a signal
+    pub nip05: Option<Nip05Config>,
+    pub impersonation: Option<ImpersonationConfig>,
+    pub link_blocklist: Option<LinkBlocklistConfig>,
+    pub proof_of_work: Option<ProofOfWorkConfig>,
+    pub tag_limits: Option<TagLimitsConfig>,
+    pub kind_policy: Option<KindPolicyConfig>,
+    pub duplicate_cluster: Option<DuplicateClusterConfig>,
+    pub classifier: Option<ClassifierConfig>,
+}
+
+impl RulesConfig {
+    pub fn from_file(path: &Path) -> Result<Self, EventAnalysisError> {
+        Config::builder()
+            .add_source(File::from(path))
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .map_err(|e| EventAnalysisError::ConfigError(e.to_string()))
+    }
+
+    /// Keeps the rules from changing state shared with live cleaners, for
+    /// shadow mode
+    pub fn shadow(&mut self) {
+        if let Some(duplicate_cluster_config) = &mut self.duplicate_cluster {
+            duplicate_cluster_config.shadow();
+        }
+    }
+
+    /// Rules refreshing their data in the background do it on the tracker,
+    /// until the token is cancelled
+    pub async fn build_rules(
+        &self,
+        nostr_client: &Client,
+        settings: &Settings,
+        tracker: &TaskTracker,
+        cancellation_token: &CancellationToken,
+    ) -> Result<Vec<Box<dyn Rule>>, EventAnalysisError> {
+        let mut rules: Vec<Box<dyn Rule>> = Vec::new();
+
+        // Cheap checks that don't need any lookup go first
+        if let Some(kind_policy_config) = &self.kind_policy {
+            rules.push(Box::new(KindPolicyRule::new(KindPolicy::from_file(
+                &kind_policy_config.policy_file,
+            )?)));
+        }
+
+        if let Some(proof_of_work_config) = &self.proof_of_work {
+            rules.push(Box::new(ProofOfWorkRule::new(PowPolicy::from_config(
+                proof_of_work_config,
+            )?)));
+        }
+
+        if let Some(tag_limits_config) = &self.tag_limits {
+            rules.push(Box::new(TagLimitsRule::new(tag_limits_config)));
+        }
+
+        let nip05_verifier = match &self.nip05 {
+            Some(nip05_config) => Some(Arc::new(Nip05Verifier::from_config(nip05_config)?)),
+            None => None,
+        };
+
+        if let Some(impersonation_config) = &self.impersonation {
+            let index = ProtectedIdentityIndex::load(nostr_client, impersonation_config).await?;
+            info!(
+                "Impersonation rule enabled with {} protected identities",
+                index.len()
+            );
+            rules.push(Box::new(ImpersonationRule::new(
+                index,
+                impersonation_config.min_signals,
+                nip05_verifier.clone(),
+            )));
+        }
+
+        if let Some(link_blocklist_config) = &self.link_blocklist {
+            rules.push(Box::new(
+                LinkBlocklistRule::from_config(link_blocklist_config, tracker, cancellation_token)
+                    .await?,
+            ));
+        }
+
+        if let Some(classifier_config) = &self.classifier {
+            rules.push(Box::new(ClassifierRule::from_config(classifier_config)?));
+        }
+
+        // Last, so content already rejected by another rule isn't counted
+        if let Some(duplicate_cluster_config) = &self.duplicate_cluster {
+            rules.push(Box::new(
+                DuplicateClusterRule::from_config(duplicate_cluster_config, settings).await?,
+            ));
+        }
+
+        Ok(rules)
+    }
+}
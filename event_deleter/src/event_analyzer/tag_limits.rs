@@ -0,0 +1,225 @@
+use super::{DeleteRequest, EventAnalysisError, Evidence, Rejection, Rule};
+use async_trait::async_trait;
+use nostr_sdk::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+use tracing::debug;
+
+// Same limit hellthreadPolicy applies at ingestion in policies.ts
+const HELLTHREAD_LIMIT: usize = 100;
+
+/// Limits for a single kind, missing values are not limited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct TagLimits {
+    pub max_p_tags: Option<usize>,
+    pub max_e_tags: Option<usize>,
+    pub max_t_tags: Option<usize>,
+    pub max_tag_value_length: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TagLimitsConfig {
+    /// Limits per kind, kinds without an entry are not checked
+    #[serde(default = "default_kinds")]
+    pub kinds: HashMap<u16, TagLimits>,
+}
+
+fn default_kinds() -> HashMap<u16, TagLimits> {
+    HashMap::from([(
+        Kind::TextNote.as_u16(),
+        TagLimits {
+            max_p_tags: Some(HELLTHREAD_LIMIT),
+            ..Default::default()
+        },
+    )])
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagLimitViolation {
+    TooManyTags {
+        tag: &'static str,
+        count: usize,
+        limit: usize,
+    },
+    TagValueTooLong {
+        length: usize,
+        limit: usize,
+    },
+}
+
+impl Display for TagLimitViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TagLimitViolation::TooManyTags { tag, count, limit } => {
+                write!(f, "{} {} tags, limit is {}", count, tag, limit)
+            }
+            TagLimitViolation::TagValueTooLong { length, limit } => {
+                write!(f, "tag value of {} bytes, limit is {}", length, limit)
+            }
+        }
+    }
+}
+
+impl TagLimits {
+    pub fn check(&self, event: &Event) -> Result<(), TagLimitViolation> {
+        for (tag, limit) in [
+            ("p", self.max_p_tags),
+            ("e", self.max_e_tags),
+            ("t", self.max_t_tags),
+        ] {
+            let Some(limit) = limit else {
+                continue;
+            };
+
+            let count = count_tags(event, tag);
+            if count > limit {
+                return Err(TagLimitViolation::TooManyTags { tag, count, limit });
+            }
+        }
+
+        if let Some(limit) = self.max_tag_value_length {
+            let longest = event
+                .tags
+                .iter()
+                .flat_map(|tag| tag.as_slice().iter().skip(1))
+                .map(String::len)
+                .max()
+                .unwrap_or_default();
+
+            if longest > limit {
+                return Err(TagLimitViolation::TagValueTooLong {
+                    length: longest,
+                    limit,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn count_tags(event: &Event, name: &str) -> usize {
+    event
+        .tags
+        .iter()
+        .filter(|tag| tag.as_slice().first().map(String::as_str) == Some(name))
+        .count()
+}
+
+/// Re-applies the current tag limits to stored events, so anything accepted
+/// before a limit existed or that arrived through sync gets cleaned too.
+pub struct TagLimitsRule {
+    kinds: HashMap<Kind, TagLimits>,
+}
+
+impl TagLimitsRule {
+    pub fn new(config: &TagLimitsConfig) -> Self {
+        TagLimitsRule {
+            kinds: config
+                .kinds
+                .iter()
+                .map(|(kind, limits)| (Kind::from(*kind), *limits))
+                .collect(),
+        }
+    }
+}
+
+pub const RULE_ID: &str = "tag_limits";
+
+#[async_trait]
+impl Rule for TagLimitsRule {
+    fn id(&self) -> &'static str {
+        RULE_ID
+    }
+
+    async fn evaluate(&self, event: &Event) -> Result<Option<Rejection>, EventAnalysisError> {
+        let Some(limits) = self.kinds.get(&event.kind) else {
+            return Ok(None);
+        };
+
+        match limits.check(event) {
+            Ok(()) => Ok(None),
+            Err(violation) => {
+                debug!("Event {} exceeds tag limits: {}", event.id, violation);
+                Ok(Some(
+                    Rejection::new(self.id(), DeleteRequest::TagSpam(event.id))
+                        .with_evidence(Evidence::Detail(violation.to_string())),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_with_tags(tags: Vec<Tag>) -> Event {
+        EventBuilder::text_note("hello", tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    fn p_tags(count: usize) -> Vec<Tag> {
+        (0..count)
+            .map(|_| Tag::public_key(Keys::generate().public_key()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_hellthread_limit_by_default() {
+        let config: TagLimitsConfig = serde_json::from_str("{}").unwrap();
+        let rule = TagLimitsRule::new(&config);
+
+        let hellthread = note_with_tags(p_tags(101));
+        assert_eq!(
+            rule.evaluate(&hellthread)
+                .await
+                .unwrap()
+                .map(|rejection| rejection.delete_request),
+            Some(DeleteRequest::TagSpam(hellthread.id))
+        );
+
+        let thread = note_with_tags(p_tags(100));
+        assert_eq!(rule.evaluate(&thread).await.unwrap(), None);
+
+        // Contact lists are made of p tags, they are not limited by default
+        let contact_list = EventBuilder::new(Kind::ContactList, "", p_tags(500))
+            .to_event(&Keys::generate())
+            .unwrap();
+        assert_eq!(rule.evaluate(&contact_list).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_tag_limits() {
+        let limits = TagLimits {
+            max_t_tags: Some(2),
+            max_tag_value_length: Some(10),
+            ..Default::default()
+        };
+
+        let hashtags = note_with_tags(vec![
+            Tag::hashtag("a"),
+            Tag::hashtag("b"),
+            Tag::hashtag("c"),
+        ]);
+        assert_eq!(
+            limits.check(&hashtags),
+            Err(TagLimitViolation::TooManyTags {
+                tag: "t",
+                count: 3,
+                limit: 2
+            })
+        );
+
+        let long_value = note_with_tags(vec![Tag::hashtag("a".repeat(11))]);
+        assert_eq!(
+            limits.check(&long_value),
+            Err(TagLimitViolation::TagValueTooLong {
+                length: 11,
+                limit: 10
+            })
+        );
+    }
+}
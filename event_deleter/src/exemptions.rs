@@ -0,0 +1,464 @@
+use crate::event_analyzer::DeleteRequest;
+use crate::settings::{Settings, SettingsError};
+use async_trait::async_trait;
+use nostr_sdk::prelude::*;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::fs;
+use tokio::sync::Mutex;
+use tracing::info;
+
+pub static DEFAULT_EXEMPTIONS_KEY: &str = "exemptions";
+
+/// A pubkey whose events are never deleted by an author-level rule, usually
+/// after a successful appeal. Vanish requests are not affected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Exemption {
+    pub pubkey: PublicKey,
+    pub reason: String,
+    pub created_at: Timestamp,
+    /// Never expires when not set
+    pub expires_at: Option<Timestamp>,
+}
+
+impl Exemption {
+    pub fn is_active(&self, now: Timestamp) -> bool {
+        match self.expires_at {
+            Some(expires_at) => now < expires_at,
+            None => true,
+        }
+    }
+}
+
+#[async_trait]
+pub trait ExemptionSource: Send + Sync + 'static {
+    async fn get(&self, pubkey: &PublicKey) -> Result<Option<Exemption>, ExemptionError>;
+    async fn list(&self) -> Result<Vec<Exemption>, ExemptionError>;
+    async fn add(&self, exemption: &Exemption) -> Result<(), ExemptionError>;
+    /// Returns false if there was no exemption for the pubkey
+    async fn remove(&self, pubkey: &PublicKey) -> Result<bool, ExemptionError>;
+}
+
+/// A JSON array of exemptions. It's read on every lookup so edits made by
+/// hand or by `relay_admin` apply right away.
+pub struct FileExemptionSource {
+    path: PathBuf,
+    // Serializes the read-modify-write of add and remove
+    write_lock: Mutex<()>,
+}
+
+impl FileExemptionSource {
+    pub fn new(path: &Path) -> Self {
+        FileExemptionSource {
+            path: path.to_path_buf(),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    async fn read(&self) -> Result<Vec<Exemption>, ExemptionError> {
+        match fs::read_to_string(&self.path).await {
+            Ok(json) => Ok(serde_json::from_str(&json)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Written to a temporary file first so readers never see half of it
+    async fn write(&self, exemptions: &[Exemption]) -> Result<(), ExemptionError> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+
+        fs::write(&tmp_path, serde_json::to_vec_pretty(exemptions)?).await?;
+        fs::rename(&tmp_path, &self.path).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ExemptionSource for FileExemptionSource {
+    async fn get(&self, pubkey: &PublicKey) -> Result<Option<Exemption>, ExemptionError> {
+        Ok(self
+            .read()
+            .await?
+            .into_iter()
+            .find(|exemption| exemption.pubkey == *pubkey))
+    }
+
+    async fn list(&self) -> Result<Vec<Exemption>, ExemptionError> {
+        self.read().await
+    }
+
+    async fn add(&self, exemption: &Exemption) -> Result<(), ExemptionError> {
+        let _guard = self.write_lock.lock().await;
+        let mut exemptions = self.read().await?;
+        exemptions.retain(|existing| existing.pubkey != exemption.pubkey);
+        exemptions.push(exemption.clone());
+        self.write(&exemptions).await
+    }
+
+    async fn remove(&self, pubkey: &PublicKey) -> Result<bool, ExemptionError> {
+        let _guard = self.write_lock.lock().await;
+        let mut exemptions = self.read().await?;
+        let before = exemptions.len();
+        exemptions.retain(|existing| existing.pubkey != *pubkey);
+        if exemptions.len() == before {
+            return Ok(false);
+        }
+
+        self.write(&exemptions).await?;
+        Ok(true)
+    }
+}
+
+/// Exemptions in a Redis hash, pubkey to JSON, shared by every instance
+pub struct RedisExemptionSource {
+    con: ConnectionManager,
+    key: String,
+}
+
+impl RedisExemptionSource {
+    pub async fn new(redis_url: &str, key: String) -> Result<Self, ExemptionError> {
+        let client = redis::Client::open(redis_url)?;
+        let con = client.get_connection_manager().await?;
+
+        Ok(RedisExemptionSource { con, key })
+    }
+}
+
+#[async_trait]
+impl ExemptionSource for RedisExemptionSource {
+    async fn get(&self, pubkey: &PublicKey) -> Result<Option<Exemption>, ExemptionError> {
+        let mut con = self.con.clone();
+        let json: Option<String> = con.hget(&self.key, pubkey.to_hex()).await?;
+        Ok(json.map(|json| serde_json::from_str(&json)).transpose()?)
+    }
+
+    async fn list(&self) -> Result<Vec<Exemption>, ExemptionError> {
+        let mut con = self.con.clone();
+        let all: HashMap<String, String> = con.hgetall(&self.key).await?;
+
+        let mut exemptions = Vec::new();
+        for json in all.values() {
+            exemptions.push(serde_json::from_str(json)?);
+        }
+        Ok(exemptions)
+    }
+
+    async fn add(&self, exemption: &Exemption) -> Result<(), ExemptionError> {
+        let mut con = self.con.clone();
+        con.hset::<_, _, _, ()>(
+            &self.key,
+            exemption.pubkey.to_hex(),
+            serde_json::to_string(exemption)?,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, pubkey: &PublicKey) -> Result<bool, ExemptionError> {
+        let mut con = self.con.clone();
+        let removed: usize = con.hdel(&self.key, pubkey.to_hex()).await?;
+        Ok(removed > 0)
+    }
+}
+
+/// Every configured exemption source. A pubkey is exempt when any source has
+/// an active exemption for it.
+#[derive(Clone, Default)]
+pub struct Exemptions {
+    sources: Vec<Arc<dyn ExemptionSource>>,
+}
+
+impl Exemptions {
+    pub fn new(sources: Vec<Arc<dyn ExemptionSource>>) -> Self {
+        Exemptions { sources }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    pub fn sources(&self) -> &[Arc<dyn ExemptionSource>] {
+        &self.sources
+    }
+
+    /// Fails when a source can't be read, unless another one exempts the
+    /// pubkey, so a broken source never gets an exempt author deleted
+    pub async fn is_exempt(&self, pubkey: &PublicKey) -> Result<bool, ExemptionError> {
+        let now = Timestamp::now();
+        let mut error = None;
+        for source in &self.sources {
+            match source.get(pubkey).await {
+                Ok(Some(exemption)) if exemption.is_active(now) => return Ok(true),
+                Ok(_) => {}
+                Err(e) => error = Some(e),
+            }
+        }
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(false),
+        }
+    }
+
+    /// Removes the exempt authors from an automated request. Requests that
+    /// are left with nothing to delete are dropped, vanish requests are
+    /// always kept.
+    pub async fn apply(
+        &self,
+        delete_request: DeleteRequest,
+    ) -> Result<Option<DeleteRequest>, ExemptionError> {
+        if self.is_empty() {
+            return Ok(Some(delete_request));
+        }
+
+        match delete_request {
+            DeleteRequest::Vanish(_, _, _) => Ok(Some(delete_request)),
+            DeleteRequest::DuplicateCluster(ids, authors) => {
+                let mut kept_authors = Vec::new();
+                for author in authors {
+                    if self.is_exempt(&author).await? {
+                        info!("Author {} is exempt, keeping their events", author);
+                    } else {
+                        kept_authors.push(author);
+                    }
+                }
+                Ok(Some(DeleteRequest::DuplicateCluster(ids, kept_authors)))
+            }
+            _ => {
+                for author in delete_request.authors() {
+                    if self.is_exempt(&author).await? {
+                        info!(
+                            "Author {} is exempt, ignoring {} request",
+                            author, delete_request
+                        );
+                        return Ok(None);
+                    }
+                }
+                Ok(Some(delete_request))
+            }
+        }
+    }
+}
+
+/// Exemption options shared by spam_cleaner and relay_admin
+#[derive(clap::Args, Debug, Clone)]
+pub struct ExemptionArgs {
+    /// JSON file with the exempt pubkeys
+    #[arg(long)]
+    pub exemptions_file: Option<PathBuf>,
+
+    /// Redis server holding the exempt pubkeys, --redis-url when no URL is
+    /// given
+    #[arg(long, num_args = 0..=1, require_equals = true)]
+    pub exemptions_redis_url: Option<Option<String>>,
+
+    /// Hash the exempt pubkeys are stored in
+    #[arg(long, default_value = DEFAULT_EXEMPTIONS_KEY)]
+    pub exemptions_key: String,
+}
+
+impl ExemptionArgs {
+    pub async fn exemptions(&self, settings: &Settings) -> Result<Exemptions, ExemptionError> {
+        let mut sources: Vec<Arc<dyn ExemptionSource>> = Vec::new();
+
+        if let Some(path) = &self.exemptions_file {
+            sources.push(Arc::new(FileExemptionSource::new(path)));
+        }
+
+        if let Some(redis_url) = settings.feature_redis_url(&self.exemptions_redis_url)? {
+            sources.push(Arc::new(
+                RedisExemptionSource::new(&redis_url, self.exemptions_key.clone()).await?,
+            ));
+        }
+
+        Ok(Exemptions::new(sources))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ExemptionError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[error("Settings error: {0}")]
+    Settings(#[from] SettingsError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay_commander::{RawCommanderTrait, RelayCommander};
+    use std::sync::Mutex as StdMutex;
+
+    struct UnreadableSource;
+
+    #[async_trait]
+    impl ExemptionSource for UnreadableSource {
+        async fn get(&self, _pubkey: &PublicKey) -> Result<Option<Exemption>, ExemptionError> {
+            Err(std::io::Error::other("unreadable").into())
+        }
+        async fn list(&self) -> Result<Vec<Exemption>, ExemptionError> {
+            Err(std::io::Error::other("unreadable").into())
+        }
+        async fn add(&self, _exemption: &Exemption) -> Result<(), ExemptionError> {
+            Ok(())
+        }
+        async fn remove(&self, _pubkey: &PublicKey) -> Result<bool, ExemptionError> {
+            Ok(false)
+        }
+    }
+
+    struct RecordingCommander(Arc<StdMutex<Vec<Filter>>>);
+
+    #[async_trait]
+    impl RawCommanderTrait for RecordingCommander {
+        async fn delete_from_filter(
+            &self,
+            filter: Filter,
+            _dry_run: bool,
+        ) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+            self.0.lock().unwrap().push(filter);
+            Ok(None)
+        }
+    }
+
+    fn temp_file() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "exemptions-{}.json",
+            Keys::generate().public_key().to_hex()
+        ))
+    }
+
+    fn exemption(pubkey: PublicKey, expires_at: Option<Timestamp>) -> Exemption {
+        Exemption {
+            pubkey,
+            reason: "Appeal accepted".to_string(),
+            created_at: Timestamp::now(),
+            expires_at,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_source() {
+        let path = temp_file();
+        let source = FileExemptionSource::new(&path);
+        let alice = Keys::generate().public_key();
+
+        assert_eq!(source.get(&alice).await.unwrap(), None);
+
+        let alice_exemption = exemption(alice, None);
+        source.add(&alice_exemption).await.unwrap();
+        source.add(&alice_exemption).await.unwrap();
+        assert_eq!(source.list().await.unwrap(), vec![alice_exemption.clone()]);
+        assert_eq!(source.get(&alice).await.unwrap(), Some(alice_exemption));
+
+        assert!(source.remove(&alice).await.unwrap());
+        assert!(!source.remove(&alice).await.unwrap());
+        assert!(source.list().await.unwrap().is_empty());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_exemptions_override_author_requests() {
+        let path = temp_file();
+        let source = FileExemptionSource::new(&path);
+        let exempt = Keys::generate().public_key();
+        let expired = Keys::generate().public_key();
+        let other = Keys::generate().public_key();
+        source.add(&exemption(exempt, None)).await.unwrap();
+        source
+            .add(&exemption(expired, Some(Timestamp::from(1))))
+            .await
+            .unwrap();
+
+        let exemptions = Exemptions::new(vec![Arc::new(source)]);
+
+        assert_eq!(
+            exemptions
+                .apply(DeleteRequest::ForbiddenName(exempt))
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            exemptions
+                .apply(DeleteRequest::ForbiddenName(expired))
+                .await
+                .unwrap(),
+            Some(DeleteRequest::ForbiddenName(expired))
+        );
+
+        let event_id = EventId::all_zeros();
+        assert_eq!(
+            exemptions
+                .apply(DeleteRequest::DuplicateCluster(
+                    vec![event_id],
+                    vec![exempt, other]
+                ))
+                .await
+                .unwrap(),
+            Some(DeleteRequest::DuplicateCluster(vec![event_id], vec![other]))
+        );
+
+        // Users can always erase themselves
+        let vanish = DeleteRequest::Vanish("1-0".to_string(), exempt, None);
+        assert_eq!(
+            exemptions.apply(vanish.clone()).await.unwrap(),
+            Some(vanish)
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_source_fails_closed() {
+        let spammer = Keys::generate().public_key();
+        let event_id = EventId::all_zeros();
+        let exemptions = Exemptions::new(vec![Arc::new(UnreadableSource)]);
+
+        assert!(exemptions.is_exempt(&spammer).await.is_err());
+        assert!(exemptions
+            .apply(DeleteRequest::SpamAuthor(spammer))
+            .await
+            .is_err());
+        assert_eq!(
+            exemptions
+                .apply(DeleteRequest::TagSpam(event_id))
+                .await
+                .unwrap(),
+            Some(DeleteRequest::TagSpam(event_id))
+        );
+
+        // Event deletions go through, the author is left for the retry
+        let filters = Arc::new(StdMutex::new(Vec::new()));
+        let relay_commander =
+            RelayCommander::new(RecordingCommander(filters.clone())).with_exemptions(exemptions);
+        assert!(relay_commander
+            .execute_delete(
+                vec![
+                    DeleteRequest::TagSpam(event_id),
+                    DeleteRequest::SpamAuthor(spammer),
+                ],
+                false,
+            )
+            .await
+            .is_err());
+
+        let filters = filters.lock().unwrap();
+        assert_eq!(filters.len(), 1);
+        assert!(filters[0].authors.is_none());
+    }
+}
@@ -0,0 +1,298 @@
+use crate::http_server::{HttpHandler, HttpResponse};
+use crate::redis_supervisor::{RedisStatus, RedisSupervisor};
+use crate::settings::DEFAULT_STRFRY_PATH;
+use crate::vanish_subscriber_task::{RedisClientConnectionTrait, RedisClientTrait};
+use async_trait::async_trait;
+use serde::Serialize;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use tokio::process::Command;
+use tokio::time::{timeout, Duration, Instant};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Large vanish requests are deleted well within this
+const DEFAULT_MAX_FLUSH_DURATION: Duration = Duration::from_secs(30 * 60);
+
+/// Progress reported by the vanish subscriber tasks, read by the health
+/// endpoints. Clones share the same state.
+#[derive(Clone)]
+pub struct HealthState {
+    inner: Arc<Mutex<HealthStateInner>>,
+}
+
+struct HealthStateInner {
+    started_at: Instant,
+    last_xread: Option<Instant>,
+    flushing_since: Option<Instant>,
+    last_flush: Option<(Instant, Option<String>)>,
+}
+
+impl Default for HealthState {
+    fn default() -> Self {
+        HealthState {
+            inner: Arc::new(Mutex::new(HealthStateInner {
+                started_at: Instant::now(),
+                last_xread: None,
+                flushing_since: None,
+                last_flush: None,
+            })),
+        }
+    }
+}
+
+impl HealthState {
+    pub fn record_xread(&self) {
+        self.inner.lock().unwrap().last_xread = Some(Instant::now());
+    }
+
+    pub fn record_flush_started(&self) {
+        self.inner.lock().unwrap().flushing_since = Some(Instant::now());
+    }
+
+    /// The error of the flush, if it failed
+    pub fn record_flush(&self, error: Option<String>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.flushing_since = None;
+        inner.last_flush = Some((Instant::now(), error));
+    }
+
+    fn snapshot(&self, max_xread_age: Duration, max_flush_duration: Duration) -> HealthSnapshot {
+        let inner = self.inner.lock().unwrap();
+        let since_xread = inner.last_xread.unwrap_or(inner.started_at).elapsed();
+        // The reader blocks while the deletion task works through a large
+        // vanish request, that isn't being stuck unless it takes too long
+        let flushing = inner
+            .flushing_since
+            .is_some_and(|at| at.elapsed() <= max_flush_duration);
+
+        HealthSnapshot {
+            live: since_xread <= max_xread_age || flushing,
+            secs_since_last_xread: inner.last_xread.map(|at| at.elapsed().as_secs_f64()),
+            flushing_for_secs: inner.flushing_since.map(|at| at.elapsed().as_secs_f64()),
+            last_flush: inner.last_flush.as_ref().map(|(at, error)| FlushReport {
+                ok: error.is_none(),
+                secs_ago: at.elapsed().as_secs_f64(),
+                error: error.clone(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HealthSnapshot {
+    live: bool,
+    secs_since_last_xread: Option<f64>,
+    flushing_for_secs: Option<f64>,
+    last_flush: Option<FlushReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct FlushReport {
+    ok: bool,
+    secs_ago: f64,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CheckReport {
+    ok: bool,
+    error: Option<String>,
+}
+
+impl CheckReport {
+    fn from_result(result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => CheckReport {
+                ok: true,
+                error: None,
+            },
+            Err(error) => CheckReport {
+                ok: false,
+                error: Some(error),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ReadinessReport {
+    ready: bool,
+    redis: CheckReport,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redis_connection: Option<RedisStatus>,
+    strfry: CheckReport,
+    #[serde(flatten)]
+    health: HealthSnapshot,
+}
+
+/// Serves `/healthz`, failing when the stream reader is wedged, and
+/// `/readyz`, which also needs Redis to answer, the strfry binary to run and
+/// the last flush to have succeeded. Both answer with a JSON report.
+pub struct HealthHandler<T: RedisClientTrait> {
+    state: HealthState,
+    redis_client: T,
+    con: tokio::sync::Mutex<Option<T::Connection>>,
+    max_xread_age: Duration,
+    max_flush_duration: Duration,
+    strfry_path: String,
+    supervisor: Option<RedisSupervisor>,
+}
+
+impl<T: RedisClientTrait> HealthHandler<T> {
+    pub fn new(state: HealthState, redis_client: T, max_xread_age: Duration) -> Self {
+        HealthHandler {
+            state,
+            redis_client,
+            con: tokio::sync::Mutex::new(None),
+            max_xread_age,
+            max_flush_duration: DEFAULT_MAX_FLUSH_DURATION,
+            strfry_path: DEFAULT_STRFRY_PATH.to_string(),
+            supervisor: None,
+        }
+    }
+
+    /// A flush running for longer than this counts as stuck, even if the
+    /// stream reader is only waiting on it
+    pub fn with_max_flush_duration(mut self, max_flush_duration: Duration) -> Self {
+        self.max_flush_duration = max_flush_duration;
+        self
+    }
+
+    /// `/readyz` also needs the subscriber's connection to be up, not
+    /// backing off
+    pub fn with_redis_supervisor(mut self, supervisor: RedisSupervisor) -> Self {
+        self.supervisor = Some(supervisor);
+        self
+    }
+
+    /// The same binary RawCommander runs
+    pub fn with_strfry_path(mut self, strfry_path: &str) -> Self {
+        self.strfry_path = strfry_path.to_string();
+        self
+    }
+
+    async fn check_redis(&self) -> Result<(), String> {
+        let mut con = self.con.lock().await;
+        if con.is_none() {
+            let new_con = timeout(CHECK_TIMEOUT, self.redis_client.get_connection())
+                .await
+                .map_err(|_| "Timed out connecting".to_string())?
+                .map_err(|e| e.to_string())?;
+            *con = Some(new_con);
+        }
+
+        let Some(con) = con.as_mut() else {
+            return Err("No connection".to_string());
+        };
+
+        timeout(CHECK_TIMEOUT, con.ping())
+            .await
+            .map_err(|_| "Timed out waiting for PING".to_string())?
+            .map_err(|e| e.to_string())
+    }
+}
+
+async fn check_strfry(strfry_path: &str) -> Result<(), String> {
+    let status = timeout(
+        CHECK_TIMEOUT,
+        Command::new(strfry_path)
+            .arg("--help")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .status(),
+    )
+    .await
+    .map_err(|_| "Timed out".to_string())?
+    .map_err(|e| e.to_string())?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("Exited with status: {}", status))
+    }
+}
+
+fn json_response(ok: bool, report: &impl Serialize) -> HttpResponse {
+    let mut response = HttpResponse::ok(
+        "application/json",
+        serde_json::to_string(report).unwrap_or_default(),
+    );
+    if !ok {
+        response.status = 503;
+    }
+    response
+}
+
+#[async_trait]
+impl<T: RedisClientTrait> HttpHandler for HealthHandler<T> {
+    async fn handle(&self, path: &str) -> Option<HttpResponse> {
+        match path {
+            "/healthz" => {
+                let snapshot = self
+                    .state
+                    .snapshot(self.max_xread_age, self.max_flush_duration);
+                Some(json_response(snapshot.live, &snapshot))
+            }
+            "/readyz" => {
+                let (redis, strfry) =
+                    tokio::join!(self.check_redis(), check_strfry(&self.strfry_path));
+                let health = self
+                    .state
+                    .snapshot(self.max_xread_age, self.max_flush_duration);
+                let last_flush_ok = !matches!(&health.last_flush, Some(flush) if !flush.ok);
+                let redis_connection = self.supervisor.as_ref().map(RedisSupervisor::status);
+                let connected = matches!(redis_connection, None | Some(RedisStatus::Connected));
+
+                let report = ReadinessReport {
+                    ready: health.live
+                        && last_flush_ok
+                        && redis.is_ok()
+                        && connected
+                        && strfry.is_ok(),
+                    redis: CheckReport::from_result(redis),
+                    redis_connection,
+                    strfry: CheckReport::from_result(strfry),
+                    health,
+                };
+                Some(json_response(report.ready, &report))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_liveness_follows_the_stream_reader() {
+        let state = HealthState::default();
+        let max_xread_age = Duration::from_secs(60);
+        let max_flush_duration = Duration::from_secs(600);
+
+        state.record_xread();
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(state.snapshot(max_xread_age, max_flush_duration).live);
+
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert!(!state.snapshot(max_xread_age, max_flush_duration).live);
+
+        // Waiting on a long deletion
+        state.record_flush_started();
+        assert!(state.snapshot(max_xread_age, max_flush_duration).live);
+
+        // But not forever
+        tokio::time::advance(Duration::from_secs(601)).await;
+        assert!(!state.snapshot(max_xread_age, max_flush_duration).live);
+
+        state.record_flush(Some("Delete command failed".to_string()));
+        let snapshot = state.snapshot(max_xread_age, max_flush_duration);
+        assert!(!snapshot.live);
+        let last_flush = snapshot.last_flush.unwrap();
+        assert!(!last_flush.ok);
+        assert_eq!(last_flush.error.as_deref(), Some("Delete command failed"));
+    }
+}
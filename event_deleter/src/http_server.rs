@@ -0,0 +1,218 @@
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+// Only GET requests without a body are served, the head fits in here
+const MAX_REQUEST_HEAD_BYTES: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn ok(content_type: &'static str, body: String) -> Self {
+        HttpResponse {
+            status: 200,
+            content_type,
+            body,
+        }
+    }
+
+    fn not_found() -> Self {
+        HttpResponse {
+            status: 404,
+            content_type: "text/plain",
+            body: "Not found\n".to_string(),
+        }
+    }
+
+    fn bad_request() -> Self {
+        HttpResponse {
+            status: 400,
+            content_type: "text/plain",
+            body: "Bad request\n".to_string(),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+}
+
+/// Serves some of the paths of the HTTP server
+#[async_trait]
+pub trait HttpHandler: Send + Sync + 'static {
+    /// None if the path isn't handled here
+    async fn handle(&self, path: &str) -> Option<HttpResponse>;
+}
+
+/// A minimal HTTP/1.1 server for the operational endpoints. Each path is
+/// offered to the handlers in order until one of them answers.
+///
+/// It isn't added to the task tracker so it doesn't hold up shutdown, it
+/// stops when the token is cancelled or the process exits. Returns the bound
+/// address.
+pub async fn spawn_http_server(
+    addr: SocketAddr,
+    handlers: Vec<Arc<dyn HttpHandler>>,
+    cancellation_token: CancellationToken,
+) -> Result<SocketAddr, std::io::Error> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    let handlers = Arc::new(handlers);
+
+    info!("HTTP server listening on {}", local_addr);
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = cancellation_token.cancelled() => {
+                    break;
+                }
+
+                result = listener.accept() => {
+                    match result {
+                        Ok((stream, _)) => {
+                            let handlers = handlers.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = serve_connection(stream, &handlers).await {
+                                    debug!("HTTP connection error: {}", e);
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            error!("Failed to accept HTTP connection: {}", e);
+                        }
+                    }
+                }
+            }
+        }
+
+        debug!("HTTP server stopped");
+    });
+
+    Ok(local_addr)
+}
+
+async fn serve_connection(
+    mut stream: TcpStream,
+    handlers: &[Arc<dyn HttpHandler>],
+) -> Result<(), std::io::Error> {
+    let head = match timeout(REQUEST_TIMEOUT, read_request_head(&mut stream)).await {
+        Ok(head) => head?,
+        Err(_) => return Ok(()),
+    };
+
+    let response = match request_path(&head) {
+        Some(path) => {
+            let mut response = None;
+            for handler in handlers {
+                response = handler.handle(path).await;
+                if response.is_some() {
+                    break;
+                }
+            }
+            response.unwrap_or_else(HttpResponse::not_found)
+        }
+        None => HttpResponse::bad_request(),
+    };
+
+    let header = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+async fn read_request_head(stream: &mut TcpStream) -> Result<String, std::io::Error> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+
+    while !head.windows(4).any(|window| window == b"\r\n\r\n")
+        && head.len() < MAX_REQUEST_HEAD_BYTES
+    {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+// The path of a GET request line, without the query string
+fn request_path(head: &str) -> Option<&str> {
+    let mut parts = head.lines().next()?.split_whitespace();
+    if parts.next()? != "GET" {
+        return None;
+    }
+
+    let target = parts.next()?;
+    Some(target.split('?').next().unwrap_or(target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Hello;
+
+    #[async_trait]
+    impl HttpHandler for Hello {
+        async fn handle(&self, path: &str) -> Option<HttpResponse> {
+            (path == "/hello").then(|| HttpResponse::ok("text/plain", "hi".to_string()))
+        }
+    }
+
+    async fn get(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_routes_requests_to_handlers() {
+        let token = CancellationToken::new();
+        let addr = spawn_http_server(
+            "127.0.0.1:0".parse().unwrap(),
+            vec![Arc::new(Hello)],
+            token.clone(),
+        )
+        .await
+        .unwrap();
+
+        let response = get(addr, "GET /hello?x=1 HTTP/1.1\r\nHost: test\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhi"));
+
+        let response = get(addr, "GET /missing HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let response = get(addr, "POST /hello HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        token.cancel();
+    }
+}
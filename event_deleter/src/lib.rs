@@ -1,6 +1,17 @@
 pub mod analyzer_worker;
+pub mod audit_log;
+pub mod deletion_queue;
 pub mod deletion_task;
 pub mod event_analyzer;
+pub mod exemptions;
+pub mod health;
+pub mod http_server;
+pub mod metrics;
+pub mod quarantine;
+pub mod redis_supervisor;
 pub mod relay_commander;
+pub mod settings;
+pub mod shadow_log;
 pub mod vanish_subscriber_task;
 pub mod worker_pool;
+pub mod write_policy;
@@ -0,0 +1,156 @@
+use crate::http_server::{HttpHandler, HttpResponse};
+use async_trait::async_trait;
+use metrics_exporter_prometheus::{BuildError, PrometheusBuilder, PrometheusHandle};
+
+/// Events validated by the spam cleaner, by `result`: accepted, rejected,
+/// error or timeout. Retried events are counted once, errors and timeouts
+/// when the last attempt failed
+pub static EVENTS_VALIDATED: &str = "events_validated_total";
+/// Rejections by `request_type` and `rule`
+pub static REJECTIONS: &str = "rejections_total";
+pub static VALIDATION_DURATION: &str = "validation_duration_seconds";
+/// Validation attempts that timed out
+pub static VALIDATION_TIMEOUTS: &str = "validation_timeouts_total";
+/// Items waiting in the queue of a worker pool, by `pool`
+pub static WORKER_QUEUE_DEPTH: &str = "worker_pool_queue_depth";
+/// Workers allowed to run at once by adaptive concurrency, by `pool`
+pub static WORKER_CONCURRENCY_LIMIT: &str = "worker_pool_concurrency_limit";
+/// Failed attempts tried again, by `pool`
+pub static WORKER_RETRIES: &str = "worker_pool_retries_total";
+/// Items given up on, by `pool`
+pub static WORKER_POISONED_ITEMS: &str = "worker_pool_poisoned_items_total";
+pub static STRFRY_DELETE_DURATION: &str = "strfry_delete_duration_seconds";
+pub static STRFRY_DELETE_FAILURES: &str = "strfry_delete_failures_total";
+/// Time a delete command waited for the rate limiter, by `path`: vanish or
+/// spam
+pub static DELETION_RATE_LIMIT_WAIT: &str = "deletion_rate_limit_wait_seconds";
+/// Time between the last acknowledged vanish request and the stream tail
+pub static VANISH_STREAM_LAG: &str = "vanish_stream_lag_seconds";
+/// Time from reading a vanish request to its deletion being acknowledged
+pub static VANISH_ACK_LATENCY: &str = "vanish_ack_latency_seconds";
+/// Failed Redis connections and commands the vanish subscriber backed off from
+pub static REDIS_CONNECTION_FAILURES: &str = "redis_connection_failures_total";
+
+// Validation takes milliseconds, strfry deletes and vanish acks can take
+// minutes
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+
+/// Installs the global Prometheus recorder. Until this is called the metrics
+/// recorded across the crate are no-ops.
+pub fn install_recorder() -> Result<PrometheusHandle, BuildError> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets(DURATION_BUCKETS)?
+        .install_recorder()?;
+    describe_metrics();
+
+    Ok(handle)
+}
+
+fn describe_metrics() {
+    metrics::describe_counter!(EVENTS_VALIDATED, "Events validated, by result");
+    metrics::describe_counter!(
+        REJECTIONS,
+        "Rejected events, by delete request type and rule"
+    );
+    metrics::describe_histogram!(
+        VALIDATION_DURATION,
+        metrics::Unit::Seconds,
+        "Time spent validating an event"
+    );
+    metrics::describe_counter!(VALIDATION_TIMEOUTS, "Validation attempts that timed out");
+    metrics::describe_gauge!(
+        WORKER_QUEUE_DEPTH,
+        "Items waiting in the queue of a worker pool"
+    );
+    metrics::describe_gauge!(
+        WORKER_CONCURRENCY_LIMIT,
+        "Workers of a pool allowed to run at once"
+    );
+    metrics::describe_counter!(WORKER_RETRIES, "Failed worker attempts tried again");
+    metrics::describe_counter!(
+        WORKER_POISONED_ITEMS,
+        "Items the workers failed on for good"
+    );
+    metrics::describe_histogram!(
+        STRFRY_DELETE_DURATION,
+        metrics::Unit::Seconds,
+        "Duration of the strfry delete commands"
+    );
+    metrics::describe_counter!(STRFRY_DELETE_FAILURES, "Failed strfry delete commands");
+    metrics::describe_histogram!(
+        DELETION_RATE_LIMIT_WAIT,
+        metrics::Unit::Seconds,
+        "Time a delete command waited for the rate limiter, by deletion path"
+    );
+    metrics::describe_gauge!(
+        VANISH_STREAM_LAG,
+        metrics::Unit::Seconds,
+        "Age difference between the last acknowledged vanish request and the stream tail"
+    );
+    metrics::describe_histogram!(
+        VANISH_ACK_LATENCY,
+        metrics::Unit::Seconds,
+        "Time from reading a vanish request to acknowledging its deletion"
+    );
+    metrics::describe_counter!(
+        REDIS_CONNECTION_FAILURES,
+        "Redis failures the vanish subscriber backed off from"
+    );
+}
+
+/// Serves the metrics on `/metrics` in the Prometheus text format
+pub struct MetricsHandler {
+    handle: PrometheusHandle,
+}
+
+impl MetricsHandler {
+    pub fn new(handle: PrometheusHandle) -> Self {
+        MetricsHandler { handle }
+    }
+}
+
+#[async_trait]
+impl HttpHandler for MetricsHandler {
+    async fn handle(&self, path: &str) -> Option<HttpResponse> {
+        if path != "/metrics" {
+            return None;
+        }
+
+        Some(HttpResponse::ok(
+            "text/plain; version=0.0.4",
+            self.handle.render(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_renders_recorded_metrics() {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets(DURATION_BUCKETS)
+            .unwrap()
+            .build_recorder();
+        let handler = MetricsHandler::new(recorder.handle());
+
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!(EVENTS_VALIDATED, "result" => "rejected").increment(2);
+            metrics::histogram!(VALIDATION_DURATION).record(0.02);
+        });
+
+        let response = handler.handle("/metrics").await.unwrap();
+        assert_eq!(response.status, 200);
+        assert!(response
+            .body
+            .contains("events_validated_total{result=\"rejected\"} 2"));
+        assert!(response
+            .body
+            .contains("validation_duration_seconds_bucket{le=\"0.05\"} 1"));
+
+        assert!(handler.handle("/healthz").await.is_none());
+    }
+}
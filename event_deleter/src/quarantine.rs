@@ -0,0 +1,417 @@
+use crate::event_analyzer::DeleteRequest;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::{info, warn};
+
+static QUARANTINE_FILE_EXTENSION: &str = ".jsonl.gz";
+
+/// A deleted event kept around in case the deletion has to be undone
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuarantinedEvent {
+    pub quarantined_at: Timestamp,
+    pub rule_id: String,
+    pub request_type: String,
+    pub event: Event,
+}
+
+impl QuarantinedEvent {
+    pub fn new(delete_request: &DeleteRequest, event: Event) -> Self {
+        QuarantinedEvent {
+            quarantined_at: Timestamp::now(),
+            rule_id: delete_request
+                .rule_id()
+                .unwrap_or(delete_request.request_type())
+                .to_string(),
+            request_type: delete_request.request_type().to_string(),
+            event,
+        }
+    }
+}
+
+/// Selects the quarantined events to restore, unset fields match anything
+#[derive(Debug, Clone, Default)]
+pub struct RestoreQuery {
+    pub pubkey: Option<PublicKey>,
+    pub rule_id: Option<String>,
+    /// Quarantined at or after
+    pub since: Option<Timestamp>,
+    /// Quarantined at or before
+    pub until: Option<Timestamp>,
+}
+
+impl RestoreQuery {
+    pub fn matches(&self, quarantined: &QuarantinedEvent) -> bool {
+        if let Some(pubkey) = &self.pubkey {
+            if quarantined.event.pubkey != *pubkey {
+                return false;
+            }
+        }
+
+        if let Some(rule_id) = &self.rule_id {
+            if quarantined.rule_id != *rule_id {
+                return false;
+            }
+        }
+
+        if let Some(since) = self.since {
+            if quarantined.quarantined_at < since {
+                return false;
+            }
+        }
+
+        if let Some(until) = self.until {
+            if quarantined.quarantined_at > until {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// A directory of gzipped JSONL files, one per deleted batch. Files older
+/// than the retention period are removed each time a new one is written.
+pub struct QuarantineStore {
+    dir: PathBuf,
+    retention: Duration,
+}
+
+impl QuarantineStore {
+    pub fn new(dir: &Path, retention_days: u64) -> Result<Self, QuarantineError> {
+        fs::create_dir_all(dir)?;
+
+        Ok(QuarantineStore {
+            dir: dir.to_path_buf(),
+            retention: Duration::from_secs(retention_days.saturating_mul(24 * 60 * 60)),
+        })
+    }
+
+    pub async fn archive(&self, entries: Vec<QuarantinedEvent>) -> Result<(), QuarantineError> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let dir = self.dir.clone();
+        let retention = self.retention;
+        tokio::task::spawn_blocking(move || {
+            let path = write_archive(&dir, &entries)?;
+            info!("Quarantined {} events in {}", entries.len(), path.display());
+
+            let pruned = prune(&dir, retention)?;
+            if pruned > 0 {
+                info!("Removed {} expired quarantine files", pruned);
+            }
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Matching events across all the archives, each event once
+    pub async fn find(
+        &self,
+        query: RestoreQuery,
+    ) -> Result<Vec<QuarantinedEvent>, QuarantineError> {
+        let dir = self.dir.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut seen = HashSet::new();
+            let mut matches = Vec::new();
+
+            for path in archive_paths(&dir)? {
+                let reader = BufReader::new(GzDecoder::new(File::open(&path)?));
+                for line in reader.lines() {
+                    let quarantined: QuarantinedEvent = serde_json::from_str(&line?)?;
+                    if query.matches(&quarantined) && seen.insert(quarantined.event.id) {
+                        matches.push(quarantined);
+                    }
+                }
+            }
+
+            Ok(matches)
+        })
+        .await?
+    }
+}
+
+fn write_archive(dir: &Path, entries: &[QuarantinedEvent]) -> Result<PathBuf, QuarantineError> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    // Names sort by creation time, and create_new keeps two batches in the
+    // same nanosecond from overwriting each other
+    let mut suffix = 0;
+    let (path, file) = loop {
+        let path = dir.join(format!("{}-{}{}", nanos, suffix, QUARANTINE_FILE_EXTENSION));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => break (path, file),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => suffix += 1,
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    for entry in entries {
+        serde_json::to_writer(&mut encoder, entry)?;
+        encoder.write_all(b"\n")?;
+    }
+    encoder.finish()?.sync_all()?;
+
+    Ok(path)
+}
+
+fn prune(dir: &Path, retention: Duration) -> Result<usize, QuarantineError> {
+    let mut pruned = 0;
+    for path in archive_paths(dir)? {
+        let age = fs::metadata(&path)?
+            .modified()?
+            .elapsed()
+            .unwrap_or_default();
+
+        if age >= retention {
+            match fs::remove_file(&path) {
+                Ok(()) => pruned += 1,
+                Err(e) => warn!("Failed to remove {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    Ok(pruned)
+}
+
+// Oldest first
+fn archive_paths(dir: &Path) -> Result<Vec<PathBuf>, QuarantineError> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.ends_with(QUARANTINE_FILE_EXTENSION))
+        })
+        .collect();
+    paths.sort();
+
+    Ok(paths)
+}
+
+/// Quarantine options for the binaries deleting with rules
+#[derive(clap::Args, Debug, Clone)]
+pub struct QuarantineArgs {
+    /// Archives events in this directory before a rule deletes them, so they
+    /// can be restored with `relay_admin restore`. Vanish requests are never
+    /// archived
+    #[arg(long)]
+    pub quarantine_dir: Option<PathBuf>,
+
+    /// Days archived events are kept
+    #[arg(long, default_value_t = 30)]
+    pub quarantine_retention_days: u64,
+}
+
+impl QuarantineArgs {
+    pub fn store(&self) -> Result<Option<QuarantineStore>, QuarantineError> {
+        self.quarantine_dir
+            .as_deref()
+            .map(|dir| QuarantineStore::new(dir, self.quarantine_retention_days))
+            .transpose()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum QuarantineError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[error("Quarantine task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay_commander::{RawCommanderTrait, RelayCommander};
+    use async_trait::async_trait;
+
+    // Serves the stored events to scans and ignores deletes
+    struct StoredEvents(Vec<Event>);
+
+    #[async_trait]
+    impl RawCommanderTrait for StoredEvents {
+        async fn delete_from_filter(
+            &self,
+            _filter: Filter,
+            _dry_run: bool,
+        ) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+            Ok(None)
+        }
+
+        async fn scan_filter(
+            &self,
+            filter: Filter,
+        ) -> Result<Vec<Event>, Box<dyn std::error::Error>> {
+            Ok(self
+                .0
+                .iter()
+                .filter(|event| filter.match_event(event))
+                .cloned()
+                .collect())
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "quarantine-{}",
+            Keys::generate().public_key().to_hex()
+        ))
+    }
+
+    fn note(keys: &Keys) -> Event {
+        EventBuilder::text_note("hello", []).to_event(keys).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_archive_and_find() {
+        let dir = temp_dir();
+        let store = QuarantineStore::new(&dir, 30).unwrap();
+        let alice = Keys::generate();
+        let bob = Keys::generate();
+
+        let alice_note = note(&alice);
+        let bob_note = note(&bob);
+        store
+            .archive(vec![
+                QuarantinedEvent::new(&DeleteRequest::TagSpam(alice_note.id), alice_note.clone()),
+                QuarantinedEvent::new(&DeleteRequest::SpamAuthor(bob.public_key()), bob_note),
+            ])
+            .await
+            .unwrap();
+        // Deleted again by another batch
+        store
+            .archive(vec![QuarantinedEvent::new(
+                &DeleteRequest::TagSpam(alice_note.id),
+                alice_note.clone(),
+            )])
+            .await
+            .unwrap();
+
+        let by_pubkey = store
+            .find(RestoreQuery {
+                pubkey: Some(alice.public_key()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(by_pubkey.len(), 1);
+        assert_eq!(by_pubkey[0].event, alice_note);
+        assert_eq!(by_pubkey[0].rule_id, "tag_limits");
+
+        let by_rule = store
+            .find(RestoreQuery {
+                rule_id: Some("classifier".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(by_rule.len(), 1);
+        assert_eq!(by_rule[0].event.pubkey, bob.public_key());
+
+        let future = store
+            .find(RestoreQuery {
+                since: Some(Timestamp::now() + 3600),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(future.is_empty());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_vanish_requests_are_not_quarantined() {
+        let dir = temp_dir();
+        let spammer = Keys::generate();
+        let vanished = Keys::generate();
+        let spam = note(&spammer);
+        let vanished_note = note(&vanished);
+
+        let relay_commander = RelayCommander::new(StoredEvents(vec![
+            spam.clone(),
+            vanished_note.clone(),
+            note(&Keys::generate()),
+        ]))
+        .with_quarantine(QuarantineStore::new(&dir, 30).unwrap());
+
+        relay_commander
+            .execute_delete(
+                vec![
+                    DeleteRequest::ForbiddenName(spammer.public_key()),
+                    // Also flagged by a rule, vanish still wins
+                    DeleteRequest::SpamAuthor(vanished.public_key()),
+                    DeleteRequest::Vanish("1-0".to_string(), vanished.public_key(), None),
+                ],
+                false,
+            )
+            .await
+            .unwrap();
+
+        let store = QuarantineStore::new(&dir, 30).unwrap();
+        let quarantined = store.find(RestoreQuery::default()).await.unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].event, spam);
+        assert_eq!(quarantined[0].rule_id, "forbidden_name");
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_dry_runs_are_not_quarantined() {
+        let dir = temp_dir();
+        let spammer = Keys::generate();
+
+        let relay_commander = RelayCommander::new(StoredEvents(vec![note(&spammer)]))
+            .with_quarantine(QuarantineStore::new(&dir, 30).unwrap());
+        relay_commander
+            .execute_delete(vec![DeleteRequest::SpamAuthor(spammer.public_key())], true)
+            .await
+            .unwrap();
+
+        let store = QuarantineStore::new(&dir, 30).unwrap();
+        assert!(store
+            .find(RestoreQuery::default())
+            .await
+            .unwrap()
+            .is_empty());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_prune_expired_archives() {
+        let dir = temp_dir();
+        let store = QuarantineStore::new(&dir, 30).unwrap();
+        let entry = QuarantinedEvent::new(
+            &DeleteRequest::TagSpam(EventId::all_zeros()),
+            note(&Keys::generate()),
+        );
+        write_archive(&store.dir, &[entry]).unwrap();
+
+        assert_eq!(prune(&store.dir, store.retention).unwrap(), 0);
+        assert_eq!(prune(&store.dir, Duration::ZERO).unwrap(), 1);
+        assert!(archive_paths(&store.dir).unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,313 @@
+use crate::metrics::REDIS_CONNECTION_FAILURES;
+use crate::vanish_subscriber_task::RedisClientTrait;
+use nonzero_ext::nonzero;
+use serde::Serialize;
+use std::fmt::Display;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+/// Where the Redis connection of the vanish subscriber stands
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum RedisStatus {
+    Connecting,
+    Connected,
+    /// Backing off after failures in a row
+    Reconnecting {
+        failures: u32,
+        error: String,
+    },
+    /// The failure budget ran out, the process is shutting down
+    GaveUp {
+        error: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SupervisorPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// How long the first connection is waited for
+    pub startup_timeout: Duration,
+    /// Failures in a row before giving up
+    pub max_failures: NonZeroU32,
+}
+
+impl Default for SupervisorPolicy {
+    fn default() -> Self {
+        SupervisorPolicy {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            startup_timeout: Duration::from_secs(60),
+            max_failures: nonzero!(10u32),
+        }
+    }
+}
+
+impl SupervisorPolicy {
+    // Doubles from the initial backoff, with equal jitter so restarted
+    // subscribers don't retry in lockstep
+    fn backoff(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(16);
+        let delay = self
+            .initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+
+        let half = delay / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// Backs off between Redis failures and gives up, cancelling the token the
+/// process shuts down with, once `max_failures` happen in a row. Clones share
+/// the same state, so health checks can read it.
+///
+/// Connections are `ConnectionManager`s, which reconnect on their own after a
+/// failed command, so only the retries are paced here.
+#[derive(Clone)]
+pub struct RedisSupervisor {
+    policy: SupervisorPolicy,
+    inner: Arc<Mutex<SupervisorState>>,
+    cancellation_token: CancellationToken,
+}
+
+struct SupervisorState {
+    status: RedisStatus,
+    failures: u32,
+}
+
+impl RedisSupervisor {
+    pub fn new(policy: SupervisorPolicy, cancellation_token: CancellationToken) -> Self {
+        RedisSupervisor {
+            policy,
+            inner: Arc::new(Mutex::new(SupervisorState {
+                status: RedisStatus::Connecting,
+                failures: 0,
+            })),
+            cancellation_token,
+        }
+    }
+
+    pub fn status(&self) -> RedisStatus {
+        self.inner.lock().unwrap().status.clone()
+    }
+
+    pub fn gave_up(&self) -> bool {
+        matches!(self.status(), RedisStatus::GaveUp { .. })
+    }
+
+    /// Connects, retrying with backoff for up to the startup timeout
+    pub async fn connect<T: RedisClientTrait>(
+        &self,
+        redis_client: &T,
+    ) -> Result<T::Connection, SupervisorError> {
+        let connect = async {
+            loop {
+                match redis_client.get_connection().await {
+                    Ok(con) => {
+                        self.record_success();
+                        return Ok(con);
+                    }
+                    Err(e) => self.backoff(&e).await?,
+                }
+            }
+        };
+
+        match time::timeout(self.policy.startup_timeout, connect).await {
+            Ok(result) => result,
+            Err(_) => {
+                let error = SupervisorError::StartupTimeout(self.policy.startup_timeout);
+                self.give_up(error.to_string());
+                Err(error)
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.failures > 0 {
+            info!("Redis is back after {} failures", inner.failures);
+        }
+        inner.failures = 0;
+        inner.status = RedisStatus::Connected;
+    }
+
+    /// Waits before the next attempt, or fails once the budget ran out or
+    /// the process is shutting down
+    pub async fn backoff(&self, error: &impl Display) -> Result<(), SupervisorError> {
+        let delay = self.record_failure(error)?;
+
+        tokio::select! {
+            _ = self.cancellation_token.cancelled() => Err(SupervisorError::Cancelled),
+            _ = time::sleep(delay) => Ok(()),
+        }
+    }
+
+    /// Same as `backoff` without the wait, returns how long the caller
+    /// should wait before its next attempt
+    pub fn record_failure(&self, error: &impl Display) -> Result<Duration, SupervisorError> {
+        metrics::counter!(REDIS_CONNECTION_FAILURES).increment(1);
+
+        let failures = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.failures += 1;
+            inner.failures
+        };
+        if failures >= self.policy.max_failures.get() {
+            self.give_up(error.to_string());
+            return Err(SupervisorError::GaveUp(failures));
+        }
+
+        let delay = self.policy.backoff(failures);
+        warn!(
+            "Redis failure {} of {}, retrying in {:?}: {}",
+            failures, self.policy.max_failures, delay, error
+        );
+        self.inner.lock().unwrap().status = RedisStatus::Reconnecting {
+            failures,
+            error: error.to_string(),
+        };
+
+        Ok(delay)
+    }
+
+    fn give_up(&self, error: String) {
+        error!("Giving up on Redis: {}", error);
+        self.inner.lock().unwrap().status = RedisStatus::GaveUp { error };
+        self.cancellation_token.cancel();
+    }
+}
+
+/// Redis retry options of the vanish subscriber
+#[derive(clap::Args, Debug, Clone)]
+pub struct RedisSupervisorArgs {
+    /// Wait (in milliseconds) after the first Redis failure, doubled after
+    /// each one in a row
+    #[arg(long, default_value_t = 500)]
+    pub redis_initial_backoff_millis: u64,
+
+    /// Longest wait (in milliseconds) between Redis retries
+    #[arg(long, default_value_t = 30_000)]
+    pub redis_max_backoff_millis: u64,
+
+    /// Seconds to wait for Redis on startup before exiting
+    #[arg(long, default_value_t = 60)]
+    pub redis_startup_timeout_secs: u64,
+
+    /// Redis failures in a row before exiting
+    #[arg(long, default_value_t = nonzero!(10u32))]
+    pub redis_max_failures: NonZeroU32,
+}
+
+impl RedisSupervisorArgs {
+    pub fn policy(&self) -> SupervisorPolicy {
+        SupervisorPolicy {
+            initial_backoff: Duration::from_millis(self.redis_initial_backoff_millis),
+            max_backoff: Duration::from_millis(self.redis_max_backoff_millis),
+            startup_timeout: Duration::from_secs(self.redis_startup_timeout_secs),
+            max_failures: self.redis_max_failures,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SupervisorError {
+    #[error("Redis wasn't reachable within {0:?}")]
+    StartupTimeout(Duration),
+
+    #[error("Gave up on Redis after {0} failures in a row")]
+    GaveUp(u32),
+
+    #[error("Shutting down")]
+    Cancelled,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vanish_subscriber_task::RedisClientConnection;
+    use async_trait::async_trait;
+    use redis::{ErrorKind, RedisError};
+
+    struct UnreachableRedis;
+
+    #[async_trait]
+    impl RedisClientTrait for UnreachableRedis {
+        type Connection = RedisClientConnection;
+        async fn get_connection(&self) -> Result<Self::Connection, RedisError> {
+            Err(RedisError::from((ErrorKind::IoError, "Connection refused")))
+        }
+    }
+
+    #[test]
+    fn test_backoff_is_jittered_and_capped() {
+        let policy = SupervisorPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(8),
+            ..Default::default()
+        };
+
+        for (failures, expected) in [(1, 1), (2, 2), (3, 4), (4, 8), (5, 8), (100, 8)] {
+            let delay = policy.backoff(failures);
+            let expected = Duration::from_secs(expected);
+            assert!(delay >= expected / 2 && delay <= expected, "{:?}", delay);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_gives_up_when_the_budget_runs_out() {
+        let token = CancellationToken::new();
+        let supervisor = RedisSupervisor::new(
+            SupervisorPolicy {
+                max_failures: nonzero!(3u32),
+                ..Default::default()
+            },
+            token.clone(),
+        );
+
+        assert!(supervisor.backoff(&"down").await.is_ok());
+        assert!(matches!(
+            supervisor.status(),
+            RedisStatus::Reconnecting { failures: 1, .. }
+        ));
+
+        // A success resets the budget
+        supervisor.record_success();
+        assert_eq!(supervisor.status(), RedisStatus::Connected);
+        assert!(supervisor.backoff(&"down").await.is_ok());
+        assert!(supervisor.backoff(&"down").await.is_ok());
+        assert!(!token.is_cancelled());
+
+        assert!(matches!(
+            supervisor.backoff(&"down").await,
+            Err(SupervisorError::GaveUp(3))
+        ));
+        assert!(supervisor.gave_up());
+        assert!(token.is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_startup_wait_is_bounded() {
+        let token = CancellationToken::new();
+        let supervisor = RedisSupervisor::new(
+            SupervisorPolicy {
+                startup_timeout: Duration::from_secs(5),
+                max_failures: nonzero!(1000u32),
+                ..Default::default()
+            },
+            token.clone(),
+        );
+
+        assert!(matches!(
+            supervisor.connect(&UnreachableRedis).await,
+            Err(SupervisorError::StartupTimeout(_))
+        ));
+        assert!(supervisor.gave_up());
+        assert!(token.is_cancelled());
+    }
+}
@@ -1,109 +1,500 @@
+pub mod chunked_delete;
+pub mod rate_limit;
+
+use crate::audit_log::{AuditLog, AuditRecord, DeleteCommand};
 use crate::event_analyzer::DeleteRequest;
+use crate::exemptions::Exemptions;
+use crate::metrics::{STRFRY_DELETE_DURATION, STRFRY_DELETE_FAILURES};
+use crate::quarantine::{QuarantineStore, QuarantinedEvent};
+use crate::settings::{Settings, DEFAULT_STRFRY_PATH};
 use async_trait::async_trait;
+use chunked_delete::CheckpointStore;
 use nostr_sdk::prelude::*;
-use std::collections::HashSet;
+use rate_limit::{DeletionPath, DeletionRateLimiter};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::num::NonZeroUsize;
+use std::process::Stdio;
+use std::sync::{Arc, LazyLock};
+use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
+use tokio::time::Instant;
+use tracing::info;
+
+// strfry logs "Deleting N events", or "Would delete N events" in a dry run
+static DELETED_COUNT_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)delet\w* (\d+) events").unwrap());
 
 #[derive(Clone)]
 pub struct RelayCommander<T: RawCommanderTrait> {
     raw_commander: T,
+    audit_log: AuditLog,
+    quarantine: Option<Arc<QuarantineStore>>,
+    exemptions: Exemptions,
+    rate_limiter: Option<DeletionRateLimiter>,
+    max_events_per_command: Option<NonZeroUsize>,
+    checkpoints: Option<Arc<dyn CheckpointStore>>,
 }
 
 impl<T: RawCommanderTrait> RelayCommander<T> {
     pub fn new(raw_commander: T) -> Self {
-        RelayCommander { raw_commander }
+        RelayCommander {
+            raw_commander,
+            audit_log: AuditLog::default(),
+            quarantine: None,
+            exemptions: Exemptions::default(),
+            rate_limiter: None,
+            max_events_per_command: None,
+            checkpoints: None,
+        }
+    }
+
+    /// Records every executed delete in the audit log
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    /// Archives the events deleted by rules before deleting them
+    pub fn with_quarantine(mut self, quarantine: QuarantineStore) -> Self {
+        self.quarantine = Some(Arc::new(quarantine));
+        self
+    }
+
+    /// Exempt authors are left out of author deletions, except for vanish
+    /// requests
+    pub fn with_exemptions(mut self, exemptions: Exemptions) -> Self {
+        self.exemptions = exemptions;
+        self
+    }
+
+    /// Throttles the delete commands, vanish requests before spam
+    pub fn with_rate_limiter(mut self, rate_limiter: DeletionRateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Counts the events of each author before deleting, and splits author
+    /// deletions so no command deletes more than this
+    pub fn with_max_events_per_command(mut self, max_events: NonZeroUsize) -> Self {
+        self.max_events_per_command = Some(max_events);
+        self
+    }
+
+    /// Saves the progress of paged vanish deletions, an interrupted one
+    /// resumes where it stopped
+    pub fn with_checkpoints(mut self, checkpoints: Arc<dyn CheckpointStore>) -> Self {
+        self.checkpoints = Some(checkpoints);
+        self
+    }
+
+    /// Imports events back into strfry, to undo deletions
+    pub async fn restore(&self, events: Vec<Event>) -> Result<(), Box<dyn Error>> {
+        self.raw_commander.import_events(events).await
+    }
+}
+
+impl RelayCommander<RawCommander> {
+    /// Runs the strfry binary of the settings
+    pub fn from_settings(settings: &Settings) -> Self {
+        RelayCommander::new(RawCommander::new(&settings.strfry_path))
     }
 }
 
 impl Default for RelayCommander<RawCommander> {
     fn default() -> Self {
-        let raw_commander = RawCommander {};
-        RelayCommander::new(raw_commander)
+        RelayCommander::new(RawCommander::default())
     }
 }
 
+// Which part of a request a delete command covers
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CommandScope {
+    Ids,
+    Authors,
+    GiftWraps,
+}
+
 impl<T: RawCommanderTrait> RelayCommander<T> {
     pub async fn execute_delete(
         &self,
         delete_reason: Vec<DeleteRequest>,
         dry_run: bool,
     ) -> Result<(), Box<dyn Error>> {
-        let mut ids = HashSet::new();
-        let mut authors = HashSet::new();
-        let mut gift_wrap_pubkeys = HashSet::new();
-
+        // Exemptions may have been added since the request was validated.
+        // Requests whose authors can't be checked are left out and the batch
+        // fails once the rest is deleted
+        let mut checked = Vec::with_capacity(delete_reason.len());
+        let mut exemption_error = None;
         for reason in delete_reason {
-            match reason {
-                DeleteRequest::ReplyCopy(id) => {
-                    ids.insert(id);
+            match self.exemptions.apply(reason).await {
+                Ok(Some(reason)) => checked.push(reason),
+                Ok(None) => {}
+                Err(e) => exemption_error = Some(e),
+            }
+        }
+        let delete_reason = checked;
+
+        // Nothing is deleted in a dry run, so there's nothing to restore later
+        if let Some(quarantine) = self.quarantine.as_ref().filter(|_| !dry_run) {
+            self.quarantine(quarantine, &delete_reason).await?;
+        }
+
+        // Vanish commands run first, each path has its own rate limits
+        let (vanish, spam): (Vec<&DeleteRequest>, Vec<&DeleteRequest>) = delete_reason
+            .iter()
+            .partition(|reason| DeletionPath::of(reason) == DeletionPath::Vanish);
+        let mut filters = delete_filters(DeletionPath::Vanish, &vanish);
+        filters.extend(delete_filters(DeletionPath::Spam, &spam));
+
+        // Commands that already ran are audited even if a later one fails
+        let mut executed = Vec::new();
+        let mut failure = None;
+        for (path, scope, filter) in filters {
+            let mut commands = Vec::new();
+            let result = match (scope, self.max_events_per_command, &filter.authors) {
+                (CommandScope::Authors, Some(max_events), Some(authors)) => {
+                    let authors = authors.iter().copied().collect();
+                    self.delete_authors_in_chunks(path, authors, max_events, dry_run, &mut commands)
+                        .await
                 }
-                DeleteRequest::ForbiddenName(pubkey) => {
-                    authors.insert(pubkey);
+                _ => self.run_command(path, filter, dry_run, &mut commands).await,
+            };
+
+            executed.extend(commands.into_iter().map(|command| (path, scope, command)));
+            if let Err(e) = result {
+                failure = Some(e);
+                break;
+            }
+        }
+
+        if !self.audit_log.is_empty() && !executed.is_empty() {
+            let records: Vec<AuditRecord> = delete_reason
+                .iter()
+                .map(|reason| {
+                    let commands = executed
+                        .iter()
+                        .filter(|(path, scope, command)| covers(*path, *scope, command, reason))
+                        .map(|(_, _, command)| command.clone())
+                        .collect();
+                    AuditRecord::new(reason, commands, delete_reason.len(), dry_run)
+                })
+                .filter(|record| !record.commands.is_empty())
+                .collect();
+
+            self.audit_log.append(&records).await;
+        }
+
+        match (failure, exemption_error) {
+            (Some(e), _) => Err(e.into()),
+            (None, Some(e)) => Err(format!("Failed to check exemptions: {}", e).into()),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl<T: RawCommanderTrait> RelayCommander<T> {
+    // Runs a single delete command within the rate limits, adding it to
+    // `commands` if it succeeds
+    async fn run_command(
+        &self,
+        path: DeletionPath,
+        filter: Filter,
+        dry_run: bool,
+        commands: &mut Vec<DeleteCommand>,
+    ) -> Result<(), String> {
+        let turn = match &self.rate_limiter {
+            Some(rate_limiter) => Some(rate_limiter.acquire(path).await),
+            None => None,
+        };
+
+        let started = Instant::now();
+        let result = self
+            .raw_commander
+            .delete_from_filter(filter.clone(), dry_run)
+            .await
+            .map_err(|e| e.to_string());
+        metrics::histogram!(STRFRY_DELETE_DURATION).record(started.elapsed().as_secs_f64());
+
+        if let (Some(rate_limiter), Some(turn)) = (&self.rate_limiter, turn) {
+            rate_limiter.finish(turn).await;
+        }
+
+        match result {
+            Ok(deleted) => {
+                if let Some(rate_limiter) = &self.rate_limiter {
+                    rate_limiter.record_deleted(path, deleted.unwrap_or_else(|| estimate(&filter)));
                 }
-                DeleteRequest::Vanish(_, pubkey, _) => {
-                    authors.insert(pubkey);
-                    gift_wrap_pubkeys.insert(pubkey);
+                commands.push(DeleteCommand { filter, deleted });
+                Ok(())
+            }
+            Err(e) => {
+                metrics::counter!(STRFRY_DELETE_FAILURES).increment(1);
+                Err(e)
+            }
+        }
+    }
+
+    // Vanish requests are left out, NIP-62 asks for the events to be gone for
+    // good. If archiving fails nothing is deleted.
+    async fn quarantine(
+        &self,
+        quarantine: &QuarantineStore,
+        delete_reason: &[DeleteRequest],
+    ) -> Result<(), Box<dyn Error>> {
+        let vanished: HashSet<PublicKey> = delete_reason
+            .iter()
+            .filter(|reason| matches!(reason, DeleteRequest::Vanish(_, _, _)))
+            .flat_map(|reason| reason.authors())
+            .collect();
+
+        let mut by_id = HashMap::new();
+        let mut by_author = HashMap::new();
+        for reason in delete_reason {
+            if matches!(reason, DeleteRequest::Vanish(_, _, _)) {
+                continue;
+            }
+
+            for id in reason.event_ids() {
+                by_id.entry(id).or_insert(reason);
+            }
+
+            for author in reason.authors() {
+                if !vanished.contains(&author) {
+                    by_author.entry(author).or_insert(reason);
                 }
             }
         }
 
-        if !ids.is_empty() {
-            let ids_filter = Filter::new().ids(ids);
-            self.raw_commander
-                .delete_from_filter(ids_filter, dry_run)
-                .await?;
+        let mut filters = Vec::new();
+        if !by_id.is_empty() {
+            filters.push(Filter::new().ids(by_id.keys().copied()));
         }
 
-        if !authors.is_empty() {
-            let authors_filter = Filter::new().authors(authors.clone());
-            self.raw_commander
-                .delete_from_filter(authors_filter, dry_run)
-                .await?;
+        if !by_author.is_empty() {
+            filters.push(Filter::new().authors(by_author.keys().copied()));
         }
 
-        if !gift_wrap_pubkeys.is_empty() {
-            let gift_wrap_filter = Filter::new()
-                .kind(Kind::GiftWrap)
-                .pubkeys(gift_wrap_pubkeys);
-            self.raw_commander
-                .delete_from_filter(gift_wrap_filter, dry_run)
-                .await?;
+        let mut entries = Vec::new();
+        let mut archived = HashSet::new();
+        for filter in filters {
+            for event in self.raw_commander.scan_filter(filter).await? {
+                if vanished.contains(&event.pubkey) || !archived.insert(event.id) {
+                    continue;
+                }
+
+                let reason = by_id.get(&event.id).or(by_author.get(&event.pubkey));
+                if let Some(reason) = reason {
+                    entries.push(QuarantinedEvent::new(reason, event));
+                }
+            }
         }
 
+        quarantine.archive(entries).await?;
         Ok(())
     }
 }
 
-#[derive(Default)]
-pub struct RawCommander {}
+fn delete_filters(
+    path: DeletionPath,
+    delete_reason: &[&DeleteRequest],
+) -> Vec<(DeletionPath, CommandScope, Filter)> {
+    let mut ids = HashSet::new();
+    let mut authors = HashSet::new();
+    let mut gift_wrap_pubkeys = HashSet::new();
+
+    for reason in delete_reason {
+        ids.extend(reason.event_ids());
+        authors.extend(reason.authors());
+
+        if let DeleteRequest::Vanish(_, pubkey, _) = reason {
+            gift_wrap_pubkeys.insert(*pubkey);
+        }
+    }
+
+    let mut filters = Vec::new();
+    if !ids.is_empty() {
+        filters.push((path, CommandScope::Ids, Filter::new().ids(ids)));
+    }
+
+    if !authors.is_empty() {
+        filters.push((path, CommandScope::Authors, Filter::new().authors(authors)));
+    }
+
+    if !gift_wrap_pubkeys.is_empty() {
+        let gift_wrap_filter = Filter::new()
+            .kind(Kind::GiftWrap)
+            .pubkeys(gift_wrap_pubkeys);
+        filters.push((path, CommandScope::GiftWraps, gift_wrap_filter));
+    }
+
+    filters
+}
+
+// Events a command deleted when strfry doesn't say, at least one per id or
+// author
+fn estimate(filter: &Filter) -> usize {
+    let ids = filter.ids.as_ref().map_or(0, |ids| ids.len());
+    let authors = filter.authors.as_ref().map_or(0, |authors| authors.len());
+    ids + authors
+}
+
+// Author deletions can be split in chunks, a request is only covered by the
+// ones with its authors
+fn covers(
+    path: DeletionPath,
+    scope: CommandScope,
+    command: &DeleteCommand,
+    delete_request: &DeleteRequest,
+) -> bool {
+    if DeletionPath::of(delete_request) != path {
+        return false;
+    }
+
+    match scope {
+        CommandScope::Ids => !delete_request.event_ids().is_empty(),
+        CommandScope::Authors => match &command.filter.authors {
+            Some(authors) => delete_request
+                .authors()
+                .iter()
+                .any(|author| authors.contains(author)),
+            None => false,
+        },
+        CommandScope::GiftWraps => matches!(delete_request, DeleteRequest::Vanish(_, _, _)),
+    }
+}
+
+pub struct RawCommander {
+    strfry_path: String,
+}
+
+impl RawCommander {
+    pub fn new(strfry_path: &str) -> Self {
+        RawCommander {
+            strfry_path: strfry_path.to_string(),
+        }
+    }
+}
+
+impl Default for RawCommander {
+    fn default() -> Self {
+        RawCommander::new(DEFAULT_STRFRY_PATH)
+    }
+}
 
 #[async_trait]
-impl RawCommanderTrait for RawCommander {}
+impl RawCommanderTrait for RawCommander {
+    fn strfry_path(&self) -> &str {
+        &self.strfry_path
+    }
+}
+
 #[async_trait]
 pub trait RawCommanderTrait: Sync + Send + 'static {
+    /// strfry binary the commands run
+    fn strfry_path(&self) -> &str {
+        DEFAULT_STRFRY_PATH
+    }
+
+    /// Returns how many events strfry deleted, or would have in a dry run,
+    /// when it says so
     async fn delete_from_filter(
         &self,
         filter: Filter,
         dry_run: bool,
-    ) -> std::result::Result<(), Box<dyn Error>> {
+    ) -> std::result::Result<Option<usize>, Box<dyn Error>> {
         let json_filter = filter.as_json();
         let command_str = format!(
-            "./strfry delete --filter='{}' {}",
+            "{} delete --filter='{}' {}",
+            self.strfry_path(),
             json_filter,
             if dry_run { "--dry-run" } else { "" }
         );
 
-        let status = Command::new("bash")
+        let output = Command::new("bash")
             .arg("-c")
             .arg(&command_str)
-            .status()
+            .output()
             .await?;
 
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        for line in stderr.lines() {
+            info!("strfry: {}", line);
+        }
+
+        if output.status.success() {
+            Ok(DELETED_COUNT_REGEX
+                .captures(&stderr)
+                .and_then(|captures| captures[1].parse().ok()))
+        } else {
+            Err(format!("Delete command failed with status: {}", output.status).into())
+        }
+    }
+
+    /// How many stored events match the filter
+    async fn count_filter(&self, filter: Filter) -> std::result::Result<usize, Box<dyn Error>> {
+        let command_str = format!("{} scan --count '{}'", self.strfry_path(), filter.as_json());
+
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(&command_str)
+            .stderr(Stdio::inherit())
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            return Err(format!("Count command failed with status: {}", output.status).into());
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().parse()?)
+    }
+
+    /// The stored events matching the filter
+    async fn scan_filter(&self, filter: Filter) -> std::result::Result<Vec<Event>, Box<dyn Error>> {
+        let command_str = format!("{} scan '{}'", self.strfry_path(), filter.as_json());
+
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(&command_str)
+            .stderr(Stdio::inherit())
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            return Err(format!("Scan command failed with status: {}", output.status).into());
+        }
+
+        let mut events = Vec::new();
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            events.push(Event::from_json(line)?);
+        }
+
+        Ok(events)
+    }
+
+    async fn import_events(&self, events: Vec<Event>) -> std::result::Result<(), Box<dyn Error>> {
+        let mut child = Command::new("bash")
+            .arg("-c")
+            .arg(format!("{} import", self.strfry_path()))
+            .stdin(Stdio::piped())
+            .spawn()?;
+
+        let mut jsonl = String::new();
+        for event in &events {
+            jsonl.push_str(&event.as_json());
+            jsonl.push('\n');
+        }
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(jsonl.as_bytes()).await?;
+        }
+
+        let status = child.wait().await?;
         if status.success() {
             Ok(())
         } else {
-            Err(format!("Delete command failed with status: {}", status).into())
+            Err(format!("Import command failed with status: {}", status).into())
         }
     }
 }
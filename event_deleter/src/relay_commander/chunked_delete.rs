@@ -0,0 +1,572 @@
+use super::rate_limit::DeletionPath;
+use super::{RawCommanderTrait, RelayCommander};
+use crate::audit_log::DeleteCommand;
+use async_trait::async_trait;
+use nostr_sdk::prelude::*;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroUsize;
+use thiserror::Error;
+use tracing::{info, warn};
+
+pub static DEFAULT_CHECKPOINT_KEY: &str = "vanish_checkpoints";
+
+/// How far the pages of an author went. Pages go backwards in time from
+/// `started_at`, the next one ends at `until`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub started_at: Timestamp,
+    pub until: Timestamp,
+}
+
+/// Where the progress of paged vanish deletions is kept, so a restarted
+/// subscriber resumes them
+#[async_trait]
+pub trait CheckpointStore: Send + Sync {
+    async fn load(&self, pubkey: &PublicKey) -> Result<Option<Checkpoint>, CheckpointError>;
+    async fn save(&self, pubkey: &PublicKey, checkpoint: Checkpoint)
+        -> Result<(), CheckpointError>;
+    async fn remove(&self, pubkey: &PublicKey) -> Result<(), CheckpointError>;
+}
+
+/// Checkpoints in a Redis hash, by pubkey
+pub struct RedisCheckpointStore {
+    con: ConnectionManager,
+    key: String,
+}
+
+impl RedisCheckpointStore {
+    pub async fn new(redis_url: &str, key: String) -> Result<Self, CheckpointError> {
+        let client = redis::Client::open(redis_url)?;
+        let con = client.get_connection_manager().await?;
+
+        Ok(RedisCheckpointStore { con, key })
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for RedisCheckpointStore {
+    async fn load(&self, pubkey: &PublicKey) -> Result<Option<Checkpoint>, CheckpointError> {
+        let mut con = self.con.clone();
+        let checkpoint: Option<String> = con.hget(&self.key, pubkey.to_hex()).await?;
+
+        Ok(checkpoint
+            .map(|checkpoint| serde_json::from_str(&checkpoint))
+            .transpose()?)
+    }
+
+    async fn save(
+        &self,
+        pubkey: &PublicKey,
+        checkpoint: Checkpoint,
+    ) -> Result<(), CheckpointError> {
+        let mut con = self.con.clone();
+        con.hset::<_, _, _, ()>(
+            &self.key,
+            pubkey.to_hex(),
+            serde_json::to_string(&checkpoint)?,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, pubkey: &PublicKey) -> Result<(), CheckpointError> {
+        let mut con = self.con.clone();
+        con.hdel::<_, _, ()>(&self.key, pubkey.to_hex()).await?;
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CheckpointError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+impl<T: RawCommanderTrait> RelayCommander<T> {
+    /// Deletes the events of the authors with commands of at most
+    /// `max_events` events each, as counted before deleting. Authors with
+    /// fewer events share commands, the others are deleted page by page.
+    pub(super) async fn delete_authors_in_chunks(
+        &self,
+        path: DeletionPath,
+        authors: Vec<PublicKey>,
+        max_events: NonZeroUsize,
+        dry_run: bool,
+        commands: &mut Vec<DeleteCommand>,
+    ) -> Result<(), String> {
+        let max_events = max_events.get();
+        let mut group = Vec::new();
+        let mut group_events = 0;
+
+        for author in authors {
+            let count = self
+                .raw_commander
+                .count_filter(Filter::new().author(author))
+                .await
+                .map_err(|e| e.to_string())?;
+
+            if count > max_events {
+                self.delete_author_pages(path, author, max_events, dry_run, commands)
+                    .await?;
+                continue;
+            }
+
+            if group_events + count > max_events {
+                let filter = Filter::new().authors(std::mem::take(&mut group));
+                self.run_command(path, filter, dry_run, commands).await?;
+                group_events = 0;
+            }
+            group.push(author);
+            group_events += count;
+        }
+
+        if !group.is_empty() {
+            self.run_command(path, Filter::new().authors(group), dry_run, commands)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // Each page is the `max_events` newest events before `until`, deleted
+    // down to the oldest of them. Vanish pages are checkpointed, dry runs
+    // neither resume nor leave checkpoints behind.
+    async fn delete_author_pages(
+        &self,
+        path: DeletionPath,
+        author: PublicKey,
+        max_events: usize,
+        dry_run: bool,
+        commands: &mut Vec<DeleteCommand>,
+    ) -> Result<(), String> {
+        let checkpoints = match path {
+            DeletionPath::Vanish if !dry_run => self.checkpoints.as_ref(),
+            _ => None,
+        };
+
+        let resumed = match checkpoints {
+            Some(checkpoints) => checkpoints.load(&author).await.map_err(|e| e.to_string())?,
+            None => None,
+        };
+        if let Some(checkpoint) = resumed {
+            info!(
+                "Resuming the deletion of {} from {}",
+                author, checkpoint.until
+            );
+        }
+        let now = Timestamp::now();
+        let mut checkpoint = resumed.unwrap_or(Checkpoint {
+            started_at: now,
+            until: now,
+        });
+
+        loop {
+            let page = Filter::new()
+                .author(author)
+                .until(checkpoint.until)
+                .limit(max_events);
+            let events = self
+                .raw_commander
+                .scan_filter(page)
+                .await
+                .map_err(|e| e.to_string())?;
+
+            let oldest = match events.iter().map(|event| event.created_at).min() {
+                Some(oldest) if events.len() >= max_events => oldest,
+                // The last page
+                _ => {
+                    let filter = Filter::new().author(author).until(checkpoint.until);
+                    self.run_command(path, filter, dry_run, commands).await?;
+                    break;
+                }
+            };
+
+            // A whole page in the same second can't be split by time
+            let next_until = if oldest >= checkpoint.until {
+                self.delete_second_by_ids(
+                    path,
+                    author,
+                    checkpoint.until,
+                    max_events,
+                    dry_run,
+                    commands,
+                )
+                .await?;
+                checkpoint.until.as_u64().checked_sub(1)
+            } else {
+                let filter = Filter::new()
+                    .author(author)
+                    .since(oldest + 1u64)
+                    .until(checkpoint.until);
+                self.run_command(path, filter, dry_run, commands).await?;
+                Some(oldest.as_u64())
+            };
+
+            let Some(next_until) = next_until else {
+                break;
+            };
+            checkpoint.until = Timestamp::from(next_until);
+            if let Some(checkpoints) = checkpoints {
+                checkpoints
+                    .save(&author, checkpoint)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+
+        // Events published while the pages were deleted
+        let filter = Filter::new()
+            .author(author)
+            .since(checkpoint.started_at + 1u64);
+        self.run_command(path, filter, dry_run, commands).await?;
+
+        if let Some(checkpoints) = checkpoints {
+            checkpoints
+                .remove(&author)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    // Deletes the events of one second `max_events` ids at a time, each scan
+    // finds the ones left. Dry runs delete nothing, so they count the second
+    // as a whole, and so does a scan returning ids that were already deleted.
+    async fn delete_second_by_ids(
+        &self,
+        path: DeletionPath,
+        author: PublicKey,
+        second: Timestamp,
+        max_events: usize,
+        dry_run: bool,
+        commands: &mut Vec<DeleteCommand>,
+    ) -> Result<(), String> {
+        let whole_second = Filter::new().author(author).since(second).until(second);
+        if dry_run {
+            warn!(
+                "More than {} events of {} at {}, counting them in one command",
+                max_events, author, second
+            );
+            return self
+                .run_command(path, whole_second, dry_run, commands)
+                .await;
+        }
+
+        let mut deleted_ids = Vec::new();
+        loop {
+            let events = self
+                .raw_commander
+                .scan_filter(whole_second.clone().limit(max_events))
+                .await
+                .map_err(|e| e.to_string())?;
+            let ids: Vec<EventId> = events.iter().map(|event| event.id).collect();
+
+            if ids.is_empty() {
+                return Ok(());
+            }
+            if ids == deleted_ids {
+                warn!(
+                    "Events of {} at {} weren't deleted by id, deleting the second in one command",
+                    author, second
+                );
+                return self
+                    .run_command(path, whole_second, dry_run, commands)
+                    .await;
+            }
+
+            let filter = Filter::new().author(author).ids(ids.clone());
+            self.run_command(path, filter, dry_run, commands).await?;
+            if ids.len() < max_events {
+                return Ok(());
+            }
+            deleted_ids = ids;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nonzero_ext::nonzero;
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::sync::{Arc, Mutex};
+
+    // Stored events of a single author, one per second. Deletes that aren't
+    // dry runs remove them
+    #[derive(Clone)]
+    struct StoredEvents {
+        events: Arc<Mutex<Vec<Event>>>,
+        deletes: Arc<Mutex<Vec<Filter>>>,
+    }
+
+    impl StoredEvents {
+        fn new(keys: &Keys, count: u64) -> Self {
+            StoredEvents::from_events(events(keys, 1..=count))
+        }
+
+        fn from_events(events: Vec<Event>) -> Self {
+            StoredEvents {
+                events: Arc::new(Mutex::new(events)),
+                deletes: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+
+        fn matching(&self, filter: &Filter) -> Vec<Event> {
+            let mut events: Vec<Event> = self
+                .events
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|event| filter.match_event(event))
+                .cloned()
+                .collect();
+            events.sort_by_key(|event| std::cmp::Reverse(event.created_at));
+            events.truncate(filter.limit.unwrap_or(usize::MAX));
+            events
+        }
+    }
+
+    #[async_trait]
+    impl RawCommanderTrait for StoredEvents {
+        async fn delete_from_filter(
+            &self,
+            filter: Filter,
+            dry_run: bool,
+        ) -> Result<Option<usize>, Box<dyn Error>> {
+            let deleted = self.matching(&filter).len();
+            if !dry_run {
+                self.events
+                    .lock()
+                    .unwrap()
+                    .retain(|event| !filter.match_event(event));
+            }
+            self.deletes.lock().unwrap().push(filter);
+            Ok(Some(deleted))
+        }
+
+        async fn scan_filter(&self, filter: Filter) -> Result<Vec<Event>, Box<dyn Error>> {
+            Ok(self.matching(&filter))
+        }
+
+        async fn count_filter(&self, filter: Filter) -> Result<usize, Box<dyn Error>> {
+            Ok(self.matching(&filter).len())
+        }
+    }
+
+    fn events(keys: &Keys, seconds: impl IntoIterator<Item = u64>) -> Vec<Event> {
+        seconds
+            .into_iter()
+            .enumerate()
+            .map(|(i, created_at)| {
+                EventBuilder::text_note(format!("spam {}", i), [])
+                    .custom_created_at(Timestamp::from(created_at))
+                    .to_event(keys)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[derive(Default)]
+    struct MemoryCheckpoints(Mutex<HashMap<PublicKey, Checkpoint>>);
+
+    #[async_trait]
+    impl CheckpointStore for MemoryCheckpoints {
+        async fn load(&self, pubkey: &PublicKey) -> Result<Option<Checkpoint>, CheckpointError> {
+            Ok(self.0.lock().unwrap().get(pubkey).copied())
+        }
+
+        async fn save(
+            &self,
+            pubkey: &PublicKey,
+            checkpoint: Checkpoint,
+        ) -> Result<(), CheckpointError> {
+            self.0.lock().unwrap().insert(*pubkey, checkpoint);
+            Ok(())
+        }
+
+        async fn remove(&self, pubkey: &PublicKey) -> Result<(), CheckpointError> {
+            self.0.lock().unwrap().remove(pubkey);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pages_and_resumes_large_authors() {
+        let keys = Keys::generate();
+        let stored = StoredEvents::new(&keys, 25);
+        let checkpoints = Arc::new(MemoryCheckpoints::default());
+        let relay_commander = RelayCommander::new(stored.clone())
+            .with_max_events_per_command(nonzero!(10usize))
+            .with_checkpoints(checkpoints.clone());
+
+        // Interrupted after the first page
+        let started_at = Timestamp::now();
+        checkpoints
+            .save(
+                &keys.public_key(),
+                Checkpoint {
+                    started_at,
+                    until: Timestamp::from(15),
+                },
+            )
+            .await
+            .unwrap();
+
+        let mut commands = Vec::new();
+        relay_commander
+            .delete_authors_in_chunks(
+                DeletionPath::Vanish,
+                vec![keys.public_key()],
+                nonzero!(10usize),
+                false,
+                &mut commands,
+            )
+            .await
+            .unwrap();
+
+        let deleted: Vec<Option<usize>> = commands.iter().map(|command| command.deleted).collect();
+        // 7..=15, 1..=6, then the events newer than the start
+        assert_eq!(deleted, vec![Some(9), Some(6), Some(0)]);
+
+        assert!(checkpoints
+            .load(&keys.public_key())
+            .await
+            .unwrap()
+            .is_none());
+
+        let deletes = stored.deletes.lock().unwrap();
+        assert_eq!(deletes[0].since, Some(Timestamp::from(7)));
+        assert_eq!(deletes[0].until, Some(Timestamp::from(15)));
+        assert_eq!(deletes[1].since, None);
+        assert_eq!(deletes[1].until, Some(Timestamp::from(6)));
+        assert_eq!(deletes[2].since, Some(started_at + 1u64));
+    }
+
+    #[tokio::test]
+    async fn test_splits_crowded_seconds_by_id() {
+        let keys = Keys::generate();
+        // 15 events in the 25th second
+        let stored = StoredEvents::from_events(events(&keys, (1..=25).chain([25; 14])));
+        let relay_commander = RelayCommander::new(stored.clone());
+
+        let mut commands = Vec::new();
+        relay_commander
+            .delete_authors_in_chunks(
+                DeletionPath::Vanish,
+                vec![keys.public_key()],
+                nonzero!(10usize),
+                false,
+                &mut commands,
+            )
+            .await
+            .unwrap();
+
+        let deleted: Vec<Option<usize>> = commands.iter().map(|command| command.deleted).collect();
+        // After 26.., the 25th second by id in two commands, then 16..=24,
+        // 7..=15, 1..=6 and the events newer than the start
+        assert_eq!(
+            deleted,
+            vec![
+                Some(0),
+                Some(10),
+                Some(5),
+                Some(9),
+                Some(9),
+                Some(6),
+                Some(0)
+            ]
+        );
+        assert!(commands[1].filter.ids.is_some());
+        assert!(stored.events.lock().unwrap().is_empty());
+
+        // Dry runs can't tell which ids are left, the second is counted once
+        let stored = StoredEvents::from_events(events(&keys, [25; 15]));
+        let relay_commander = RelayCommander::new(stored.clone());
+        let mut commands = Vec::new();
+        relay_commander
+            .delete_authors_in_chunks(
+                DeletionPath::Vanish,
+                vec![keys.public_key()],
+                nonzero!(10usize),
+                true,
+                &mut commands,
+            )
+            .await
+            .unwrap();
+
+        let deleted: Vec<Option<usize>> = commands.iter().map(|command| command.deleted).collect();
+        assert_eq!(deleted, vec![Some(0), Some(15), Some(0), Some(0)]);
+    }
+
+    #[tokio::test]
+    async fn test_dry_runs_skip_checkpoints() {
+        let keys = Keys::generate();
+        let stored = StoredEvents::new(&keys, 25);
+        let checkpoints = Arc::new(MemoryCheckpoints::default());
+        let relay_commander = RelayCommander::new(stored.clone())
+            .with_max_events_per_command(nonzero!(10usize))
+            .with_checkpoints(checkpoints.clone());
+
+        let checkpoint = Checkpoint {
+            started_at: Timestamp::now(),
+            until: Timestamp::from(15),
+        };
+        checkpoints
+            .save(&keys.public_key(), checkpoint)
+            .await
+            .unwrap();
+
+        let mut commands = Vec::new();
+        relay_commander
+            .delete_authors_in_chunks(
+                DeletionPath::Vanish,
+                vec![keys.public_key()],
+                nonzero!(10usize),
+                true,
+                &mut commands,
+            )
+            .await
+            .unwrap();
+
+        // Started over from now, and the real run's checkpoint is left alone
+        let deleted: Vec<Option<usize>> = commands.iter().map(|command| command.deleted).collect();
+        assert_eq!(deleted, vec![Some(9), Some(9), Some(7), Some(0)]);
+        assert_eq!(
+            checkpoints.load(&keys.public_key()).await.unwrap(),
+            Some(checkpoint)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_groups_small_authors() {
+        let small_keys = [Keys::generate(), Keys::generate(), Keys::generate()];
+        let mut stored_events = Vec::new();
+        for keys in &small_keys {
+            stored_events.extend(events(keys, 1..=4));
+        }
+        let stored = StoredEvents::from_events(stored_events);
+        let relay_commander = RelayCommander::new(stored.clone());
+
+        let mut commands = Vec::new();
+        relay_commander
+            .delete_authors_in_chunks(
+                DeletionPath::Spam,
+                small_keys.iter().map(|keys| keys.public_key()).collect(),
+                nonzero!(10usize),
+                false,
+                &mut commands,
+            )
+            .await
+            .unwrap();
+
+        let deleted: Vec<Option<usize>> = commands.iter().map(|command| command.deleted).collect();
+        assert_eq!(deleted, vec![Some(8), Some(4)]);
+    }
+}
@@ -0,0 +1,549 @@
+use crate::event_analyzer::DeleteRequest;
+use crate::metrics::DELETION_RATE_LIMIT_WAIT;
+use crate::settings::{Settings, SettingsError};
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::sync::Notify;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::warn;
+
+pub static DEFAULT_VANISH_PRIORITY_KEY: &str = "vanish_pending";
+
+// How often spam cleanup checks whether vanish commands are still pending in
+// other processes
+const SHARED_PRIORITY_POLL: Duration = Duration::from_millis(100);
+
+/// Deletions with their own limits. Vanish commands go first, spam cleanup
+/// holds off while any of them is waiting, in this process or, with a shared
+/// `VanishPriority`, in any other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeletionPath {
+    Vanish,
+    Spam,
+}
+
+impl DeletionPath {
+    pub fn of(delete_request: &DeleteRequest) -> Self {
+        match delete_request {
+            DeleteRequest::Vanish(_, _, _) => DeletionPath::Vanish,
+            _ => DeletionPath::Spam,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeletionPath::Vanish => "vanish",
+            DeletionPath::Spam => "spam",
+        }
+    }
+}
+
+/// Limits of a deletion path, unset ones don't throttle. Up to a second
+/// worth of commands or events can run in a burst.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimit {
+    pub commands_per_sec: Option<NonZeroU32>,
+    /// How many events strfry deletes isn't known until the command ends, a
+    /// big command puts the bucket in debt and the next one waits it out
+    pub events_per_sec: Option<NonZeroU32>,
+}
+
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: NonZeroU32) -> Self {
+        let rate = rate.get() as f64;
+        TokenBucket {
+            rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Time until there's a whole token, zero if there already is one
+    fn wait_time(&mut self) -> Duration {
+        let now = Instant::now();
+        let refilled = self.rate * now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + refilled).min(self.rate);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+        }
+    }
+
+    fn take(&mut self, tokens: f64) {
+        self.tokens -= tokens;
+    }
+}
+
+struct PathBuckets {
+    commands: Option<TokenBucket>,
+    events: Option<TokenBucket>,
+}
+
+impl PathBuckets {
+    fn new(rate_limit: RateLimit) -> Self {
+        PathBuckets {
+            commands: rate_limit.commands_per_sec.map(TokenBucket::new),
+            events: rate_limit.events_per_sec.map(TokenBucket::new),
+        }
+    }
+
+    fn wait_time(&mut self) -> Duration {
+        let commands = self.commands.as_mut().map(TokenBucket::wait_time);
+        let events = self.events.as_mut().map(TokenBucket::wait_time);
+        commands.unwrap_or_default().max(events.unwrap_or_default())
+    }
+}
+
+struct LimiterState {
+    vanish: PathBuckets,
+    spam: PathBuckets,
+    vanish_waiting: usize,
+}
+
+impl LimiterState {
+    fn buckets(&mut self, path: DeletionPath) -> &mut PathBuckets {
+        match path {
+            DeletionPath::Vanish => &mut self.vanish,
+            DeletionPath::Spam => &mut self.spam,
+        }
+    }
+}
+
+/// Vanish commands waiting or running in any process, so spam cleanup in
+/// the others holds off. Entries expire on their own, a crashed process
+/// doesn't block spam cleanup for good.
+#[async_trait]
+pub trait VanishPriority: Send + Sync {
+    async fn raise(&self, id: &str, ttl: Duration) -> Result<(), RateLimitError>;
+    async fn lower(&self, id: &str) -> Result<(), RateLimitError>;
+    async fn pending(&self) -> Result<bool, RateLimitError>;
+}
+
+/// Pending vanish commands in a Redis sorted set, scored by when they expire
+pub struct RedisVanishPriority {
+    con: ConnectionManager,
+    key: String,
+}
+
+impl RedisVanishPriority {
+    pub async fn new(redis_url: &str, key: String) -> Result<Self, RateLimitError> {
+        let client = redis::Client::open(redis_url)?;
+        let con = client.get_connection_manager().await?;
+
+        Ok(RedisVanishPriority { con, key })
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[async_trait]
+impl VanishPriority for RedisVanishPriority {
+    async fn raise(&self, id: &str, ttl: Duration) -> Result<(), RateLimitError> {
+        let mut con = self.con.clone();
+        let ttl_millis = ttl.as_millis() as u64;
+        redis::pipe()
+            .atomic()
+            .zadd(&self.key, id, now_millis() + ttl_millis)
+            .ignore()
+            .pexpire(&self.key, ttl_millis as i64)
+            .ignore()
+            .query_async::<()>(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    async fn lower(&self, id: &str) -> Result<(), RateLimitError> {
+        let mut con = self.con.clone();
+        redis::cmd("ZREM")
+            .arg(&self.key)
+            .arg(id)
+            .query_async::<()>(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    async fn pending(&self) -> Result<bool, RateLimitError> {
+        let mut con = self.con.clone();
+        let (pending,): (usize,) = redis::pipe()
+            .atomic()
+            .zrembyscore(&self.key, "-inf", now_millis())
+            .ignore()
+            .zcard(&self.key)
+            .query_async(&mut con)
+            .await?;
+        Ok(pending > 0)
+    }
+}
+
+/// Token buckets on the strfry delete commands per second and on the events
+/// they delete per second, so large sweeps don't starve the live relay
+/// reading from the same LMDB. Clones share the buckets, other processes
+/// have their own.
+#[derive(Clone)]
+pub struct DeletionRateLimiter {
+    state: Arc<Mutex<LimiterState>>,
+    vanish_done: Arc<Notify>,
+    shared_priority: Option<Arc<dyn VanishPriority>>,
+    shared_priority_ttl: Duration,
+}
+
+/// A delete command that got its turn, handed back to `finish` once it ran
+#[must_use]
+pub struct DeletionTurn {
+    // The entry of a vanish command in the shared priority
+    shared_id: Option<String>,
+}
+
+// Counts a vanish command as waiting until it gets its turn or is cancelled
+struct VanishWaiting<'a> {
+    limiter: &'a DeletionRateLimiter,
+}
+
+impl<'a> VanishWaiting<'a> {
+    fn new(limiter: &'a DeletionRateLimiter) -> Self {
+        limiter.state.lock().unwrap().vanish_waiting += 1;
+        VanishWaiting { limiter }
+    }
+}
+
+impl Drop for VanishWaiting<'_> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().vanish_waiting -= 1;
+        self.limiter.vanish_done.notify_waiters();
+    }
+}
+
+impl DeletionRateLimiter {
+    pub fn new(vanish: RateLimit, spam: RateLimit) -> Self {
+        DeletionRateLimiter {
+            state: Arc::new(Mutex::new(LimiterState {
+                vanish: PathBuckets::new(vanish),
+                spam: PathBuckets::new(spam),
+                vanish_waiting: 0,
+            })),
+            vanish_done: Arc::new(Notify::new()),
+            shared_priority: None,
+            shared_priority_ttl: Duration::from_secs(60),
+        }
+    }
+
+    /// Vanish commands are also announced to, and spam commands also wait
+    /// for, the other processes sharing `priority`. An announced command
+    /// stops counting after `ttl` if it's never finished
+    pub fn with_shared_priority(
+        mut self,
+        priority: Arc<dyn VanishPriority>,
+        ttl: Duration,
+    ) -> Self {
+        self.shared_priority = Some(priority);
+        self.shared_priority_ttl = ttl;
+        self
+    }
+
+    /// Waits until a delete command of the path can run
+    pub async fn acquire(&self, path: DeletionPath) -> DeletionTurn {
+        let started = Instant::now();
+        let _waiting = (path == DeletionPath::Vanish).then(|| VanishWaiting::new(self));
+
+        let shared_id = match (&self.shared_priority, path) {
+            (Some(priority), DeletionPath::Vanish) => {
+                let id = format!("{}-{:016x}", std::process::id(), rand::random::<u64>());
+                match priority.raise(&id, self.shared_priority_ttl).await {
+                    Ok(()) => Some(id),
+                    Err(e) => {
+                        warn!("Failed to announce a vanish command: {}", e);
+                        None
+                    }
+                }
+            }
+            (Some(priority), DeletionPath::Spam) => {
+                // Goes ahead when it can't tell, spam cleanup isn't stopped
+                // by a Redis outage
+                loop {
+                    match priority.pending().await {
+                        Ok(true) => sleep(SHARED_PRIORITY_POLL).await,
+                        Ok(false) => break,
+                        Err(e) => {
+                            warn!("Failed to check for pending vanish commands: {}", e);
+                            break;
+                        }
+                    }
+                }
+                None
+            }
+            (None, _) => None,
+        };
+
+        loop {
+            // Registered before checking so a vanish command finishing in
+            // between isn't missed
+            let vanish_done = self.vanish_done.notified();
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                if path == DeletionPath::Spam && state.vanish_waiting > 0 {
+                    None
+                } else {
+                    let buckets = state.buckets(path);
+                    let wait = buckets.wait_time();
+                    if wait.is_zero() {
+                        if let Some(commands) = &mut buckets.commands {
+                            commands.take(1.0);
+                        }
+                        break;
+                    }
+                    Some(wait)
+                }
+            };
+
+            match wait {
+                Some(wait) => sleep(wait).await,
+                None => vanish_done.await,
+            }
+        }
+
+        metrics::histogram!(DELETION_RATE_LIMIT_WAIT, "path" => path.as_str())
+            .record(started.elapsed().as_secs_f64());
+        DeletionTurn { shared_id }
+    }
+
+    /// Called once the command of the turn ran, whether it succeeded or not
+    pub async fn finish(&self, turn: DeletionTurn) {
+        if let (Some(priority), Some(id)) = (&self.shared_priority, turn.shared_id) {
+            if let Err(e) = priority.lower(&id).await {
+                warn!("Failed to clear a finished vanish command: {}", e);
+            }
+        }
+    }
+
+    /// Charges the events a command deleted
+    pub fn record_deleted(&self, path: DeletionPath, events: usize) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(bucket) = &mut state.buckets(path).events {
+            bucket.take(events as f64);
+        }
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct RateLimitArgs {
+    /// Maximum strfry delete commands per second for vanish requests
+    #[arg(long)]
+    pub vanish_commands_per_sec: Option<NonZeroU32>,
+
+    /// Maximum events deleted per second for vanish requests
+    #[arg(long)]
+    pub vanish_events_per_sec: Option<NonZeroU32>,
+
+    /// Maximum strfry delete commands per second for spam cleanup
+    #[arg(long)]
+    pub spam_commands_per_sec: Option<NonZeroU32>,
+
+    /// Maximum events deleted per second for spam cleanup
+    #[arg(long)]
+    pub spam_events_per_sec: Option<NonZeroU32>,
+
+    /// Shares the pending vanish commands through Redis, so spam_cleaner
+    /// holds off while vanish_subscriber deletes. --redis-url when no URL is
+    /// given, both binaries need the same server and key
+    #[arg(long, num_args = 0..=1, require_equals = true)]
+    pub vanish_priority_redis_url: Option<Option<String>>,
+
+    /// Sorted set the pending vanish commands are kept in
+    #[arg(long, default_value = DEFAULT_VANISH_PRIORITY_KEY)]
+    pub vanish_priority_key: String,
+
+    /// Seconds a pending vanish command holds spam cleanup off if its process
+    /// never clears it
+    #[arg(long, default_value_t = 60)]
+    pub vanish_priority_ttl_secs: u64,
+}
+
+impl RateLimitArgs {
+    pub async fn rate_limiter(
+        &self,
+        settings: &Settings,
+    ) -> Result<DeletionRateLimiter, RateLimitError> {
+        let rate_limiter = DeletionRateLimiter::new(
+            RateLimit {
+                commands_per_sec: self.vanish_commands_per_sec,
+                events_per_sec: self.vanish_events_per_sec,
+            },
+            RateLimit {
+                commands_per_sec: self.spam_commands_per_sec,
+                events_per_sec: self.spam_events_per_sec,
+            },
+        );
+
+        match settings.feature_redis_url(&self.vanish_priority_redis_url)? {
+            Some(redis_url) => Ok(rate_limiter.with_shared_priority(
+                Arc::new(
+                    RedisVanishPriority::new(&redis_url, self.vanish_priority_key.clone()).await?,
+                ),
+                Duration::from_secs(self.vanish_priority_ttl_secs),
+            )),
+            None => Ok(rate_limiter),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RateLimitError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+
+    #[error("Settings error: {0}")]
+    Settings(#[from] SettingsError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nonzero_ext::nonzero;
+    use std::collections::HashMap;
+
+    // What Redis would hold, shared by the limiters of two processes
+    #[derive(Default)]
+    struct MemoryPriority(Mutex<HashMap<String, Instant>>);
+
+    #[async_trait]
+    impl VanishPriority for MemoryPriority {
+        async fn raise(&self, id: &str, ttl: Duration) -> Result<(), RateLimitError> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(id.to_string(), Instant::now() + ttl);
+            Ok(())
+        }
+
+        async fn lower(&self, id: &str) -> Result<(), RateLimitError> {
+            self.0.lock().unwrap().remove(id);
+            Ok(())
+        }
+
+        async fn pending(&self) -> Result<bool, RateLimitError> {
+            let mut entries = self.0.lock().unwrap();
+            entries.retain(|_, expires_at| *expires_at > Instant::now());
+            Ok(!entries.is_empty())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_buckets() {
+        let limiter = DeletionRateLimiter::new(
+            RateLimit::default(),
+            RateLimit {
+                commands_per_sec: Some(nonzero!(2u32)),
+                events_per_sec: Some(nonzero!(100u32)),
+            },
+        );
+
+        // The burst
+        let started = Instant::now();
+        let _ = limiter.acquire(DeletionPath::Spam).await;
+        let _ = limiter.acquire(DeletionPath::Spam).await;
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        let _ = limiter.acquire(DeletionPath::Spam).await;
+        assert_eq!(started.elapsed(), Duration::from_millis(500));
+
+        // 300 events is 2 seconds in debt, plus the missing token
+        limiter.record_deleted(DeletionPath::Spam, 300);
+        let started = Instant::now();
+        let _ = limiter.acquire(DeletionPath::Spam).await;
+        assert_eq!(started.elapsed(), Duration::from_millis(2010));
+
+        // Vanish commands have their own limits
+        let started = Instant::now();
+        let _ = limiter.acquire(DeletionPath::Vanish).await;
+        assert_eq!(started.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_vanish_goes_first() {
+        let limiter = DeletionRateLimiter::new(
+            RateLimit {
+                commands_per_sec: Some(nonzero!(1u32)),
+                events_per_sec: None,
+            },
+            RateLimit::default(),
+        );
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let _ = limiter.acquire(DeletionPath::Vanish).await;
+
+        let mut tasks = Vec::new();
+        for path in [DeletionPath::Vanish, DeletionPath::Spam] {
+            let limiter = limiter.clone();
+            let order = order.clone();
+            tasks.push(tokio::spawn(async move {
+                let _ = limiter.acquire(path).await;
+                order.lock().unwrap().push(path);
+            }));
+            tokio::task::yield_now().await;
+        }
+
+        // Spam isn't limited, but waits for the vanish command
+        assert!(order.lock().unwrap().is_empty());
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec![DeletionPath::Vanish, DeletionPath::Spam]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_spam_waits_for_vanish_in_another_process() {
+        let priority = Arc::new(MemoryPriority::default());
+        let ttl = Duration::from_secs(60);
+        let vanish_limiter = DeletionRateLimiter::new(RateLimit::default(), RateLimit::default())
+            .with_shared_priority(priority.clone(), ttl);
+        let spam_limiter = DeletionRateLimiter::new(RateLimit::default(), RateLimit::default())
+            .with_shared_priority(priority.clone(), ttl);
+
+        let turn = vanish_limiter.acquire(DeletionPath::Vanish).await;
+        let spam = tokio::spawn(async move {
+            let _ = spam_limiter.acquire(DeletionPath::Spam).await;
+            Instant::now()
+        });
+
+        // The vanish command takes a second to run
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!spam.is_finished());
+        let finished_at = Instant::now();
+        vanish_limiter.finish(turn).await;
+
+        let spam_started = spam.await.unwrap();
+        assert!(spam_started >= finished_at);
+        assert!(spam_started - finished_at <= SHARED_PRIORITY_POLL);
+
+        // A vanish process that died doesn't hold spam cleanup off past the TTL
+        let _ = vanish_limiter.acquire(DeletionPath::Vanish).await;
+        let spam_limiter = DeletionRateLimiter::new(RateLimit::default(), RateLimit::default())
+            .with_shared_priority(priority, ttl);
+        let started = Instant::now();
+        let _ = spam_limiter.acquire(DeletionPath::Spam).await;
+        assert!(started.elapsed() >= ttl);
+        assert!(started.elapsed() <= ttl + SHARED_PRIORITY_POLL);
+    }
+}
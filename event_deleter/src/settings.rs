@@ -0,0 +1,314 @@
+use config::{Config, Environment, File};
+use nonzero_ext::nonzero;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use thiserror::Error;
+use url::Url;
+
+pub static DEFAULT_STRFRY_PATH: &str = "./strfry";
+
+/// Settings shared by the binaries, loaded from an optional YAML file, then
+/// the environment (`RELAY_URL`, `REDIS_URL`, `STRFRY_PATH`...), then flags.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Public URL of this relay. Vanish requests must be tagged with it or
+    /// with `all_relays`
+    pub relay_url: Option<String>,
+    pub redis_url: Option<String>,
+    /// Relay the validator fetches related events from
+    pub local_relay_url: String,
+    /// strfry binary the delete, scan and import commands run
+    pub strfry_path: String,
+    pub vanish_stream_key: String,
+    /// How long each read of the vanish stream blocks waiting for requests
+    pub vanish_block_millis: NonZeroUsize,
+    /// Redis URLs of the optional features turned on, by feature. Set by the
+    /// binaries from their flags, so `--print-config` shows them
+    #[serde(skip_deserializing, skip_serializing_if = "BTreeMap::is_empty")]
+    pub feature_redis_urls: BTreeMap<String, String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            relay_url: None,
+            redis_url: None,
+            local_relay_url: "ws://localhost:7777".to_string(),
+            strfry_path: DEFAULT_STRFRY_PATH.to_string(),
+            vanish_stream_key: "vanish_requests".to_string(),
+            vanish_block_millis: nonzero!(5000usize),
+            feature_redis_urls: BTreeMap::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// `relay_url`, required by the vanish subscriber
+    pub fn relay_url(&self) -> Result<&str, SettingsError> {
+        self.relay_url
+            .as_deref()
+            .ok_or(SettingsError::Missing("relay_url"))
+    }
+
+    pub fn redis_url(&self) -> Result<&str, SettingsError> {
+        self.redis_url
+            .as_deref()
+            .ok_or(SettingsError::Missing("redis_url"))
+    }
+
+    /// The Redis URL of a feature turned on with a flag taking an optional
+    /// URL, `redis_url` when the flag was given without one. `None` when the
+    /// flag wasn't given
+    pub fn feature_redis_url(
+        &self,
+        flag: &Option<Option<String>>,
+    ) -> Result<Option<String>, SettingsError> {
+        flag.as_ref()
+            .map(|redis_url| self.redis_url_or(redis_url.as_deref()).map(str::to_string))
+            .transpose()
+    }
+
+    /// `redis_url`, or `redis_url` the setting when not set
+    pub fn redis_url_or<'a>(
+        &'a self,
+        redis_url: Option<&'a str>,
+    ) -> Result<&'a str, SettingsError> {
+        let redis_url = match redis_url {
+            Some(redis_url) => redis_url,
+            None => self.redis_url()?,
+        };
+
+        redis::parse_redis_url(redis_url)
+            .ok_or_else(|| SettingsError::InvalidUrl("redis_url", redis_url.to_string()))?;
+        Ok(redis_url)
+    }
+
+    /// Records the Redis URL of a feature for `--print-config`
+    pub fn with_feature_redis_url(
+        mut self,
+        feature: &str,
+        flag: &Option<Option<String>>,
+    ) -> Result<Self, SettingsError> {
+        if let Some(redis_url) = self.feature_redis_url(flag)? {
+            self.feature_redis_urls
+                .insert(feature.to_string(), redis_url);
+        }
+        Ok(self)
+    }
+
+    /// Host of the relay URL, it names the keys of this relay's subscriber
+    pub fn relay_host(&self) -> Result<String, SettingsError> {
+        let url = parse_url("relay_url", self.relay_url()?)?;
+        url.host_str()
+            .map(str::to_string)
+            .ok_or_else(|| SettingsError::InvalidUrl("relay_url", "it has no host".to_string()))
+    }
+
+    /// The same settings with the Redis passwords hidden, for printing
+    pub fn redacted(&self) -> Self {
+        let mut settings = self.clone();
+        settings.redis_url = self.redis_url.as_deref().map(redact);
+        for redis_url in settings.feature_redis_urls.values_mut() {
+            *redis_url = redact(redis_url);
+        }
+        settings
+    }
+
+    fn validate(self) -> Result<Self, SettingsError> {
+        if self.relay_url.is_some() {
+            self.relay_host()?;
+        }
+        if let Some(redis_url) = &self.redis_url {
+            redis::parse_redis_url(redis_url)
+                .ok_or_else(|| SettingsError::InvalidUrl("redis_url", redis_url.clone()))?;
+        }
+        parse_url("local_relay_url", &self.local_relay_url)?;
+        if self.vanish_stream_key.is_empty() {
+            return Err(SettingsError::Missing("vanish_stream_key"));
+        }
+
+        Ok(self)
+    }
+}
+
+fn redact(redis_url: &str) -> String {
+    if let Ok(mut url) = Url::parse(redis_url) {
+        if url.password().is_some() && url.set_password(Some("***")).is_ok() {
+            return url.to_string();
+        }
+    }
+    redis_url.to_string()
+}
+
+fn parse_url(name: &'static str, url: &str) -> Result<Url, SettingsError> {
+    Url::parse(url).map_err(|e| SettingsError::InvalidUrl(name, format!("{}: {}", url, e)))
+}
+
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct SettingsArgs {
+    /// YAML settings file. Environment variables named like its keys, and
+    /// then these flags, override it
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Public URL of this relay, vanish requests must be tagged with it
+    #[arg(long)]
+    pub relay_url: Option<String>,
+
+    /// Redis the vanish requests and subscriber progress are kept in, and
+    /// the default of the other --*-redis-url flags
+    #[arg(long)]
+    pub redis_url: Option<String>,
+
+    /// Relay the validator fetches related events from
+    #[arg(long)]
+    pub local_relay_url: Option<String>,
+
+    /// strfry binary to run
+    #[arg(long)]
+    pub strfry_path: Option<String>,
+
+    /// Prints the effective settings as JSON and exits
+    #[arg(long)]
+    pub print_config: bool,
+}
+
+impl SettingsArgs {
+    pub fn settings(&self) -> Result<Settings, SettingsError> {
+        self.settings_with_env(Environment::default())
+    }
+
+    fn settings_with_env(&self, environment: Environment) -> Result<Settings, SettingsError> {
+        let mut builder = Config::builder().add_source(Config::try_from(&Settings::default())?);
+        if let Some(path) = &self.config {
+            builder = builder.add_source(File::from(path.as_path()));
+        }
+        builder = builder.add_source(environment.ignore_empty(true));
+        let mut settings: Settings = builder.build()?.try_deserialize()?;
+
+        if let Some(relay_url) = &self.relay_url {
+            settings.relay_url = Some(relay_url.clone());
+        }
+        if let Some(redis_url) = &self.redis_url {
+            settings.redis_url = Some(redis_url.clone());
+        }
+        if let Some(local_relay_url) = &self.local_relay_url {
+            settings.local_relay_url = local_relay_url.clone();
+        }
+        if let Some(strfry_path) = &self.strfry_path {
+            settings.strfry_path = strfry_path.clone();
+        }
+
+        settings.validate()
+    }
+
+    /// Prints the settings when `--print-config` is set, the binary should
+    /// exit if it returns true
+    pub fn print_config(&self, settings: &Settings) -> Result<bool, SettingsError> {
+        if self.print_config {
+            println!("{}", serde_json::to_string_pretty(&settings.redacted())?);
+        }
+        Ok(self.print_config)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SettingsError {
+    #[error("Config error: {0}")]
+    Config(#[from] config::ConfigError),
+
+    #[error("Missing setting: {0}")]
+    Missing(&'static str),
+
+    #[error("Invalid {0}: {1}")]
+    InvalidUrl(&'static str, String),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn environment(vars: &[(&str, &str)]) -> Environment {
+        Environment::default().source(Some(
+            vars.iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<HashMap<_, _>>(),
+        ))
+    }
+
+    #[test]
+    fn test_settings_layers() {
+        let path = std::env::temp_dir().join(format!("settings-{}.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            "relay_url: wss://file.example.com\nstrfry_path: /file/strfry\nvanish_block_millis: 100\n",
+        )
+        .unwrap();
+
+        let args = SettingsArgs {
+            config: Some(path.clone()),
+            strfry_path: Some("/flag/strfry".to_string()),
+            ..Default::default()
+        };
+        let settings = args
+            .settings_with_env(environment(&[
+                ("RELAY_URL", "wss://env.example.com"),
+                ("REDIS_URL", "redis://:secret@localhost:6379"),
+                ("STRFRY_PATH", "/env/strfry"),
+            ]))
+            .unwrap();
+
+        assert_eq!(settings.relay_url.as_deref(), Some("wss://env.example.com"));
+        assert_eq!(settings.relay_host().unwrap(), "env.example.com");
+        assert_eq!(settings.strfry_path, "/flag/strfry");
+        assert_eq!(settings.vanish_block_millis, nonzero!(100usize));
+        assert_eq!(settings.vanish_stream_key, "vanish_requests");
+        assert_eq!(
+            settings.redacted().redis_url.as_deref(),
+            Some("redis://:***@localhost:6379")
+        );
+
+        let settings = settings
+            .with_feature_redis_url("audit", &Some(None))
+            .unwrap()
+            .with_feature_redis_url("exemptions", &Some(Some("redis://other:6379".to_string())))
+            .unwrap()
+            .with_feature_redis_url("shadow", &None)
+            .unwrap();
+        assert_eq!(
+            settings.redacted().feature_redis_urls,
+            BTreeMap::from([
+                (
+                    "audit".to_string(),
+                    "redis://:***@localhost:6379".to_string()
+                ),
+                ("exemptions".to_string(), "redis://other:6379".to_string()),
+            ])
+        );
+
+        assert!(matches!(
+            args.settings_with_env(environment(&[("RELAY_URL", "not a url")])),
+            Err(SettingsError::InvalidUrl("relay_url", _))
+        ));
+        assert!(matches!(
+            SettingsArgs::default()
+                .settings_with_env(environment(&[]))
+                .unwrap()
+                .relay_url(),
+            Err(SettingsError::Missing("relay_url"))
+        ));
+        assert!(matches!(
+            Settings::default().feature_redis_url(&Some(None)),
+            Err(SettingsError::Missing("redis_url"))
+        ));
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
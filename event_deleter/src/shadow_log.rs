@@ -0,0 +1,169 @@
+use crate::event_analyzer::Rejection;
+use async_trait::async_trait;
+use nostr_sdk::prelude::*;
+use redis::aio::ConnectionManager;
+use serde::Serialize;
+use std::path::Path;
+use thiserror::Error;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+pub static DEFAULT_SHADOW_STREAM_KEY: &str = "shadow_rejections";
+
+// Keeps the stream from growing forever if nobody trims it
+const SHADOW_STREAM_MAX_LEN: usize = 1_000_000;
+
+/// A rejection recorded in shadow mode instead of being deleted
+#[derive(Debug, Serialize)]
+pub struct ShadowRecord<'a> {
+    pub recorded_at: Timestamp,
+    pub reason: String,
+    #[serde(flatten)]
+    pub rejection: &'a Rejection,
+    pub event: &'a Event,
+}
+
+impl<'a> ShadowRecord<'a> {
+    pub fn new(rejection: &'a Rejection, event: &'a Event) -> Self {
+        ShadowRecord {
+            recorded_at: Timestamp::now(),
+            reason: rejection.delete_request.to_string(),
+            rejection,
+            event,
+        }
+    }
+}
+
+/// Where shadow mode writes the rejections it would have acted on.
+#[async_trait]
+pub trait ShadowSink: Send + Sync + 'static {
+    async fn record(&self, record: &ShadowRecord<'_>) -> Result<(), ShadowLogError>;
+}
+
+/// Appends one JSON line per rejection
+pub struct FileShadowSink {
+    file: Mutex<File>,
+}
+
+impl FileShadowSink {
+    pub async fn open(path: &Path) -> Result<Self, ShadowLogError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        Ok(FileShadowSink {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+#[async_trait]
+impl ShadowSink for FileShadowSink {
+    async fn record(&self, record: &ShadowRecord<'_>) -> Result<(), ShadowLogError> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        // A single write per line so concurrent workers don't interleave
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+/// Adds each rejection to a Redis stream, as JSON in the `record` field
+pub struct RedisShadowSink {
+    con: ConnectionManager,
+    stream_key: String,
+}
+
+impl RedisShadowSink {
+    pub async fn new(redis_url: &str, stream_key: String) -> Result<Self, ShadowLogError> {
+        let client = redis::Client::open(redis_url)?;
+        let con = client.get_connection_manager().await?;
+
+        Ok(RedisShadowSink { con, stream_key })
+    }
+}
+
+#[async_trait]
+impl ShadowSink for RedisShadowSink {
+    async fn record(&self, record: &ShadowRecord<'_>) -> Result<(), ShadowLogError> {
+        let json = serde_json::to_string(record)?;
+
+        let mut con = self.con.clone();
+        redis::cmd("XADD")
+            .arg(&self.stream_key)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(SHADOW_STREAM_MAX_LEN)
+            .arg("*")
+            .arg("record")
+            .arg(json)
+            .query_async::<()>(&mut con)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ShadowLogError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_analyzer::{DeleteRequest, Evidence};
+
+    #[tokio::test]
+    async fn test_file_sink_writes_json_lines() {
+        let path = std::env::temp_dir().join(format!(
+            "shadow-{}.jsonl",
+            Keys::generate().public_key().to_hex()
+        ));
+        let sink = FileShadowSink::open(&path).await.unwrap();
+
+        let event = EventBuilder::text_note("hello", [])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let rejection = Rejection::new("classifier", DeleteRequest::SpamScore(event.id))
+            .with_evidence(Evidence::Score {
+                score: 0.97,
+                labels: vec!["spam".to_string()],
+            });
+
+        sink.record(&ShadowRecord::new(&rejection, &event))
+            .await
+            .unwrap();
+        sink.record(&ShadowRecord::new(&rejection, &event))
+            .await
+            .unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["rule_id"], "classifier");
+        assert_eq!(lines[0]["reason"], "Classified as spam");
+        assert_eq!(lines[0]["delete_request"]["spam_score"], event.id.to_hex());
+        assert_eq!(lines[0]["evidence"]["score"]["score"], 0.97);
+        assert_eq!(lines[0]["event"]["id"], event.id.to_hex());
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,34 +1,51 @@
 use crate::event_analyzer::DeleteRequest;
+use crate::health::HealthState;
+use crate::metrics::{VANISH_ACK_LATENCY, VANISH_STREAM_LAG};
+use crate::redis_supervisor::RedisSupervisor;
+use crate::settings::Settings;
 use async_trait::async_trait;
 use redis::{
     aio::ConnectionManager,
-    streams::{StreamId, StreamKey, StreamReadOptions, StreamReadReply},
+    streams::{StreamId, StreamKey, StreamRangeReply, StreamReadOptions, StreamReadReply},
     AsyncCommands, RedisError,
 };
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::sync::Arc;
-use std::{env, sync::LazyLock};
+use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc;
+use tokio::time::{self, Duration, Instant};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::{debug, error, info};
-use url::Url;
-
-static BLOCK_MILLIS: usize = 5000;
-static VANISH_STREAM_KEY: &str = "vanish_requests";
-static RELAY_URL: LazyLock<String> =
-    LazyLock::new(|| env::var("RELAY_URL").expect("RELAY_URL must be set"));
-static VANISH_LAST_ID_KEY: LazyLock<String> = LazyLock::new(|| {
-    let url = Url::parse(&*RELAY_URL).expect("Invalid RELAY_URL format");
-    let sanitized_url = url
-        .host_str()
-        .expect("RELAY_URL must have a host")
-        .to_string();
-
-    format!(
-        "vanish_requests:deletion_subscriber:last_id:{}",
-        sanitized_url
-    )
-});
+
+// Requests sent to the deletion task and still waiting for their ack. The
+// oldest are forgotten past this, their ack latency isn't recorded
+const MAX_PENDING_ACKS: usize = 10_000;
+
+// The stream tail is read at most this often to compute the lag, acks and
+// reads can come in bursts
+const STREAM_LAG_INTERVAL: Duration = Duration::from_secs(1);
+
+// The stream read and the key the last processed id of this relay is saved in
+struct VanishStream {
+    key: String,
+    last_id_key: String,
+    relay_url: String,
+    block_millis: usize,
+}
+
+impl VanishStream {
+    fn from_settings(settings: &Settings) -> Result<Self, Box<dyn Error>> {
+        Ok(VanishStream {
+            key: settings.vanish_stream_key.clone(),
+            last_id_key: format!(
+                "vanish_requests:deletion_subscriber:last_id:{}",
+                settings.relay_host()?
+            ),
+            relay_url: settings.relay_url()?.to_string(),
+            block_millis: settings.vanish_block_millis.get(),
+        })
+    }
+}
 
 pub struct RedisClient {
     client: redis::Client,
@@ -41,9 +58,9 @@ pub trait RedisClientTrait: Send + Sync + 'static {
 }
 
 impl RedisClient {
-    pub fn new(url: &str) -> Self {
-        let client = redis::Client::open(url).expect("Failed to create Redis client");
-        RedisClient { client }
+    pub fn new(url: &str) -> Result<Self, RedisError> {
+        let client = redis::Client::open(url)?;
+        Ok(RedisClient { client })
     }
 }
 
@@ -70,22 +87,19 @@ pub trait RedisClientConnectionTrait: Send + Sync + 'static {
         ids: &[String],
         opts: &StreamReadOptions,
     ) -> Result<StreamReadReply, RedisError>;
+    /// The id of the newest entry in the stream
+    async fn last_stream_id(&mut self, key: &str) -> Result<Option<String>, RedisError>;
+    async fn ping(&mut self) -> Result<(), RedisError>;
 }
 
 #[async_trait]
 impl RedisClientConnectionTrait for RedisClientConnection {
     async fn get(&mut self, key: &str) -> Result<String, RedisError> {
-        match self.con.get(key).await {
-            Ok(value) => Ok(value),
-            Err(_) => self.con.get(key).await,
-        }
+        self.con.get(key).await
     }
 
     async fn set(&mut self, key: &str, value: String) -> Result<(), RedisError> {
-        match self.con.set(key, value.clone()).await {
-            Ok(()) => Ok(()),
-            Err(_) => self.con.set(key, value).await,
-        }
+        self.con.set(key, value).await
     }
 
     async fn xread_options(
@@ -94,77 +108,138 @@ impl RedisClientConnectionTrait for RedisClientConnection {
         ids: &[String],
         opts: &StreamReadOptions,
     ) -> Result<StreamReadReply, RedisError> {
-        match self.con.xread_options(keys, ids, opts).await {
-            Ok(reply) => Ok(reply),
-            Err(_) => self.con.xread_options(keys, ids, opts).await,
-        }
+        self.con.xread_options(keys, ids, opts).await
+    }
+
+    async fn last_stream_id(&mut self, key: &str) -> Result<Option<String>, RedisError> {
+        let reply: StreamRangeReply = self.con.xrevrange_count(key, "+", "-", 1).await?;
+        Ok(reply.ids.into_iter().next().map(|stream_id| stream_id.id))
+    }
+
+    async fn ping(&mut self) -> Result<(), RedisError> {
+        redis::cmd("PING").query_async(&mut self.con).await
     }
 }
 
+/// Connects through the supervisor before spawning the tasks, so it fails
+/// if Redis can't be reached within the startup timeout
+#[allow(clippy::too_many_arguments)]
 pub async fn spawn_vanish_subscriber<T: RedisClientTrait>(
     tracker: &TaskTracker,
     deletion_sender: mpsc::Sender<DeleteRequest>,
     mut ack_receiver: mpsc::Receiver<DeleteRequest>,
     redis_client: T,
+    health: HealthState,
+    supervisor: RedisSupervisor,
+    settings: &Settings,
     cancellation_token: CancellationToken,
 ) -> Result<(), Box<dyn Error>> {
-    let redis_client = Arc::new(redis_client);
+    let stream = Arc::new(VanishStream::from_settings(settings)?);
+    let sent_at = Arc::new(Mutex::new(PendingAcks::default()));
+    let stream_lag = Arc::new(StreamLag::default());
+    let last_acked_id = Arc::new(Mutex::new(None::<String>));
+
+    let mut ack_con = supervisor.connect(&redis_client).await?;
+    let mut reader_con = supervisor.connect(&redis_client).await?;
+    let start_id = ack_con
+        .get(&stream.last_id_key)
+        .await
+        .unwrap_or_else(|_| "0-0".to_string());
 
-    let redis_client_clone = redis_client.clone();
+    let stream_clone = stream.clone();
+    let sent_at_clone = sent_at.clone();
+    let stream_lag_clone = stream_lag.clone();
+    let last_acked_id_clone = last_acked_id.clone();
+    let supervisor_clone = supervisor.clone();
+    let mut last_id = start_id.clone();
     tracker.spawn(async move {
-        let (mut con, mut last_id) = match get_connection_and_last_id(redis_client_clone).await {
-            Ok(result) => result,
-            Err(e) => {
-                error!("Failed to get Redis connection: {}", e);
-                return;
-            }
-        };
+        let supervisor = supervisor_clone;
+        let stream = stream_clone;
+        let con = &mut ack_con;
+        // The newest id waiting to be saved, and when to retry after a
+        // failed save. Acks keep being drained in the meantime
+        let mut unsaved_id = None::<String>;
+        let mut retry_at = None::<Instant>;
 
-        while let Some(ack) = ack_receiver.recv().await {
-            if let DeleteRequest::Vanish(id, ..) = ack {
+        loop {
+            let ack = tokio::select! {
+                ack = ack_receiver.recv() => match ack {
+                    Some(ack) => Some(ack),
+                    None => break,
+                },
+                _ = time::sleep_until(retry_at.unwrap_or_else(Instant::now)), if retry_at.is_some() => {
+                    retry_at = None;
+                    None
+                }
+            };
+
+            if let Some(DeleteRequest::Vanish(id, ..)) = ack {
                 debug!("Received ack");
 
-                if id > last_id {
-                    let save_last_id_result: Result<(), RedisError> =
-                        con.set(&VANISH_LAST_ID_KEY, last_id.clone()).await;
+                let sent = sent_at_clone.lock().unwrap().ack(&id);
+                if let Some(sent) = sent {
+                    metrics::histogram!(VANISH_ACK_LATENCY).record(sent.elapsed().as_secs_f64());
+                }
+                stream_lag_clone.record(con, &stream.key, &id).await;
+                *last_acked_id_clone.lock().unwrap() = Some(id.clone());
 
-                    if let Err(e) = save_last_id_result {
-                        //Failed to save last id: broken pipe
-                        error!("Failed to save last id: {}", e);
-                    } else {
-                        info!("Updating last vanish stream id processed to {}", last_id);
-                    }
+                if stream_id_parts(&id) > stream_id_parts(&last_id) {
+                    unsaved_id = Some(std::mem::replace(&mut last_id, id));
+                }
+            }
 
-                    last_id = id.clone();
+            if retry_at.is_some() || supervisor.gave_up() {
+                continue;
+            }
+            let Some(id) = unsaved_id.take() else {
+                continue;
+            };
+
+            match con.set(&stream.last_id_key, id.clone()).await {
+                Ok(()) => {
+                    supervisor.record_success();
+                    info!("Updating last vanish stream id processed to {}", id);
+                }
+                Err(e) => {
+                    error!("Failed to save last id: {}", e);
+                    // Counts against the failure budget like the reader's
+                    // errors
+                    if let Ok(delay) = supervisor.record_failure(&e) {
+                        retry_at = Some(Instant::now() + delay);
+                    }
+                    unsaved_id = Some(id);
                 }
             }
         }
-    });
 
-    let redis_client_clone = redis_client.clone();
-    tracker.spawn(async move {
-        let (mut con, mut last_id) = match get_connection_and_last_id(redis_client_clone).await {
-            Ok(result) => result,
-            Err(e) => {
-                error!("Failed to get Redis connection: {}", e);
-                return;
+        // Acks are drained on shutdown, one last try to save the newest
+        if let Some(id) = unsaved_id {
+            match con.set(&stream.last_id_key, id.clone()).await {
+                Ok(()) => info!("Updating last vanish stream id processed to {}", id),
+                Err(e) => error!("Failed to save last id {}: {}", id, e),
             }
-        };
+        }
+    });
 
-        let opts = StreamReadOptions::default().block(BLOCK_MILLIS);
+    tracker.spawn(async move {
+        let con = &mut reader_con;
+        let mut last_id = start_id.clone();
+        let opts = StreamReadOptions::default().block(stream.block_millis);
 
         info!("Starting from last id processed: {}", last_id);
 
         loop {
-            tokio::select! {
+            let result = tokio::select! {
                 _ = cancellation_token.cancelled() => {
                     break;
                 }
 
                 result = async {
                     let reply: StreamReadReply = con
-                        .xread_options(&[VANISH_STREAM_KEY], &[last_id.clone()], &opts)
+                        .xread_options(&[stream.key.as_str()], &[last_id.clone()], &opts)
                         .await?;
+                    health.record_xread();
+                    supervisor.record_success();
 
                     for StreamKey { ids, .. } in reply.keys {
                         for stream_id in ids {
@@ -172,16 +247,40 @@ pub async fn spawn_vanish_subscriber<T: RedisClientTrait>(
                                 continue;
                             }
 
-                            process_stream_id(&stream_id, &deletion_sender).await?;
+                            process_stream_id(
+                                &stream_id,
+                                &stream.relay_url,
+                                &deletion_sender,
+                                &sent_at,
+                            )
+                            .await?;
                             last_id = stream_id.id.clone();
                         }
                     }
+
+                    // Also refreshed here in case the deletions are stuck
+                    // and no acks arrive
+                    let acked_id = last_acked_id
+                        .lock()
+                        .unwrap()
+                        .clone()
+                        .unwrap_or_else(|| start_id.clone());
+                    stream_lag.record(con, &stream.key, &acked_id).await;
+
                     Ok::<(), Box<dyn Error>>(())
-                } => {
-                    if let Err(e) = result {
-                        error!("Error in Redis stream reader task: {}", e);
-                        continue;
-                    }
+                } => result.map_err(|e| e.to_string()),
+            };
+
+            if let Err(e) = result {
+                error!("Error in Redis stream reader task: {}", e);
+                if deletion_sender.is_closed() {
+                    error!("The deletion task stopped, stopping the reader");
+                    break;
+                }
+                // Fails once the budget ran out or on shutdown, the token is
+                // cancelled either way
+                if supervisor.backoff(&e).await.is_err() {
+                    break;
                 }
             }
         }
@@ -190,22 +289,87 @@ pub async fn spawn_vanish_subscriber<T: RedisClientTrait>(
     Ok(())
 }
 
-async fn get_connection_and_last_id<T: RedisClientTrait>(
-    redis_client: Arc<T>,
-) -> Result<(T::Connection, String), RedisError> {
-    let mut con = redis_client.get_connection().await?;
-    let last_id = con
-        .get(&VANISH_LAST_ID_KEY)
-        .await
-        .unwrap_or_else(|_| "0-0".to_string());
-    Ok((con, last_id))
+/// When each request was sent to the deletion task, for the ack latency.
+/// Acks come in stream order, so an ack also drops the older requests that
+/// will never get theirs, like the ones the deletion task didn't get to.
+#[derive(Default)]
+struct PendingAcks {
+    sent_at: BTreeMap<(u64, u64), Instant>,
+}
+
+impl PendingAcks {
+    fn insert(&mut self, id: &str) {
+        if self.sent_at.len() >= MAX_PENDING_ACKS {
+            self.sent_at.pop_first();
+        }
+        self.sent_at.insert(stream_id_parts(id), Instant::now());
+    }
+
+    fn remove(&mut self, id: &str) {
+        self.sent_at.remove(&stream_id_parts(id));
+    }
+
+    /// When the acked request was sent, if it's still known
+    fn ack(&mut self, id: &str) -> Option<Instant> {
+        let id = stream_id_parts(id);
+        let sent = self.sent_at.remove(&id);
+        self.sent_at = self.sent_at.split_off(&id);
+        sent
+    }
+}
+
+/// Reads the stream tail to set the lag gauge, at most once per
+/// `STREAM_LAG_INTERVAL`
+#[derive(Default)]
+struct StreamLag {
+    recorded_at: Mutex<Option<Instant>>,
+}
+
+impl StreamLag {
+    // The lag is the difference between the millisecond parts of the ids
+    async fn record<C: RedisClientConnectionTrait>(
+        &self,
+        con: &mut C,
+        stream_key: &str,
+        acked_id: &str,
+    ) {
+        {
+            let mut recorded_at = self.recorded_at.lock().unwrap();
+            if recorded_at.is_some_and(|recorded_at| recorded_at.elapsed() < STREAM_LAG_INTERVAL) {
+                return;
+            }
+            *recorded_at = Some(Instant::now());
+        }
+
+        match con.last_stream_id(stream_key).await {
+            Ok(tail_id) => {
+                let lag_millis = tail_id.map_or(0, |tail_id| {
+                    stream_id_parts(&tail_id)
+                        .0
+                        .saturating_sub(stream_id_parts(acked_id).0)
+                });
+                metrics::gauge!(VANISH_STREAM_LAG).set(lag_millis as f64 / 1000.0);
+            }
+            Err(e) => {
+                debug!("Failed to read the vanish stream tail: {}", e);
+            }
+        }
+    }
+}
+
+// The millisecond and sequence parts of a stream id
+fn stream_id_parts(id: &str) -> (u64, u64) {
+    let (millis, sequence) = id.split_once('-').unwrap_or((id, "0"));
+    (millis.parse().unwrap_or(0), sequence.parse().unwrap_or(0))
 }
 
 async fn process_stream_id(
     stream_id: &StreamId,
+    relay_url: &str,
     deletion_sender: &mpsc::Sender<DeleteRequest>,
+    sent_at: &Mutex<PendingAcks>,
 ) -> Result<(), Box<dyn Error>> {
-    let vanish_request = match DeleteRequest::try_from(stream_id) {
+    let vanish_request = match DeleteRequest::from_vanish_stream_id(stream_id, relay_url) {
         Ok(vanish_request) => vanish_request,
         Err(e) => {
             // Log the error and continue processing the next stream id
@@ -220,8 +384,10 @@ async fn process_stream_id(
 
     info!("Received vanish request: {:?}", vanish_request);
 
+    sent_at.lock().unwrap().insert(&stream_id.id);
     deletion_sender.send(vanish_request).await.map_err(|e| {
         error!("Failed to send vanish request: {}", e);
+        sent_at.lock().unwrap().remove(&stream_id.id);
         e
     })?;
 
@@ -231,18 +397,26 @@ async fn process_stream_id(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::redis_supervisor::SupervisorPolicy;
+    use nonzero_ext::nonzero;
     use nostr_sdk::prelude::Keys;
+    use redis::ErrorKind;
     use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
+    use tokio::time::{self, Duration};
 
     struct MockRedisClient {
         last_id: String,
         stream_ids_sequence: Arc<Mutex<Vec<StreamReadReply>>>,
+        // Sets failing before they work again, and every id set
+        failing_sets: usize,
+        sets: Arc<Mutex<Vec<String>>>,
     }
     struct MockRedisClientConnection {
         last_id: String,
         stream_ids_sequence: Arc<Mutex<Vec<StreamReadReply>>>,
         index: usize,
+        failing_sets: usize,
+        sets: Arc<Mutex<Vec<String>>>,
     }
 
     #[async_trait::async_trait]
@@ -252,6 +426,11 @@ mod tests {
         }
 
         async fn set(&mut self, _key: &str, value: String) -> Result<(), RedisError> {
+            self.sets.lock().unwrap().push(value.clone());
+            if self.failing_sets > 0 {
+                self.failing_sets -= 1;
+                return Err(RedisError::from((ErrorKind::IoError, "Broken pipe")));
+            }
             self.last_id = value;
             Ok(())
         }
@@ -274,6 +453,14 @@ mod tests {
                 Ok(StreamReadReply { keys: Vec::new() })
             }
         }
+
+        async fn last_stream_id(&mut self, _key: &str) -> Result<Option<String>, RedisError> {
+            Ok(None)
+        }
+
+        async fn ping(&mut self) -> Result<(), RedisError> {
+            Ok(())
+        }
     }
 
     impl MockRedisClient {
@@ -281,6 +468,8 @@ mod tests {
             MockRedisClient {
                 last_id,
                 stream_ids_sequence,
+                failing_sets: 0,
+                sets: Arc::new(Mutex::new(Vec::new())),
             }
         }
     }
@@ -293,6 +482,8 @@ mod tests {
                 last_id: self.last_id.clone(),
                 stream_ids_sequence: self.stream_ids_sequence.clone(),
                 index: 0,
+                failing_sets: self.failing_sets,
+                sets: self.sets.clone(),
             })
         }
     }
@@ -304,7 +495,7 @@ mod tests {
 
         let stream_read_reply_1 = StreamReadReply {
             keys: vec![StreamKey {
-                key: VANISH_STREAM_KEY.to_string(),
+                key: "vanish_requests".to_string(),
                 ids: vec![StreamId {
                     id: "1-0".to_string(),
                     map: HashMap::from([
@@ -328,7 +519,7 @@ mod tests {
 
         let stream_read_reply_2 = StreamReadReply {
             keys: vec![StreamKey {
-                key: VANISH_STREAM_KEY.to_string(),
+                key: "vanish_requests".to_string(),
                 ids: vec![StreamId {
                     id: "2-0".to_string(),
                     map: HashMap::from([
@@ -383,6 +574,12 @@ mod tests {
             deletion_sender,
             ack_receiver,
             redis_client,
+            HealthState::default(),
+            RedisSupervisor::new(SupervisorPolicy::default(), cancellation_token.clone()),
+            &Settings {
+                relay_url: Some("wss://example.com".to_string()),
+                ..Default::default()
+            },
             cancellation_token,
         )
         .await
@@ -409,4 +606,180 @@ mod tests {
             panic!("Expected second request to be Vanish");
         }
     }
+
+    #[tokio::test]
+    async fn test_failed_saves_count_against_the_budget() {
+        let stream_read_reply = StreamReadReply {
+            keys: vec![StreamKey {
+                key: "vanish_requests".to_string(),
+                ids: vec![StreamId {
+                    id: "1-0".to_string(),
+                    map: HashMap::from([
+                        (
+                            "pubkey".to_string(),
+                            redis::Value::BulkString(Keys::generate().public_key().to_hex().into()),
+                        ),
+                        ("kind".to_string(), redis::Value::Int(62)),
+                        (
+                            "tags".to_string(),
+                            redis::Value::BulkString("all_relays".into()),
+                        ),
+                    ]),
+                }],
+            }],
+        };
+        let redis_client = MockRedisClient {
+            failing_sets: usize::MAX,
+            ..MockRedisClient::new(
+                "0-0".to_string(),
+                Arc::new(Mutex::new(vec![stream_read_reply])),
+            )
+        };
+        let (deletion_sender, mut deletion_receiver) = mpsc::channel::<DeleteRequest>(10);
+        let (ack_sender, ack_receiver) = mpsc::channel(10);
+        let cancellation_token = CancellationToken::new();
+        let tracker = TaskTracker::new();
+
+        // Acks without ever stopping, only the supervisor can
+        tracker.spawn(async move {
+            while let Some(request) = deletion_receiver.recv().await {
+                let _ = ack_sender.send(request).await;
+            }
+        });
+
+        let supervisor = RedisSupervisor::new(
+            SupervisorPolicy {
+                max_failures: nonzero!(1u32),
+                ..Default::default()
+            },
+            cancellation_token.clone(),
+        );
+        spawn_vanish_subscriber(
+            &tracker,
+            deletion_sender,
+            ack_receiver,
+            redis_client,
+            HealthState::default(),
+            supervisor,
+            &Settings {
+                relay_url: Some("wss://example.com".to_string()),
+                ..Default::default()
+            },
+            cancellation_token.clone(),
+        )
+        .await
+        .unwrap();
+        tracker.close();
+
+        time::timeout(Duration::from_secs(5), tracker.wait())
+            .await
+            .expect("The subscriber should give up");
+        assert!(cancellation_token.is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acks_are_drained_while_saves_fail() {
+        let ids = vec!["1-0", "2-0", "3-0"]
+            .into_iter()
+            .map(|id| StreamId {
+                id: id.to_string(),
+                map: HashMap::from([
+                    (
+                        "pubkey".to_string(),
+                        redis::Value::BulkString(Keys::generate().public_key().to_hex().into()),
+                    ),
+                    ("kind".to_string(), redis::Value::Int(62)),
+                    (
+                        "tags".to_string(),
+                        redis::Value::BulkString("all_relays".into()),
+                    ),
+                ]),
+            })
+            .collect();
+        let stream_read_reply = StreamReadReply {
+            keys: vec![StreamKey {
+                key: "vanish_requests".to_string(),
+                ids,
+            }],
+        };
+        let redis_client = MockRedisClient {
+            failing_sets: 1,
+            ..MockRedisClient::new(
+                "0-0".to_string(),
+                Arc::new(Mutex::new(vec![stream_read_reply])),
+            )
+        };
+        let sets = redis_client.sets.clone();
+        let (deletion_sender, mut deletion_receiver) = mpsc::channel::<DeleteRequest>(10);
+        let (ack_sender, ack_receiver) = mpsc::channel(10);
+        let (release_sender, release_receiver) = tokio::sync::oneshot::channel::<()>();
+        let cancellation_token = CancellationToken::new();
+        let tracker = TaskTracker::new();
+
+        // Acks everything, then holds the channel open until released
+        let token = cancellation_token.clone();
+        tracker.spawn(async move {
+            for _ in 0..3 {
+                let request = deletion_receiver.recv().await.unwrap();
+                ack_sender.send(request).await.unwrap();
+            }
+            token.cancel();
+            let _ = release_receiver.await;
+        });
+
+        let supervisor = RedisSupervisor::new(
+            SupervisorPolicy {
+                initial_backoff: Duration::from_secs(60),
+                max_backoff: Duration::from_secs(60),
+                ..Default::default()
+            },
+            cancellation_token.clone(),
+        );
+        spawn_vanish_subscriber(
+            &tracker,
+            deletion_sender,
+            ack_receiver,
+            redis_client,
+            HealthState::default(),
+            supervisor,
+            &Settings {
+                relay_url: Some("wss://example.com".to_string()),
+                ..Default::default()
+            },
+            cancellation_token,
+        )
+        .await
+        .unwrap();
+        tracker.close();
+
+        // The first save failed, the other acks wait for the retry
+        time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(*sets.lock().unwrap(), vec!["0-0"]);
+
+        // Only the newest id is saved then
+        time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(*sets.lock().unwrap(), vec!["0-0", "2-0"]);
+
+        release_sender.send(()).unwrap();
+        tracker.wait().await;
+        assert_eq!(*sets.lock().unwrap(), vec!["0-0", "2-0"]);
+    }
+
+    #[test]
+    fn test_acks_drop_older_pending_requests() {
+        let mut pending = PendingAcks::default();
+        for id in ["9-0", "10-0", "10-1", "11-0"] {
+            pending.insert(id);
+        }
+
+        // Numeric order, "9-0" is older than "10-0"
+        assert!(pending.ack("10-1").is_some());
+        assert_eq!(pending.sent_at.keys().collect::<Vec<_>>(), vec![&(11, 0)]);
+        assert!(pending.ack("10-0").is_none());
+
+        for i in 0..MAX_PENDING_ACKS + 10 {
+            pending.insert(&format!("{}-0", 100 + i));
+        }
+        assert_eq!(pending.sent_at.len(), MAX_PENDING_ACKS);
+    }
 }
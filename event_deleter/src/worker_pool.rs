@@ -1,95 +1,495 @@
+pub mod concurrency_limit;
+mod keyed_queue;
+
+use crate::metrics::{WORKER_POISONED_ITEMS, WORKER_QUEUE_DEPTH, WORKER_RETRIES};
+use concurrency_limit::{AdaptiveConcurrency, ConcurrencyLimiter};
+use keyed_queue::KeyedQueue;
+use metrics::Gauge;
 use std::error::Error;
-use std::fmt::Debug;
-use std::num::{NonZeroU16, NonZeroU64};
-use std::sync::Arc;
+use std::fmt::{Debug, Display};
+use std::num::{NonZeroU16, NonZeroU32, NonZeroU64};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
 use tokio::sync::mpsc::{self, Sender};
-use tokio::time::{timeout, Duration};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout, Duration, Instant};
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, trace, warn};
+
+// Items of a busy key can't be taken right away, so more of them may wait in
+// keyed mode before the dispatcher holds off
+const KEYED_ITEMS_PER_WORKER: usize = 4;
+
+/// How a pool retries the items a worker fails on
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts per item, including the first one
+    pub max_attempts: NonZeroU32,
+    /// Wait before the first retry, doubled for each of the next ones
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: NonZeroU32, initial_backoff: Duration) -> Self {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff,
+            ..Default::default()
+        }
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+/// A single attempt, failed items are dropped
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: NonZeroU32::MIN,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// An item the worker failed on, with the error of the last attempt
+#[derive(Debug)]
+pub struct PoisonedItem<Item> {
+    pub item: Item,
+    pub error: String,
+    pub attempts: u32,
+}
+
+/// What a pool does with its items once the cancellation token is cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Stop taking new items and finish the ones already sent to the pool.
+    /// Whatever is still running at the deadline is aborted.
+    Drain { deadline: Duration },
+    /// Cancel the calls in flight and drop the queued items right away
+    Abort,
+}
+
+impl Default for ShutdownMode {
+    fn default() -> Self {
+        ShutdownMode::Drain {
+            deadline: Duration::from_secs(30),
+        }
+    }
+}
+
+/// What happened to the items sent to a pool
+#[derive(Debug)]
+pub struct PoolShutdownReport<Item> {
+    /// Items the worker finished successfully
+    pub processed: usize,
+    /// Items given up on, they are in `poisoned`
+    pub failed: usize,
+    /// Items never processed because the pool was aborted
+    pub dropped: usize,
+    /// Items whose processing was cut short by an abort
+    pub in_flight: usize,
+    /// Attempts that failed and were tried again
+    pub retries: usize,
+    /// Items that failed every attempt, or with an error that isn't retryable
+    pub poisoned: Vec<PoisonedItem<Item>>,
+}
+
+impl<Item> Display for PoolShutdownReport<Item> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "processed: {}, failed: {}, dropped: {}, in flight: {}, retries: {}",
+            self.processed, self.failed, self.dropped, self.in_flight, self.retries
+        )
+    }
+}
+
+// Updated by the pool tasks as they go
+struct PoolStats<Item> {
+    // Taken from the item receiver by the dispatcher
+    accepted: usize,
+    // Left in the item receiver by an abort
+    unread: usize,
+    processed: usize,
+    in_flight: usize,
+    retries: usize,
+    poisoned: Vec<PoisonedItem<Item>>,
+}
+
+impl<Item> Default for PoolStats<Item> {
+    fn default() -> Self {
+        PoolStats {
+            accepted: 0,
+            unread: 0,
+            processed: 0,
+            in_flight: 0,
+            retries: 0,
+            poisoned: Vec::new(),
+        }
+    }
+}
 
-pub struct WorkerPool {}
+/// A running pool
+pub struct WorkerPoolHandle<Item> {
+    tracker: TaskTracker,
+    stats: Arc<StdMutex<PoolStats<Item>>>,
+}
+
+impl<Item> WorkerPoolHandle<Item> {
+    /// Waits for the pool to finish
+    pub async fn report(self) -> PoolShutdownReport<Item> {
+        self.tracker.wait().await;
+        let stats = std::mem::take(&mut *self.stats.lock().unwrap());
+
+        // Accepted items that didn't end any other way were sitting in a
+        // worker queue when the pool was aborted
+        let failed = stats.poisoned.len();
+        let unfinished = stats
+            .accepted
+            .saturating_sub(stats.processed + failed + stats.in_flight);
+
+        PoolShutdownReport {
+            processed: stats.processed,
+            failed,
+            dropped: stats.unread + unfinished,
+            in_flight: stats.in_flight,
+            retries: stats.retries,
+            poisoned: stats.poisoned,
+        }
+    }
+}
+
+pub struct WorkerPool {
+    name: String,
+    num_workers: NonZeroU16,
+    worker_timeout_secs: NonZeroU64,
+    retry_policy: RetryPolicy,
+    shutdown_mode: ShutdownMode,
+    adaptive_concurrency: Option<AdaptiveConcurrency>,
+}
 
 // A channel based worker pool that distributes work to a pool of workers.
-// Items come through the item_rx channel and are distributed to workers.
-// Workers implement the WorkerTask trait that receives the item to process.
+// Items come through the item_rx channel and are forwarded to a queue shared
+// by all the workers, so whichever worker is free takes the next item and a
+// slow one doesn't hold up the rest. Workers implement the WorkerTask trait
+// that receives the item to process.
 impl WorkerPool {
+    pub fn new(name: &str, num_workers: NonZeroU16, worker_timeout_secs: NonZeroU64) -> Self {
+        WorkerPool {
+            name: name.to_string(),
+            num_workers,
+            worker_timeout_secs,
+            retry_policy: RetryPolicy::default(),
+            shutdown_mode: ShutdownMode::default(),
+            adaptive_concurrency: None,
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_shutdown_mode(mut self, shutdown_mode: ShutdownMode) -> Self {
+        self.shutdown_mode = shutdown_mode;
+        self
+    }
+
+    /// Adjusts how many workers run at once, up to the number of workers
+    pub fn with_adaptive_concurrency(mut self, adaptive_concurrency: AdaptiveConcurrency) -> Self {
+        self.adaptive_concurrency = Some(adaptive_concurrency);
+        self
+    }
+
+    /// The pool's tasks are also waited for by the tracker.
+    ///
+    /// With a key extractor, items with the same key are processed one at a
+    /// time in the order they came, and free workers take the items of the
+    /// other keys meanwhile. Without it any free worker takes the next item.
     pub fn start<Item, Worker>(
+        self,
         tracker: &TaskTracker,
-        pool_name: &str,
-        num_workers: NonZeroU16,
-        worker_timeout_secs: NonZeroU64,
         item_receiver: mpsc::Receiver<Item>,
+        key_extractor: Option<KeyExtractor<Item>>,
         cancellation_token: CancellationToken,
         worker: Worker,
-    ) -> TaskTracker
+    ) -> WorkerPoolHandle<Item>
     where
         Item: Debug + Send + Sync + Clone + 'static,
         Worker: WorkerTask<Item> + Send + Sync + 'static,
     {
-        // Spawn a pool of worker tasks to process each item. Call the worker_fn for each item.
-        let mut worker_txs = Vec::new();
+        let pool_tracker = TaskTracker::new();
+        let stats = Arc::new(StdMutex::new(PoolStats::default()));
+        let queue_depth = QueueDepth::new(&self.name);
+        // Cancelled by the dispatcher when the shutdown turns into an abort
+        let abort_token = CancellationToken::new();
 
-        let shared_worker = Arc::new(worker);
+        let num_workers = self.num_workers.get() as usize;
+        let (dispatch, worker_queues): (_, Vec<WorkerQueue<Item>>) = match key_extractor {
+            Some(key_extractor) => {
+                let queue = Arc::new(KeyedQueue::new(num_workers * KEYED_ITEMS_PER_WORKER));
+                let worker_queues = (0..num_workers)
+                    .map(|_| WorkerQueue::Keyed(queue.clone()))
+                    .collect();
+                (Dispatch::Keyed(queue, key_extractor), worker_queues)
+            }
+            None => {
+                // As many items wait in the queue as there are workers
+                let (queue_tx, queue_rx) = mpsc::channel::<Item>(num_workers);
+                let queue_rx = Arc::new(Mutex::new(queue_rx));
+                let worker_queues = (0..num_workers)
+                    .map(|_| WorkerQueue::Shared(queue_rx.clone()))
+                    .collect();
+                (Dispatch::Shared(queue_tx), worker_queues)
+            }
+        };
 
-        for i in 0..num_workers.get() {
-            let (worker_tx, worker_rx) = mpsc::channel::<Item>(1);
-            worker_txs.push(worker_tx);
+        let shared_worker = Arc::new(worker);
+        let retry_policy = Arc::new(self.retry_policy);
+        let limiter = self.adaptive_concurrency.map(|adaptive_concurrency| {
+            Arc::new(ConcurrencyLimiter::new(
+                &self.name,
+                adaptive_concurrency,
+                self.num_workers,
+            ))
+        });
 
+        // Spawn a pool of worker tasks to process each item. Call the worker_fn for each item.
+        for (i, worker_queue) in worker_queues.into_iter().enumerate() {
             create_worker_task(
-                tracker,
-                pool_name.to_string(),
-                worker_timeout_secs,
-                worker_rx,
+                &pool_tracker,
+                WorkerContext {
+                    worker_name: format!("{}-{}", self.name, i),
+                    pool_name: self.name.clone(),
+                    worker_timeout_secs: self.worker_timeout_secs,
+                    retry_policy: retry_policy.clone(),
+                    stats: stats.clone(),
+                    abort_token: abort_token.clone(),
+                    limiter: limiter.clone(),
+                },
+                worker_queue,
+                queue_depth.clone(),
                 shared_worker.clone(),
-                i,
             );
         }
 
         create_dispatcher_task(
-            tracker,
-            pool_name.to_string(),
+            &pool_tracker,
+            DispatcherContext {
+                pool_name: self.name,
+                shutdown_mode: self.shutdown_mode,
+                stats: stats.clone(),
+                cancellation_token,
+                abort_token,
+            },
             item_receiver,
-            worker_txs,
-            cancellation_token,
+            dispatch,
+            queue_depth,
         );
 
-        tracker.clone()
+        pool_tracker.close();
+        let waited_tracker = pool_tracker.clone();
+        tracker.spawn(async move { waited_tracker.wait().await });
+
+        WorkerPoolHandle {
+            tracker: pool_tracker,
+            stats,
+        }
+    }
+}
+
+/// Maps an item to the key its processing is serialized on
+pub type KeyExtractor<Item> = Box<dyn Fn(&Item) -> String + Send + Sync>;
+
+// How the dispatcher hands items to the workers
+enum Dispatch<Item> {
+    Shared(Sender<Item>),
+    Keyed(Arc<KeyedQueue<Item>>, KeyExtractor<Item>),
+}
+
+impl<Item> Dispatch<Item> {
+    // Waits for room in the queue, that is, for a worker to take an item
+    async fn send(&self, item: Item) -> Result<(), String> {
+        match self {
+            Dispatch::Shared(queue_tx) => queue_tx.send(item).await.map_err(|e| e.to_string()),
+            Dispatch::Keyed(queue, key_extractor) => {
+                queue.push(key_extractor(&item), item).await;
+                Ok(())
+            }
+        }
+    }
+
+    // Workers finish what's left in the queue and stop
+    fn close(self) {
+        if let Dispatch::Keyed(queue, _) = self {
+            queue.close();
+        }
+    }
+}
+
+enum WorkerQueue<Item> {
+    Shared(Arc<Mutex<mpsc::Receiver<Item>>>),
+    Keyed(Arc<KeyedQueue<Item>>),
+}
+
+impl<Item> WorkerQueue<Item> {
+    // The item and, in keyed mode, its key to pass to `done`
+    async fn recv(&mut self) -> Option<(Item, Option<String>)> {
+        match self {
+            // Idle workers wait for the lock in turn, the first in line gets
+            // the next item
+            WorkerQueue::Shared(queue_rx) => Some((queue_rx.lock().await.recv().await?, None)),
+            WorkerQueue::Keyed(queue) => {
+                let (key, item) = queue.pop().await?;
+                Some((item, Some(key)))
+            }
+        }
+    }
+
+    fn done(&self, key: Option<String>) {
+        if let (WorkerQueue::Keyed(queue), Some(key)) = (self, key) {
+            queue.done(&key);
+        }
+    }
+}
+
+// Items handed to the workers that no worker has started on yet
+#[derive(Clone)]
+struct QueueDepth {
+    queued: Arc<AtomicUsize>,
+    gauge: Gauge,
+}
+
+impl QueueDepth {
+    fn new(pool_name: &str) -> Self {
+        QueueDepth {
+            queued: Arc::new(AtomicUsize::new(0)),
+            gauge: metrics::gauge!(WORKER_QUEUE_DEPTH, "pool" => pool_name.to_string()),
+        }
+    }
+
+    fn pushed(&self) {
+        let queued = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        self.gauge.set(queued as f64);
+    }
+
+    fn popped(&self) {
+        let queued = self.queued.fetch_sub(1, Ordering::Relaxed) - 1;
+        self.gauge.set(queued as f64);
+    }
+}
+
+struct DispatcherContext<Item> {
+    pool_name: String,
+    shutdown_mode: ShutdownMode,
+    stats: Arc<StdMutex<PoolStats<Item>>>,
+    cancellation_token: CancellationToken,
+    abort_token: CancellationToken,
+}
+
+impl<Item> DispatcherContext<Item> {
+    fn start_shutdown(&self, item_receiver: &mut mpsc::Receiver<Item>) {
+        // Senders fail from now on, what's already buffered can still be read
+        item_receiver.close();
+
+        match self.shutdown_mode {
+            ShutdownMode::Abort => {
+                info!(
+                    "{}: Cancellation token is cancelled, aborting worker pool",
+                    self.pool_name
+                );
+                self.abort_token.cancel();
+            }
+            ShutdownMode::Drain { deadline } => {
+                info!(
+                    "{}: Cancellation token is cancelled, draining worker pool for up to {:?}",
+                    self.pool_name, deadline
+                );
+
+                // Not tracked so a drain that ends early doesn't wait for it
+                let abort_token = self.abort_token.clone();
+                let pool_name = self.pool_name.clone();
+                tokio::spawn(async move {
+                    sleep(deadline).await;
+                    if !abort_token.is_cancelled() {
+                        warn!(
+                            "{}: Drain deadline reached, aborting worker pool",
+                            pool_name
+                        );
+                        abort_token.cancel();
+                    }
+                });
+            }
+        }
     }
 }
 
 fn create_dispatcher_task<Item>(
     tracker: &TaskTracker,
-    pool_name: String,
+    context: DispatcherContext<Item>,
     mut item_receiver: mpsc::Receiver<Item>,
-    worker_txs: Vec<Sender<Item>>,
-    cancellation_token: CancellationToken,
+    dispatch: Dispatch<Item>,
+    queue_depth: QueueDepth,
 ) where
     Item: Debug + Send + Clone + 'static,
 {
     tracker.spawn(async move {
-        // Simple cycle iterator to distribute work to workers in a round-robin fashion.
-        let mut worker_txs_cycle = worker_txs.iter().cycle();
+        let pool_name = &context.pool_name;
+        let mut shutting_down = false;
 
-        loop {
+        'dispatch: loop {
             tokio::select! {
-                _ = cancellation_token.cancelled() => {
-                    info!("{}: Cancellation token is cancelled, stopping worker pool", pool_name);
+                biased;
+
+                _ = context.abort_token.cancelled() => {
                     break;
                 }
 
+                _ = context.cancellation_token.cancelled(), if !shutting_down => {
+                    shutting_down = true;
+                    context.start_shutdown(&mut item_receiver);
+                }
+
                 result = item_receiver.recv() => {
                     match result {
                         Some(item) => {
                             trace!("{}: Worker pool dispatching item {:?}", pool_name, item);
-                            let Some(worker_tx) = worker_txs_cycle.next() else {
-                                error!("{}: Failed to get worker", pool_name);
-                                break;
-                            };
+                            context.stats.lock().unwrap().accepted += 1;
 
-                            if let Err(e) = worker_tx.send(item).await {
-                                error!("{}: Failed to send to worker: {}", pool_name, e);
-                                break;
+                            // Counted before sending so the worker taking it
+                            // never sees the count go below zero
+                            queue_depth.pushed();
+
+                            let send = dispatch.send(item);
+                            tokio::pin!(send);
+                            loop {
+                                tokio::select! {
+                                    biased;
+
+                                    _ = context.abort_token.cancelled() => {
+                                        break 'dispatch;
+                                    }
+
+                                    _ = context.cancellation_token.cancelled(), if !shutting_down => {
+                                        shutting_down = true;
+                                        context.start_shutdown(&mut item_receiver);
+                                    }
+
+                                    result = &mut send => {
+                                        if let Err(e) = result {
+                                            error!("{}: Failed to send to workers: {}", pool_name, e);
+                                            break 'dispatch;
+                                        }
+                                        break;
+                                    }
+                                }
                             }
                         }
                         None => {
@@ -101,52 +501,99 @@ fn create_dispatcher_task<Item>(
             }
         }
 
-        for worker_tx in worker_txs {
-            drop(worker_tx);
+        if context.abort_token.is_cancelled() {
+            item_receiver.close();
+            let mut unread = 0;
+            while item_receiver.try_recv().is_ok() {
+                unread += 1;
+            }
+            context.stats.lock().unwrap().unread = unread;
         }
 
+        dispatch.close();
+
         debug!("{}: Worker pool dispatcher finished", pool_name);
     });
 }
 
-fn create_worker_task<Item, Worker>(
-    tracker: &TaskTracker,
+// What a worker task needs besides its queue and worker
+struct WorkerContext<Item> {
+    worker_name: String,
     pool_name: String,
     worker_timeout_secs: NonZeroU64,
-    mut worker_rx: mpsc::Receiver<Item>,
+    retry_policy: Arc<RetryPolicy>,
+    stats: Arc<StdMutex<PoolStats<Item>>>,
+    abort_token: CancellationToken,
+    limiter: Option<Arc<ConcurrencyLimiter>>,
+}
+
+impl<Item> WorkerContext<Item> {
+    fn record_latency(&self, started: Instant, timed_out: bool) {
+        if let Some(limiter) = &self.limiter {
+            limiter.record(started.elapsed(), timed_out);
+        }
+    }
+}
+
+fn create_worker_task<Item, Worker>(
+    tracker: &TaskTracker,
+    context: WorkerContext<Item>,
+    mut worker_queue: WorkerQueue<Item>,
+    queue_depth: QueueDepth,
     worker: Arc<Worker>,
-    worker_index: u16,
 ) where
     Item: Debug + Send + Sync + Clone + 'static,
     Worker: WorkerTask<Item> + Send + Sync + 'static,
 {
-    let worker_name = format!("{}-{}", pool_name, worker_index);
     tracker.spawn(async move {
+        let worker_name = &context.worker_name;
         loop {
-            tokio::select! {
-                result = worker_rx.recv() => {
-                    match result {
-                        None => {
-                            debug!("{}: Worker task finished processing all items", worker_name);
-                            break;
-                        }
-                        Some(item) => {
-                            trace!("{}: Worker task processing item {:?}", worker_name, item);
-                            let result = timeout(Duration::from_secs(worker_timeout_secs.get()), worker.call(item)).await;
+            let result = tokio::select! {
+                biased;
 
-                            match result {
-                                Ok(Ok(())) => {
-                                    trace!("{}: Worker task finished successfully processing item", worker_name);
-                                }
-                                Ok(Err(e)) => {
-                                    error!("{}: Worker failed: {}", worker_name, e);
-                                }
-                                Err(_) => {
-                                    error!("{}: Worker task timed out after {} seconds", worker_name, worker_timeout_secs);
-                                }
+                _ = context.abort_token.cancelled() => {
+                    debug!("{}: Worker task aborted", worker_name);
+                    break;
+                }
+
+                result = worker_queue.recv() => result,
+            };
+
+            match result {
+                None => {
+                    debug!("{}: Worker task finished processing all items", worker_name);
+                    break;
+                }
+                Some((item, key)) => {
+                    queue_depth.popped();
+
+                    // Taken once there is an item, so idle workers don't
+                    // hold the slots. Held while processing it
+                    let _permit = match &context.limiter {
+                        Some(limiter) => tokio::select! {
+                            biased;
+
+                            _ = context.abort_token.cancelled() => {
+                                debug!("{}: Worker task aborted", worker_name);
+                                break;
                             }
+
+                            permit = limiter.acquire() => Some(permit),
+                        },
+                        None => None,
+                    };
+                    trace!("{}: Worker task processing item {:?}", worker_name, item);
+
+                    tokio::select! {
+                        _ = context.abort_token.cancelled() => {
+                            warn!("{}: Worker task aborted while processing an item", worker_name);
+                            context.stats.lock().unwrap().in_flight += 1;
+                            break;
                         }
+
+                        _ = process_item(&context, worker.as_ref(), item) => {}
                     }
+                    worker_queue.done(key);
                 }
             }
         }
@@ -155,6 +602,92 @@ fn create_worker_task<Item, Worker>(
     });
 }
 
+// Calls the worker until it succeeds, fails with an error that isn't
+// retryable or runs out of attempts
+async fn process_item<Item, Worker>(context: &WorkerContext<Item>, worker: &Worker, item: Item)
+where
+    Item: Debug + Send + Sync + Clone + 'static,
+    Worker: WorkerTask<Item> + Send + Sync + 'static,
+{
+    let worker_name = &context.worker_name;
+    let worker_timeout = Duration::from_secs(context.worker_timeout_secs.get());
+    let max_attempts = context.retry_policy.max_attempts.get();
+
+    let mut attempt = 1;
+    loop {
+        let started = Instant::now();
+        let last_attempt = attempt >= max_attempts;
+        let (error, retryable) = match timeout(worker_timeout, worker.call(item.clone())).await {
+            Ok(Ok(())) => {
+                trace!(
+                    "{}: Worker task finished successfully processing item",
+                    worker_name
+                );
+                context.record_latency(started, false);
+                context.stats.lock().unwrap().processed += 1;
+                return;
+            }
+            Ok(Err(e)) => {
+                context.record_latency(started, false);
+                let retryable = e.is_retryable();
+                if !retryable || last_attempt {
+                    worker.poisoned(&item, Some(&e));
+                }
+                (e.to_string(), retryable)
+            }
+            Err(_) => {
+                context.record_latency(started, true);
+                if last_attempt {
+                    worker.poisoned(&item, None);
+                }
+                (
+                    format!("Timed out after {} seconds", context.worker_timeout_secs),
+                    true,
+                )
+            }
+        };
+
+        if !retryable || last_attempt {
+            error!(
+                "{}: Worker failed after {} attempts: {}",
+                worker_name, attempt, error
+            );
+            metrics::counter!(WORKER_POISONED_ITEMS, "pool" => context.pool_name.clone())
+                .increment(1);
+            context.stats.lock().unwrap().poisoned.push(PoisonedItem {
+                item,
+                error,
+                attempts: attempt,
+            });
+            return;
+        }
+
+        let backoff = context.retry_policy.backoff(attempt);
+        warn!(
+            "{}: Worker failed on attempt {} of {}, retrying in {:?}: {}",
+            worker_name, attempt, max_attempts, backoff, error
+        );
+        metrics::counter!(WORKER_RETRIES, "pool" => context.pool_name.clone()).increment(1);
+        context.stats.lock().unwrap().retries += 1;
+
+        sleep(backoff).await;
+        attempt += 1;
+    }
+}
+
+/// Errors returned by workers, telling the pool whether the item is worth
+/// another attempt
+pub trait WorkerError: Display {
+    fn is_retryable(&self) -> bool;
+}
+
+/// Anything could be temporary
+impl WorkerError for Box<dyn Error> {
+    fn is_retryable(&self) -> bool {
+        true
+    }
+}
+
 /// The worker task trait that workers must implement to process items.
 /// The same instance will be used for all items so common global state can be shared.
 #[async_trait::async_trait]
@@ -162,5 +695,312 @@ pub trait WorkerTask<Item>
 where
     Item: Debug + Send + Sync + Clone + 'static,
 {
-    async fn call(&self, args: Item) -> Result<(), Box<dyn Error>>;
+    type Error: WorkerError;
+
+    async fn call(&self, args: Item) -> Result<(), Self::Error>;
+
+    /// Called once when the pool gives up on an item, with the error of the
+    /// last attempt, or `None` if it timed out
+    fn poisoned(&self, _item: &Item, _error: Option<&Self::Error>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nonzero_ext::nonzero;
+
+    // Sleeps for the item's number of seconds and records the order items finish in
+    struct Sleeper {
+        finished: Arc<StdMutex<Vec<u64>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl WorkerTask<u64> for Sleeper {
+        type Error = Box<dyn Error>;
+
+        async fn call(&self, secs: u64) -> Result<(), Box<dyn Error>> {
+            tokio::time::sleep(Duration::from_secs(secs)).await;
+            self.finished.lock().unwrap().push(secs);
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    struct FlakyError {
+        retryable: bool,
+    }
+
+    impl Display for FlakyError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "flaky, retryable: {}", self.retryable)
+        }
+    }
+
+    impl WorkerError for FlakyError {
+        fn is_retryable(&self) -> bool {
+            self.retryable
+        }
+    }
+
+    // Fails the first `item` calls for each item, 0 fails for good
+    struct Flaky {
+        calls: StdMutex<std::collections::HashMap<u32, u32>>,
+        poisoned: Arc<StdMutex<Vec<u32>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl WorkerTask<u32> for Flaky {
+        type Error = FlakyError;
+
+        async fn call(&self, item: u32) -> Result<(), FlakyError> {
+            if item == 0 {
+                return Err(FlakyError { retryable: false });
+            }
+
+            let mut calls = self.calls.lock().unwrap();
+            let count = calls.entry(item).or_default();
+            *count += 1;
+            if *count <= item {
+                return Err(FlakyError { retryable: true });
+            }
+            Ok(())
+        }
+
+        fn poisoned(&self, item: &u32, _error: Option<&FlakyError>) {
+            self.poisoned.lock().unwrap().push(*item);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_free_workers_take_the_next_item() {
+        let tracker = TaskTracker::new();
+        let finished = Arc::new(StdMutex::new(Vec::new()));
+        let (item_sender, item_receiver) = mpsc::channel(10);
+
+        WorkerPool::new("test_pool", nonzero!(2u16), nonzero!(100u64)).start(
+            &tracker,
+            item_receiver,
+            None,
+            CancellationToken::new(),
+            Sleeper {
+                finished: finished.clone(),
+            },
+        );
+        tracker.close();
+
+        // Round robin would queue the third item behind the slow first one
+        for secs in [50, 1, 1, 1] {
+            item_sender.send(secs).await.unwrap();
+        }
+        drop(item_sender);
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(*finished.lock().unwrap(), vec![1, 1, 1]);
+
+        tracker.wait().await;
+        assert_eq!(*finished.lock().unwrap(), vec![1, 1, 1, 50]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_and_poisoned_items() {
+        let tracker = TaskTracker::new();
+        let (item_sender, item_receiver) = mpsc::channel(10);
+        let poisoned_calls = Arc::new(StdMutex::new(Vec::new()));
+
+        let pool = WorkerPool::new("test_pool", nonzero!(2u16), nonzero!(100u64))
+            .with_retry_policy(RetryPolicy::new(nonzero!(3u32), Duration::from_secs(1)))
+            .start(
+                &tracker,
+                item_receiver,
+                None,
+                CancellationToken::new(),
+                Flaky {
+                    calls: StdMutex::new(Default::default()),
+                    poisoned: poisoned_calls.clone(),
+                },
+            );
+        tracker.close();
+
+        for item in [1, 2, 3, 0] {
+            item_sender.send(item).await.unwrap();
+        }
+        drop(item_sender);
+
+        let report = pool.report().await;
+        assert_eq!(report.processed, 2);
+        // 1 + 2 for the items that succeeded, 2 for the one that ran out of attempts
+        assert_eq!(report.retries, 5);
+
+        let mut poisoned: Vec<(u32, u32)> = report
+            .poisoned
+            .iter()
+            .map(|poisoned| (poisoned.item, poisoned.attempts))
+            .collect();
+        poisoned.sort();
+        assert_eq!(poisoned, vec![(0, 1), (3, 3)]);
+        assert!(report.poisoned[0].error.starts_with("flaky"));
+
+        // Once per item given up on, not per attempt
+        let mut poisoned_calls = poisoned_calls.lock().unwrap().clone();
+        poisoned_calls.sort();
+        assert_eq!(poisoned_calls, vec![0, 3]);
+
+        tracker.wait().await;
+    }
+
+    // Sleeps for the item's number of seconds and records the item once done
+    struct KeyedSleeper {
+        finished: Arc<StdMutex<Vec<(char, u64)>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl WorkerTask<(char, u64)> for KeyedSleeper {
+        type Error = Box<dyn Error>;
+
+        async fn call(&self, item: (char, u64)) -> Result<(), Box<dyn Error>> {
+            tokio::time::sleep(Duration::from_secs(item.1)).await;
+            self.finished.lock().unwrap().push(item);
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_items_with_the_same_key_run_in_order() {
+        let tracker = TaskTracker::new();
+        let finished = Arc::new(StdMutex::new(Vec::new()));
+        let (item_sender, item_receiver) = mpsc::channel(10);
+
+        WorkerPool::new("test_pool", nonzero!(4u16), nonzero!(100u64)).start(
+            &tracker,
+            item_receiver,
+            Some(Box::new(|item: &(char, u64)| item.0.to_string())),
+            CancellationToken::new(),
+            KeyedSleeper {
+                finished: finished.clone(),
+            },
+        );
+        tracker.close();
+
+        for item in [('a', 30), ('a', 1), ('b', 1), ('a', 10)] {
+            item_sender.send(item).await.unwrap();
+        }
+        drop(item_sender);
+        tracker.wait().await;
+
+        // Unkeyed, the faster ones would finish first. Other keys don't wait.
+        assert_eq!(
+            *finished.lock().unwrap(),
+            vec![('b', 1), ('a', 30), ('a', 1), ('a', 10)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keys_queued_behind_a_busy_key_still_run() {
+        let tracker = TaskTracker::new();
+        let finished = Arc::new(StdMutex::new(Vec::new()));
+        let (item_sender, item_receiver) = mpsc::channel(10);
+
+        WorkerPool::new("test_pool", nonzero!(2u16), nonzero!(100u64)).start(
+            &tracker,
+            item_receiver,
+            Some(Box::new(|item: &(char, u64)| item.0.to_string())),
+            CancellationToken::new(),
+            KeyedSleeper {
+                finished: finished.clone(),
+            },
+        );
+        tracker.close();
+
+        // One worker is stuck on 'a' and its next item waits for it, the
+        // other takes every other key whatever the key hashes to
+        for item in [('a', 50), ('a', 1), ('b', 1), ('c', 1), ('d', 1)] {
+            item_sender.send(item).await.unwrap();
+        }
+        drop(item_sender);
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(
+            *finished.lock().unwrap(),
+            vec![('b', 1), ('c', 1), ('d', 1)]
+        );
+
+        tracker.wait().await;
+        assert_eq!(finished.lock().unwrap()[3..], [('a', 50), ('a', 1)]);
+    }
+
+    async fn shutdown_after(
+        shutdown_mode: ShutdownMode,
+        items: &[u64],
+        cancel_after: Duration,
+    ) -> PoolShutdownReport<u64> {
+        let tracker = TaskTracker::new();
+        let cancellation_token = CancellationToken::new();
+        let (item_sender, item_receiver) = mpsc::channel(10);
+
+        let pool = WorkerPool::new("test_pool", nonzero!(1u16), nonzero!(100u64))
+            .with_shutdown_mode(shutdown_mode)
+            .start(
+                &tracker,
+                item_receiver,
+                None,
+                cancellation_token.clone(),
+                Sleeper {
+                    finished: Arc::new(StdMutex::new(Vec::new())),
+                },
+            );
+        tracker.close();
+
+        for secs in items {
+            item_sender.send(*secs).await.unwrap();
+        }
+
+        tokio::time::sleep(cancel_after).await;
+        cancellation_token.cancel();
+
+        let report = pool.report().await;
+        // The pool stopped taking items
+        assert!(item_sender.send(1).await.is_err());
+        report
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_abort_shutdown() {
+        let report = shutdown_after(
+            ShutdownMode::Abort,
+            &[10, 10, 10, 10],
+            Duration::from_secs(5),
+        )
+        .await;
+
+        assert_eq!(report.processed, 0);
+        assert_eq!(report.in_flight, 1);
+        assert_eq!(report.dropped, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_shutdown() {
+        let drain = ShutdownMode::Drain {
+            deadline: Duration::from_secs(100),
+        };
+        let report = shutdown_after(drain, &[10, 10, 10], Duration::from_secs(5)).await;
+        assert_eq!(report.processed, 3);
+        assert_eq!((report.failed, report.dropped, report.in_flight), (0, 0, 0));
+
+        // The second item is still running at the deadline
+        let drain = ShutdownMode::Drain {
+            deadline: Duration::from_secs(10),
+        };
+        let report = shutdown_after(drain, &[10, 10, 10], Duration::from_secs(5)).await;
+        assert_eq!(report.processed, 1);
+        assert_eq!(report.in_flight, 1);
+        assert_eq!(report.dropped, 1);
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_max() {
+        let policy = RetryPolicy::new(nonzero!(10u32), Duration::from_secs(1));
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff(9), Duration::from_secs(30));
+    }
 }
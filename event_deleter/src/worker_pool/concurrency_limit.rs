@@ -0,0 +1,208 @@
+use crate::metrics::WORKER_CONCURRENCY_LIMIT;
+use metrics::Gauge;
+use std::num::NonZeroU16;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+
+/// Adaptive concurrency for a pool, AIMD style: the limit grows by one after
+/// a full window of calls within the latency target, and is multiplied by
+/// `backoff_ratio` when a call times out or is slower than the target. The
+/// number of workers is the upper bound.
+#[derive(Debug, Clone)]
+pub struct AdaptiveConcurrency {
+    pub min_limit: NonZeroU16,
+    pub latency_target: Duration,
+    /// Between 0 and 1
+    pub backoff_ratio: f64,
+}
+
+impl AdaptiveConcurrency {
+    pub fn new(min_limit: NonZeroU16, latency_target: Duration) -> Self {
+        AdaptiveConcurrency {
+            min_limit,
+            latency_target,
+            backoff_ratio: 0.75,
+        }
+    }
+}
+
+struct LimitState {
+    limit: usize,
+    in_use: usize,
+    // Good calls since the limit last changed
+    successes: usize,
+    last_decrease: Option<Instant>,
+}
+
+/// Lets at most `limit` workers process an item at the same time
+pub(super) struct ConcurrencyLimiter {
+    config: AdaptiveConcurrency,
+    max_limit: usize,
+    state: Mutex<LimitState>,
+    released: Notify,
+    gauge: Gauge,
+}
+
+/// Frees its slot when dropped
+pub(super) struct LimitPermit<'a> {
+    limiter: &'a ConcurrencyLimiter,
+}
+
+impl Drop for LimitPermit<'_> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().in_use -= 1;
+        self.limiter.released.notify_waiters();
+    }
+}
+
+impl ConcurrencyLimiter {
+    /// Starts at the maximum, the pool behaves as a fixed one until calls
+    /// slow down. A minimum above the maximum is lowered to it
+    pub(super) fn new(
+        pool_name: &str,
+        mut config: AdaptiveConcurrency,
+        max_limit: NonZeroU16,
+    ) -> Self {
+        config.min_limit = config.min_limit.min(max_limit);
+        let max_limit = max_limit.get() as usize;
+        let gauge = metrics::gauge!(WORKER_CONCURRENCY_LIMIT, "pool" => pool_name.to_string());
+        gauge.set(max_limit as f64);
+
+        ConcurrencyLimiter {
+            config,
+            max_limit,
+            state: Mutex::new(LimitState {
+                limit: max_limit,
+                in_use: 0,
+                successes: 0,
+                last_decrease: None,
+            }),
+            released: Notify::new(),
+            gauge,
+        }
+    }
+
+    pub(super) async fn acquire(&self) -> LimitPermit<'_> {
+        loop {
+            // Registered before checking so a release in between isn't missed
+            let released = self.released.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.in_use < state.limit {
+                    state.in_use += 1;
+                    return LimitPermit { limiter: self };
+                }
+            }
+            released.await;
+        }
+    }
+
+    #[cfg(test)]
+    fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// Adjusts the limit with the outcome of a call
+    pub(super) fn record(&self, latency: Duration, timed_out: bool) {
+        let mut state = self.state.lock().unwrap();
+
+        if timed_out || latency > self.config.latency_target {
+            // Calls that were already running when the limit dropped are
+            // slow for the same reason, one decrease per target period
+            if let Some(last_decrease) = state.last_decrease {
+                if last_decrease.elapsed() < self.config.latency_target {
+                    return;
+                }
+            }
+
+            let decreased = (state.limit as f64 * self.config.backoff_ratio) as usize;
+            state.limit = decreased.clamp(self.config.min_limit.get() as usize, self.max_limit);
+            state.last_decrease = Some(Instant::now());
+        } else {
+            state.successes += 1;
+            if state.successes < state.limit {
+                return;
+            }
+
+            state.limit = (state.limit + 1).min(self.max_limit);
+        }
+
+        state.successes = 0;
+        let limit = state.limit;
+        drop(state);
+
+        self.gauge.set(limit as f64);
+        self.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nonzero_ext::nonzero;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_aimd_within_bounds() {
+        let limiter = ConcurrencyLimiter::new(
+            "test_pool",
+            AdaptiveConcurrency::new(nonzero!(2u16), Duration::from_secs(1)),
+            nonzero!(8u16),
+        );
+        assert_eq!(limiter.limit(), 8);
+
+        limiter.record(Duration::from_millis(10), true);
+        assert_eq!(limiter.limit(), 6);
+        // Too soon after the last decrease
+        limiter.record(Duration::from_secs(5), false);
+        assert_eq!(limiter.limit(), 6);
+
+        for _ in 0..3 {
+            tokio::time::advance(Duration::from_secs(2)).await;
+            limiter.record(Duration::from_secs(5), false);
+        }
+        assert_eq!(limiter.limit(), 2);
+
+        // A window of good calls adds one
+        for _ in 0..2 {
+            limiter.record(Duration::from_millis(10), false);
+        }
+        assert_eq!(limiter.limit(), 3);
+
+        for _ in 0..100 {
+            limiter.record(Duration::from_millis(10), false);
+        }
+        assert_eq!(limiter.limit(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_min_limit_above_the_max_is_lowered() {
+        let limiter = ConcurrencyLimiter::new(
+            "test_pool",
+            AdaptiveConcurrency::new(nonzero!(8u16), Duration::from_secs(1)),
+            nonzero!(4u16),
+        );
+
+        limiter.record(Duration::from_secs(5), false);
+        assert_eq!(limiter.limit(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_for_a_free_slot() {
+        let limiter = ConcurrencyLimiter::new(
+            "test_pool",
+            AdaptiveConcurrency::new(nonzero!(1u16), Duration::from_secs(1)),
+            nonzero!(1u16),
+        );
+
+        let permit = limiter.acquire().await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), limiter.acquire())
+                .await
+                .is_err()
+        );
+
+        drop(permit);
+        let _permit = limiter.acquire().await;
+    }
+}
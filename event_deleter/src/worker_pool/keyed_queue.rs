@@ -0,0 +1,128 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+/// Items waiting in a queue per key. Each key is taken by one worker at a
+/// time, so its items run in order, and any free worker takes the next ready
+/// key, so a slow key doesn't hold up the others.
+pub(super) struct KeyedQueue<Item> {
+    state: Mutex<KeyedState<Item>>,
+    capacity: usize,
+    // Notified when a key gets ready or the queue is closed
+    ready: Notify,
+    // Notified when an item is taken
+    space: Notify,
+}
+
+struct KeyedState<Item> {
+    pending: HashMap<String, VecDeque<Item>>,
+    // Keys with pending items and no worker on them, oldest first
+    ready: VecDeque<String>,
+    // Keys a worker is processing an item of
+    busy: HashSet<String>,
+    len: usize,
+    closed: bool,
+}
+
+impl<Item> KeyedQueue<Item> {
+    pub(super) fn new(capacity: usize) -> Self {
+        KeyedQueue {
+            state: Mutex::new(KeyedState {
+                pending: HashMap::new(),
+                ready: VecDeque::new(),
+                busy: HashSet::new(),
+                len: 0,
+                closed: false,
+            }),
+            capacity,
+            ready: Notify::new(),
+            space: Notify::new(),
+        }
+    }
+
+    /// Waits while `capacity` items are already waiting
+    pub(super) async fn push(&self, key: String, item: Item) {
+        loop {
+            let space = self.space.notified();
+            tokio::pin!(space);
+            space.as_mut().enable();
+
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.len < self.capacity {
+                    state.len += 1;
+                    let queue = state.pending.entry(key.clone()).or_default();
+                    queue.push_back(item);
+                    let first = queue.len() == 1;
+                    if first && !state.busy.contains(&key) {
+                        state.ready.push_back(key);
+                    }
+                    drop(state);
+
+                    self.ready.notify_waiters();
+                    return;
+                }
+            }
+
+            space.await;
+        }
+    }
+
+    /// The next item of a ready key. The key isn't handed to anyone else
+    /// until `done` is called with it. `None` once closed and empty
+    pub(super) async fn pop(&self) -> Option<(String, Item)> {
+        loop {
+            let ready = self.ready.notified();
+            tokio::pin!(ready);
+            ready.as_mut().enable();
+
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(key) = state.ready.pop_front() {
+                    let queue = state
+                        .pending
+                        .get_mut(&key)
+                        .expect("Ready keys have pending items");
+                    let item = queue.pop_front().expect("Pending queues aren't empty");
+                    if queue.is_empty() {
+                        state.pending.remove(&key);
+                    }
+                    state.len -= 1;
+                    state.busy.insert(key.clone());
+                    // The workers still waiting have nothing left to take
+                    let drained = state.closed && state.len == 0;
+                    drop(state);
+
+                    self.space.notify_waiters();
+                    if drained {
+                        self.ready.notify_waiters();
+                    }
+                    return Some((key, item));
+                }
+
+                if state.closed && state.len == 0 {
+                    return None;
+                }
+            }
+
+            ready.await;
+        }
+    }
+
+    /// Lets the next item of the key be taken
+    pub(super) fn done(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.busy.remove(key);
+        if state.pending.contains_key(key) {
+            state.ready.push_back(key.to_string());
+            drop(state);
+            self.ready.notify_waiters();
+        }
+    }
+
+    /// Items already queued are still taken, `pop` returns `None` after them
+    pub(super) fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_waiters();
+    }
+}
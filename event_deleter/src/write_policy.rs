@@ -0,0 +1,188 @@
+use crate::event_analyzer::pow::PowPolicy;
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Write};
+use tracing::{debug, error};
+
+/// A line strfry sends to its write policy plugin through stdin
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WritePolicyRequest {
+    #[serde(rename = "type")]
+    pub request_type: String,
+    pub event: Event,
+    #[serde(default)]
+    pub received_at: u64,
+    #[serde(default)]
+    pub source_type: String,
+    #[serde(default)]
+    pub source_info: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum WritePolicyAction {
+    Accept,
+    Reject,
+    ShadowReject,
+}
+
+/// The line the plugin answers with through stdout. The id is a string so
+/// requests that didn't parse can still be answered.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WritePolicyResponse {
+    pub id: String,
+    pub action: WritePolicyAction,
+    pub msg: String,
+}
+
+pub fn pow_policy_response(
+    policy: &PowPolicy,
+    request: &WritePolicyRequest,
+) -> WritePolicyResponse {
+    match policy.check(&request.event) {
+        Ok(()) => WritePolicyResponse {
+            id: request.event.id.to_hex(),
+            action: WritePolicyAction::Accept,
+            msg: String::new(),
+        },
+        Err(e) => WritePolicyResponse {
+            id: request.event.id.to_hex(),
+            action: WritePolicyAction::Reject,
+            msg: e.to_string(),
+        },
+    }
+}
+
+/// Runs the strfry write policy protocol over the given streams, one JSON
+/// request per line in and one JSON response per line out, until the input
+/// is closed.
+pub fn run_write_policy<R: BufRead, W: Write>(
+    reader: R,
+    mut writer: W,
+    policy: &PowPolicy,
+) -> io::Result<()> {
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // strfry waits for an answer to every request, malformed ones are
+        // rejected with whatever id they carry
+        let response = match serde_json::from_str::<WritePolicyRequest>(&line) {
+            Ok(request) => pow_policy_response(policy, &request),
+            Err(e) => {
+                error!("Failed to parse write policy request: {}", e);
+                WritePolicyResponse {
+                    id: request_id(&line),
+                    action: WritePolicyAction::Reject,
+                    msg: "error: malformed write policy request".to_string(),
+                }
+            }
+        };
+        debug!("{:?} {}: {}", response.action, response.id, response.msg);
+
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+fn request_id(line: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(line)
+        .ok()
+        .and_then(|request| request["event"]["id"].as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_analyzer::pow::ProofOfWorkConfig;
+    use nostr_sdk::nips::nip13::get_leading_zero_bits;
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+
+    fn request(event: &Event) -> String {
+        json!({
+            "type": "new",
+            "event": event,
+            "receivedAt": 1_700_000_000,
+            "sourceType": "IP4",
+            "sourceInfo": "127.0.0.1",
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_one_response_per_request() {
+        let policy = PowPolicy::from_config(&ProofOfWorkConfig {
+            default_difficulty: 0,
+            kinds: HashMap::from([(1, 8)]),
+            trusted_pubkeys: Vec::new(),
+        })
+        .unwrap();
+        let keys = Keys::generate();
+
+        let mined = EventBuilder::text_note("hello", [])
+            .pow(10)
+            .to_event(&keys)
+            .unwrap();
+        let reaction = EventBuilder::new(Kind::Reaction, "+", [])
+            .to_event(&keys)
+            .unwrap();
+        let unmined = (0..)
+            .map(|i| {
+                EventBuilder::text_note(format!("hello {}", i), [])
+                    .to_event(&keys)
+                    .unwrap()
+            })
+            .find(|event| get_leading_zero_bits(event.id.as_bytes()) < 8)
+            .unwrap();
+        let broken = json!({"type": "new", "event": {"id": "abc", "kind": "one"}}).to_string();
+
+        let input = [
+            request(&mined),
+            request(&unmined),
+            String::new(),
+            broken,
+            "not json".to_string(),
+            request(&reaction),
+        ]
+        .join("\n");
+        let mut output = Vec::new();
+        run_write_policy(input.as_bytes(), &mut output, &policy).unwrap();
+
+        let responses: Vec<Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let answers: Vec<(&str, &str)> = responses
+            .iter()
+            .map(|response| {
+                (
+                    response["id"].as_str().unwrap(),
+                    response["action"].as_str().unwrap(),
+                )
+            })
+            .collect();
+
+        let mined_id = mined.id.to_hex();
+        let reaction_id = reaction.id.to_hex();
+        let unmined_id = unmined.id.to_hex();
+        assert_eq!(
+            answers,
+            vec![
+                (mined_id.as_str(), "accept"),
+                (unmined_id.as_str(), "reject"),
+                ("abc", "reject"),
+                ("", "reject"),
+                (reaction_id.as_str(), "accept"),
+            ]
+        );
+    }
+}
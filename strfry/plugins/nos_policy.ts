@@ -1,25 +1,11 @@
 import type { Policy } from "https://raw.githubusercontent.com/planetary-social/strfry-policies/refs/heads/nos-changes/mod.ts";
+import kindPolicy from "./kind_policy.json" with { type: "json" };
 
+// Shared with the event_deleter kind policy rule, which removes stored events
+// of kinds that are no longer allowed
 const ALLOWED = {
-  pubs: {
-    "56d4b3d6310fadb7294b7f041aab469c5ffc8991b1b1b331981b96a246f6ae65": true, // Tagr
-  },
-  eventKinds: [
-    0, // Metadata
-    1, // Short Text Note
-    3, // Contacts
-    4, // Encrypted Direct Messages
-    5, // Event deletion
-    6, // Repost
-    7, // Reaction
-    62, // Request to Vanish
-    1059, // Gift wrap messages
-    1984, // Reports
-    10000, // Mute list
-    10002, // Relay list metadata
-    30000, // Follow Set
-    30023, // Long-form Content
-  ],
+  pubs: kindPolicy.allowed_pubkeys as Record<string, string>,
+  eventKinds: Object.keys(kindPolicy.allowed_kinds).map(Number),
 };
 
 // This overrides the allowed rules above